@@ -1,6 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
+use core_lib::package::host::{Host, GLIBC_MIN};
 
 use crate::config::Config;
 
@@ -31,8 +32,28 @@ fn ensure_symlink_dir(root: &Path, link_rel: &str, target_rel: &str) -> Result<(
   Ok(())
 }
 
+fn report_host(config: &Config) {
+  match Host::detect() {
+    Ok(host) => {
+      match host.tag() {
+        Ok(tag) => eprintln!("host: {}, bottle tag {}", host, tag),
+        Err(e) => eprintln!("host: {}, no bottle tag: {}", host, e),
+      }
+      if !host.glibc_supported() {
+        eprintln!("warning: bottles for linux need glibc {} or newer", GLIBC_MIN);
+      }
+    },
+    Err(e) => eprintln!("host: {}", e),
+  }
+  match config.base.arch_policy() {
+    Ok(policy) => eprintln!("arch: {} ({})", config.base.arch, policy),
+    Err(e) => eprintln!("arch: {} is invalid: {}", config.base.arch, e),
+  }
+}
+
 pub fn run(config: &Config) -> Result<()> {
   let prefix = &config.base.prefix;
+  report_host(config);
 
   ensure_symlink_dir(prefix, "Cellar", "local/opt")?;
   ensure_symlink_dir(prefix, "local/bin", "../bin")?;
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use core_lib::{io::{fetch::MirrorLists, read::{read_formulas, tmp_path}}, stage::{download, probe, resolve, verify}, ui::{event::{simplify_tracker, ItemEvent}, with_progess_bar, with_progess_multibar}};
+use core_lib::{io::{fetch::MirrorLists, read::tmp_path}, package::index::FormulaIndex, stage::{download, probe, resolve, verify}, ui::{event::{simplify_tracker, ItemEvent}, with_progess_bar, with_progess_multibar}};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
@@ -7,7 +7,8 @@ use super::QueryArgs;
 
 #[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names, arch = %config.base.arch))]
 pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Result<()> {
-  let formulas = read_formulas(config.base.formula_json())?;
+  let arch = config.base.arch_policy()?;
+  let index = FormulaIndex::load(config.base.formula_json())?;
 
   info!(message="resolve", ?query.names);
   let resolved = with_progess_bar(
@@ -15,7 +16,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     Some(PbStyle::Items.style()),
     Some(ItemEvent::Init { max: query.names.len() }),
     |tracker| resolve::exec(
-      &formulas,
+      &index,
       query.names.iter(),
       tracker
     ),
@@ -29,8 +30,9 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     Some(PbStyle::Items.style()),
     Some(ItemEvent::Init { max: resolved.packages.len() }),
     |tracker| probe::exec(
-      probe::Args::new(&config.base.arch, mirrors)
-        .cache(&cache_pkg, false),
+      probe::Args::new(&arch, mirrors)
+        .cache(&cache_pkg, false)
+        .jobs(config.network.parallel),
       &resolved.packages,
       tracker
     ),
@@ -42,8 +44,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     ACTIVE_PB.clone(),
     Some(PbStyle::Bytes.style()),
     |tracker| download::exec(
-      mirrors,
-      &cache_pkg,
+      download::Args::new(mirrors, &cache_pkg).jobs(config.network.parallel),
       urls.iter().filter(|v| !v.cached).map(|i| (&i.pkg, &i.url)),
       tracker
     ),
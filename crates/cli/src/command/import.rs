@@ -1,7 +1,7 @@
 use std::{collections::{HashMap, HashSet}, path::Path};
 
 use anyhow::Result;
-use core_lib::{db, io::read::read_formulas, package::{formula::Formula, package::{InstallReason, InstalledPackage, InstalledPackageRecord, PackageVersion}}, stage::link};
+use core_lib::{db, package::{index::FormulaIndex, package::{InstallReason, InstalledPackage, InstalledPackageRecord, PackageInstalled, PackageVersion}}, stage::link};
 
 use crate::config::Config;
 
@@ -22,28 +22,15 @@ fn dependency_names(installed: &HashSet<String>, packages: &HashMap<String, Pack
   required
 }
 
-fn formula_index(formulas: Vec<Formula>) -> HashMap<String, PackageVersion> {
-  let mut index = HashMap::new();
-  for formula in formulas {
-    let aliases = formula.aliases.clone();
-    let oldnames = formula.oldnames.clone();
-    let oldname = formula.oldname.clone();
-    let full_name = formula.full_name.clone();
-    let package = PackageVersion::from(formula);
-
-    index.insert(package.name.clone(), package.clone());
-    index.insert(full_name, package.clone());
-    if let Some(oldname) = oldname {
-      index.insert(oldname, package.clone());
-    }
-    for alias in aliases {
-      index.insert(alias, package.clone());
-    }
-    for oldname in oldnames {
-      index.insert(oldname, package.clone());
+/// look up every installed name, which may be an alias or an old name, in the formula index
+fn installed_packages(index: &FormulaIndex, names: &HashSet<String>) -> core_lib::error::Result<HashMap<String, PackageVersion>> {
+  let mut packages = HashMap::new();
+  for name in names {
+    if let Some(package) = index.get(name)? {
+      packages.insert(name.clone(), package);
     }
   }
-  index
+  Ok(packages)
 }
 
 fn install_date(path: &Path) -> u64 {
@@ -55,16 +42,50 @@ fn install_date(path: &Path) -> u64 {
     .unwrap_or_else(db::now_unix)
 }
 
+/// record every version next to `pkg` in the cellar, the one `opt/<name>` points to is the active one,
+/// or `pkg` if it is not linked. only the active version owns files in prefix
+fn import_versions(prefix: &Path, db_root: &Path, pkg: &PackageInstalled, meta: Option<&PackageVersion>, reason: InstallReason) -> Result<String> {
+  let versions = pkg.dest.parent().map(link::installed_versions).unwrap_or_default();
+  let active = link::linked_version(prefix, &pkg.name)
+    .filter(|linked| versions.iter().any(|keg| keg.version == *linked))
+    .unwrap_or_else(|| pkg.version.clone());
+  for keg in &versions {
+    let files = match (keg.version == active, meta.is_some_and(|pkg| pkg.keg_only.is_some())) {
+      (false, _) => Vec::new(),
+      (true, true) => vec![link::opt_file(&keg.name)],
+      (true, false) => link::owned_files(prefix, &keg.name, &keg.dest).unwrap_or_else(|_| vec![link::opt_file(&keg.name)]),
+    };
+    db::write_record(db_root, &InstalledPackage {
+      record: InstalledPackageRecord {
+        name: keg.name.clone(),
+        version: keg.version.clone(),
+        version_scheme: meta.map(|pkg| pkg.version_scheme).unwrap_or_default(),
+        desc: meta.map(|pkg| pkg.desc.clone()).unwrap_or_default(),
+        license: meta.and_then(|pkg| pkg.license.clone()),
+        deps: meta.map(|pkg| pkg.deps.clone()).unwrap_or_default(),
+        reason,
+        install_date: install_date(&keg.dest),
+        dest: keg.dest.clone(),
+        keg_only: meta.and_then(|pkg| pkg.keg_only.clone()),
+      },
+      files,
+      reloc: std::collections::BTreeMap::new(),
+      unrelocated: std::collections::BTreeMap::new(),
+    })?;
+  }
+  db::set_active(db_root, &pkg.name, Some(&active))?;
+  Ok(active)
+}
+
 pub fn run(config: &Config, query: QueryArgs) -> Result<()> {
+  let installed = link::list_installed(&config.base.local_opt())?;
+  let installed_names = installed.iter().map(|pkg| pkg.name.clone()).collect::<HashSet<_>>();
   let formula_path = config.base.formula_json();
   let packages = if formula_path.exists() {
-    formula_index(read_formulas(&formula_path)?)
+    installed_packages(&FormulaIndex::load(&formula_path)?, &installed_names)?
   } else {
     HashMap::new()
   };
-
-  let installed = link::list_installed(&config.base.local_opt())?;
-  let installed_names = installed.iter().map(|pkg| pkg.name.clone()).collect::<HashSet<_>>();
   let dependency_pkgs = dependency_names(&installed_names, &packages);
   let selected = query.names.into_iter().collect::<HashSet<_>>();
   let mut imported = 0usize;
@@ -72,29 +93,16 @@ pub fn run(config: &Config, query: QueryArgs) -> Result<()> {
     if !selected.is_empty() && !selected.contains(&pkg.name) {
       continue;
     }
-    if db::read_installed(&config.base.db, &pkg.name)?.is_some() {
+    if !db::list_versions(&config.base.db, &pkg.name)?.is_empty() {
       continue;
     }
 
-    let meta = packages.get(&pkg.name);
-    db::write_installed(&config.base.db, &InstalledPackage {
-      record: InstalledPackageRecord {
-        name: pkg.name.clone(),
-        version: pkg.version.clone(),
-        desc: meta.map(|pkg| pkg.desc.clone()).unwrap_or_default(),
-        license: meta.and_then(|pkg| pkg.license.clone()),
-        deps: meta.map(|pkg| pkg.deps.clone()).unwrap_or_default(),
-        reason: if dependency_pkgs.contains(&pkg.name) {
-          InstallReason::Dependency
-        } else {
-          InstallReason::Explicit
-        },
-        install_date: install_date(&pkg.dest),
-        dest: pkg.dest.clone(),
-      },
-      files: link::owned_files(&pkg.name, &pkg.dest).unwrap_or_else(|_| vec![format!("opt/{}", pkg.name)]),
-      reloc: std::collections::BTreeMap::new(),
-    })?;
+    let reason = if dependency_pkgs.contains(&pkg.name) {
+      InstallReason::Dependency
+    } else {
+      InstallReason::Explicit
+    };
+    import_versions(&config.base.prefix, &config.base.db, &pkg, packages.get(&pkg.name), reason)?;
     imported += 1;
   }
 
@@ -106,9 +114,9 @@ pub fn run(config: &Config, query: QueryArgs) -> Result<()> {
 mod tests {
   use std::collections::{HashMap, HashSet};
 
-  use core_lib::package::package::PackageVersion;
+  use core_lib::{db, package::package::{InstallReason, PackageVersion}, stage::{link, unpack::staging_dir}};
 
-  use super::dependency_names;
+  use super::{dependency_names, import_versions};
 
   #[test]
   fn dependency_reason_uses_installed_reverse_edges() {
@@ -123,11 +131,13 @@ mod tests {
           name: "wget".to_string(),
           version: "1.0.0".to_string(),
           revision: 0,
+          version_scheme: 0,
           desc: String::new(),
           license: None,
           deps: vec!["openssl@3".to_string()],
           prebuilds: vec![],
           link_overwrite: vec![],
+          keg_only: None,
         },
       ),
       (
@@ -136,11 +146,13 @@ mod tests {
           name: "openssl@3".to_string(),
           version: "1.0.0".to_string(),
           revision: 0,
+          version_scheme: 0,
           desc: String::new(),
           license: None,
           deps: vec![],
           prebuilds: vec![],
           link_overwrite: vec![],
+          keg_only: None,
         },
       ),
       (
@@ -149,11 +161,13 @@ mod tests {
           name: "sqlite".to_string(),
           version: "1.0.0".to_string(),
           revision: 0,
+          version_scheme: 0,
           desc: String::new(),
           license: None,
           deps: vec![],
           prebuilds: vec![],
           link_overwrite: vec![],
+          keg_only: None,
         },
       ),
     ]);
@@ -163,4 +177,35 @@ mod tests {
     assert!(!required.contains("wget"));
     assert!(!required.contains("sqlite"));
   }
+
+  #[test]
+  fn import_every_version_with_the_linked_one_active() {
+    let prefix = std::env::temp_dir().join(format!("pacbrew-import-test-{}", std::process::id()));
+    let (cellar, db_root) = (prefix.join("Cellar"), prefix.join("db"));
+    std::fs::remove_dir_all(&prefix).ok();
+    for version in ["1.0", "2.0"] {
+      std::fs::create_dir_all(cellar.join("foo").join(version)).unwrap();
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    // left by an interrupted install
+    std::fs::create_dir_all(staging_dir(&cellar, "foo").join("foo/3.0")).unwrap();
+    std::fs::create_dir_all(prefix.join("opt")).unwrap();
+    symlink::symlink_dir(cellar.join("foo/1.0"), prefix.join("opt/foo")).unwrap();
+
+    let pkg = link::guess_installed(&cellar.join("foo")).unwrap();
+    assert_eq!(pkg.version, "2.0");
+    assert_eq!(import_versions(&prefix, &db_root, &pkg, None, InstallReason::Explicit).unwrap(), "1.0");
+    let versions = db::list_versions(&db_root, "foo").unwrap();
+    assert_eq!(versions.iter().map(|i| i.version.as_str()).collect::<Vec<_>>(), vec!["1.0", "2.0"]);
+    let active = db::read_installed(&db_root, "foo").unwrap().unwrap();
+    assert_eq!(active.record.version, "1.0");
+    assert!(active.files.contains(&"opt/foo".to_string()));
+    assert!(db::read_installed_version(&db_root, "foo", "2.0").unwrap().unwrap().files.is_empty());
+
+    // not linked, the last created version is the active one
+    std::fs::remove_file(prefix.join("opt/foo")).unwrap();
+    std::fs::remove_dir_all(&db_root).unwrap();
+    assert_eq!(import_versions(&prefix, &db_root, &pkg, None, InstallReason::Explicit).unwrap(), "2.0");
+    std::fs::remove_dir_all(&prefix).ok();
+  }
 }
@@ -1,12 +1,13 @@
 use anyhow::Result;
 use std::io::{BufRead, Write};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 
-use core_lib::{db::{self, InstalledVersionStatus}, io::{fetch::MirrorLists, read::{read_formulas, tmp_path}}, package::{formula::Formula, package::{InstallReason, InstalledPackage, InstalledPackageRecord, PackageCache, PackageVersion}}, stage::{download, link, probe, resolve, unpack, verify}, ui::{event::ItemEvent, with_progess_bar, with_progess_multibar}};
+use core_lib::{db::{self, InstalledVersionStatus}, io::{fetch::MirrorLists, read::tmp_path}, package::{arch::ArchPolicy, index::FormulaIndex, package::{InstallReason, InstalledPackage, InstalledPackageRecord, PackageCache, PackageInstalled, PackageVersion, PourMode}}, stage::{download, link::{self, Conflict, KegListing}, probe, resolve, unpack, verify}, transaction::Transaction, ui::{event::ItemEvent, with_progess_bar, with_progess_multibar, EventListener as _}};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
-use super::QueryArgs;
+use super::InstallArgs;
 
 #[derive(Debug, Default)]
 struct InstallPlan {
@@ -18,6 +19,7 @@ struct InstallPlan {
 enum PlanAction {
   Install,
   Upgrade,
+  Downgrade,
   Reinstall,
 }
 
@@ -27,20 +29,17 @@ struct PlannedPackage {
   action: PlanAction,
   requested: bool,
   installed_version: Option<String>,
+  /// bottle tag picked for the host, which may be a fallback
+  bottle: Option<String>,
+  /// cellar the bottle is built for and how it is poured into ours
+  pour: Option<(String, PourMode)>,
 }
 
-fn requested_package_names(formulas: &[Formula], query: &[String]) -> Result<HashSet<String>> {
-  let mut formula_index = formulas.iter().map(|formula| (formula.name.as_str(), formula)).collect::<HashMap<_, _>>();
-  formula_index.extend(formulas.iter().flat_map(|formula| formula.oldname.iter().map(move |name| (name.as_str(), formula))));
-  formula_index.extend(formulas.iter().flat_map(|formula| formula.oldnames.iter().map(move |name| (name.as_str(), formula))));
-  formula_index.extend(formulas.iter().flat_map(|formula| formula.aliases.iter().map(move |name| (name.as_str(), formula))));
-  formula_index.extend(formulas.iter().map(|formula| (formula.full_name.as_str(), formula)));
-
+fn requested_package_names(index: &FormulaIndex, query: &[String]) -> Result<HashSet<String>> {
   query.iter()
     .map(|name| {
-      formula_index
-        .get(name.as_str())
-        .map(|formula| formula.name.clone())
+      index.get(name)?
+        .map(|package| package.name)
         .ok_or_else(|| anyhow::anyhow!("package not found: {name}"))
     })
     .collect()
@@ -50,6 +49,8 @@ fn plan_packages(
   resolved: &[PackageVersion],
   requested_names: &HashSet<String>,
   installed: &HashMap<String, InstalledPackageRecord>,
+  arch: &ArchPolicy,
+  cellar: &Path,
 ) -> InstallPlan {
   let mut plan = InstallPlan::default();
   let mut seen = HashSet::new();
@@ -60,11 +61,12 @@ fn plan_packages(
     }
 
     let is_requested = requested_names.contains(&package.name);
-    let version = package.version_full();
-    let installed_version = installed.get(&package.name).map(|record| record.version.clone());
-    let status = db::version_status(installed_version.as_deref(), &version);
+    let record = installed.get(&package.name);
+    let status = db::version_status(record.map(InstalledPackageRecord::pkg_version).as_ref(), &package.pkg_version());
+    let installed_version = record.map(|record| record.version.clone());
 
-    if !is_requested && status == InstalledVersionStatus::Satisfied {
+    // never downgrade a dependency implicitly
+    if !is_requested && matches!(status, InstalledVersionStatus::Satisfied | InstalledVersionStatus::Newer) {
       plan.skipped_dependencies.push(package.name.clone());
       continue;
     }
@@ -73,13 +75,17 @@ fn plan_packages(
       InstalledVersionStatus::Missing => PlanAction::Install,
       InstalledVersionStatus::Satisfied => PlanAction::Reinstall,
       InstalledVersionStatus::Outdated => PlanAction::Upgrade,
+      InstalledVersionStatus::Newer => PlanAction::Downgrade,
     };
 
+    let build = package.find_arch(arch);
     plan.packages.push(PlannedPackage {
       package: package.clone(),
       action,
       requested: is_requested,
       installed_version,
+      bottle: build.map(|i| i.arch.clone()),
+      pour: build.map(|i| (i.cellar.clone(), i.pour_mode(cellar))),
     });
   }
 
@@ -90,10 +96,20 @@ fn review_plan<W: Write>(writer: &mut W, plan: &InstallPlan) -> std::io::Result<
   writeln!(writer, "install plan:")?;
   for item in &plan.packages {
     let scope = if item.requested { "root" } else { "dep" };
+    let pour = match &item.pour {
+      Some((_, PourMode::SkipRelocation)) => ", skip relocation".to_string(),
+      Some((cellar, PourMode::CellarMismatch)) => format!(", built for {cellar}"),
+      _ => String::new(),
+    };
+    let bottle = item.bottle.as_deref().map(|i| format!(" [{i}{pour}]")).unwrap_or_default();
     match item.action {
-      PlanAction::Install => writeln!(writer, "  install   {scope} {} {}", item.package.name, item.package.version_full())?,
-      PlanAction::Upgrade => writeln!(writer, "  upgrade   {scope} {} {} -> {}", item.package.name, item.installed_version.as_deref().unwrap_or("?"), item.package.version_full())?,
-      PlanAction::Reinstall => writeln!(writer, "  reinstall {scope} {} {}", item.package.name, item.package.version_full())?,
+      PlanAction::Install => writeln!(writer, "  install   {scope} {} {}{bottle}", item.package.name, item.package.version_full())?,
+      PlanAction::Upgrade => writeln!(writer, "  upgrade   {scope} {} {} -> {}{bottle}", item.package.name, item.installed_version.as_deref().unwrap_or("?"), item.package.version_full())?,
+      PlanAction::Downgrade => writeln!(writer, "  downgrade {scope} {} {} -> {}{bottle}", item.package.name, item.installed_version.as_deref().unwrap_or("?"), item.package.version_full())?,
+      PlanAction::Reinstall => writeln!(writer, "  reinstall {scope} {} {}{bottle}", item.package.name, item.package.version_full())?,
+    }
+    if let Some(reason) = &item.package.keg_only {
+      writeln!(writer, "            keg-only, only opt/{} is linked: {reason}", item.package.name)?;
     }
   }
   // if !plan.skipped_dependencies.is_empty() {
@@ -105,6 +121,16 @@ fn review_plan<W: Write>(writer: &mut W, plan: &InstallPlan) -> std::io::Result<
   Ok(())
 }
 
+/// packages whose bottles are built for another fixed cellar
+fn cellar_mismatches(plan: &InstallPlan) -> Vec<String> {
+  plan.packages.iter()
+    .filter_map(|item| match &item.pour {
+      Some((cellar, PourMode::CellarMismatch)) => Some(format!("{} ({})", item.package.name, cellar)),
+      _ => None,
+    })
+    .collect()
+}
+
 fn prompt_yes_no<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &str) -> std::io::Result<bool> {
   loop {
     write!(writer, "{prompt}")?;
@@ -124,38 +150,134 @@ fn prompt_yes_no<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, prompt: &
   }
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(query = ?query.names, arch = %config.base.arch))]
-pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Result<bool> {
-  let formulas = read_formulas(config.base.formula_json())?;
-  let requested_names = requested_package_names(&formulas, &query.names)?;
+/// conflicts of links of `packages` in order, paths linked by earlier packages are taken into account
+fn find_conflicts(prefix: &Path, owners: &HashMap<String, String>, packages: &[(&PackageVersion, Vec<String>)]) -> Vec<Conflict> {
+  let mut result = Vec::new();
+  let mut planned = HashMap::<&str, &str>::new();
+  for (package, targets) in packages {
+    result.extend(link::conflicts(prefix, &package.name, targets, owners, &package.link_overwrite));
+    for path in targets {
+      match planned.insert(path, &package.name) {
+        Some(owner) if owner != package.name => result.push(Conflict { name: package.name.clone(), path: path.clone(), owner: Some(owner.to_string()) }),
+        _ => {},
+      }
+    }
+  }
+  result
+}
+
+pub fn review_conflicts<W: Write>(writer: &mut W, conflicts: &[Conflict]) -> std::io::Result<()> {
+  let mut grouped = BTreeMap::<(&str, Option<&str>), Vec<&str>>::new();
+  for conflict in conflicts {
+    grouped.entry((&conflict.name, conflict.owner.as_deref())).or_default().push(&conflict.path);
+  }
+  writeln!(writer, "link conflicts:")?;
+  for ((name, owner), paths) in grouped {
+    let owner = owner.map(|i| format!("owned by {i}")).unwrap_or_else(|| "not linked by pacbrew".to_string());
+    writeln!(writer, "  {name} would overwrite {} path(s) {owner}: {}", paths.len(), paths.join(", "))?;
+  }
+  Ok(())
+}
+
+/// all bottles are known after unpack, check again before anything is linked
+fn check_unpacked(prefix: &Path, owners: &HashMap<String, String>, package_index: &HashMap<&str, &PackageVersion>, unpacked: &[PackageInstalled], overwrite: bool) -> Result<()> {
+  let links = unpacked.iter()
+    .map(|pkg| {
+      let meta = *package_index.get(pkg.name.as_str()).unwrap();
+      Ok((meta, match meta.keg_only {
+        Some(_) => vec![link::opt_file(&pkg.name)],
+        None => link::owned_files(prefix, &pkg.name, &pkg.dest)?,
+      }))
+    })
+    .collect::<core_lib::error::Result<Vec<_>>>()?;
+  let conflicts = find_conflicts(prefix, owners, &links);
+  if !conflicts.is_empty() && !overwrite {
+    review_conflicts(&mut std::io::stderr(), &conflicts)?;
+    anyhow::bail!("{} path(s) are in the way, pass --overwrite to link over them", conflicts.len());
+  }
+  Ok(())
+}
+
+/// roll back the install, staged kegs included
+fn abort(tx: Transaction, e: anyhow::Error) -> anyhow::Error {
+  warn!(error=%e, changes=tx.changes().len(), "install failed, roll back");
+  if let Err(error) = tx.rollback() {
+    error!(%error, "roll back failed, it would be retried by the next install");
+  }
+  e
+}
+
+/// links planned from bottles already in cache, others are known after download
+async fn cached_links<'a>(plan: &'a InstallPlan, arch: &ArchPolicy, cache_pkg: &Path, prefix: &Path) -> Vec<(&'a PackageVersion, Vec<String>)> {
+  let mut result = Vec::new();
+  for item in &plan.packages {
+    let Some(build) = item.package.find_arch(arch) else {
+      continue;
+    };
+    if item.package.keg_only.is_some() {
+      result.push((&item.package, vec![link::opt_file(&item.package.name)]));
+      continue;
+    }
+    let bottle = cache_pkg.join(&build.filename);
+    if !bottle.exists() {
+      continue;
+    }
+    match KegListing::from_bottle(&bottle).await.and_then(|keg| link::collect_link_targets(&keg, Some(prefix))) {
+      Ok(targets) => result.push((&item.package, std::iter::once(link::opt_file(&item.package.name)).chain(targets).collect())),
+      Err(error) => warn!(%error, bottle=%bottle.display(), "cannot list bottle"),
+    }
+  }
+  result
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(query = ?args.query.names, arch = %config.base.arch))]
+pub async fn run(config: &Config, mirrors: &MirrorLists, args: InstallArgs) -> Result<bool> {
+  let query = args.query;
+  let arch = config.base.arch_policy()?;
+  let index = FormulaIndex::load(config.base.formula_json())?;
+  let requested_names = requested_package_names(&index, &query.names)?;
   let installed = db::installed_index(&config.base.db)?;
 
   info!(message="resolve", ?query.names);
   let resolved = resolve::exec(
-    &formulas,
+    &index,
     query.names.iter(),
     (),
   ).await.unwrap();
 
-  let plan = plan_packages(&resolved.packages, &requested_names, &installed);
+  let local_opt_dir = config.base.local_opt();
+  let plan = plan_packages(&resolved.packages, &requested_names, &installed, &arch, &local_opt_dir);
   if !plan.skipped_dependencies.is_empty() {
     info!(message="skip satisfied dependencies", skipped=plan.skipped_dependencies.join(","));
   }
   review_plan(&mut std::io::stderr(), &plan)?;
+  let mismatches = cellar_mismatches(&plan);
+  if !mismatches.is_empty() && !config.relocation.force {
+    anyhow::bail!("bottles are built for another cellar than {}: {}, set `relocation.force` to relocate them anyway", local_opt_dir.display(), mismatches.join(", "));
+  }
+  let cached_pkg = config.base.cache_pkg();
+  let owners = db::file_owners(&config.base.db)?;
+  let conflicts = find_conflicts(&config.base.prefix, &owners, &cached_links(&plan, &arch, &cached_pkg, &config.base.prefix).await);
+  if !conflicts.is_empty() {
+    review_conflicts(&mut std::io::stderr(), &conflicts)?;
+    if !args.overwrite {
+      anyhow::bail!("{} path(s) are in the way, pass --overwrite to link over them", conflicts.len());
+    }
+  }
   if !prompt_yes_no(&mut std::io::BufReader::new(std::io::stdin()), &mut std::io::stderr(), "Proceed with download? [Y/n] ")? {
     eprintln!("aborted");
     return Ok(false);
   }
 
-  let cached_pkg = config.base.cache_pkg();
   info!(message="probe", ?resolved.names, planned=plan.packages.iter().map(|i| i.package.name.as_str()).collect::<Vec<_>>().join(","));
   let urls = with_progess_bar(
     ACTIVE_PB.clone(),
     Some(PbStyle::Items.style()),
     Some(ItemEvent::Init { max: plan.packages.len() }),
     |tracker| probe::exec(
-      probe::Args::new(&config.base.arch, mirrors)
-        .cache(&cached_pkg, false),
+      probe::Args::new(&arch, mirrors)
+        .cache(&cached_pkg, false)
+        .jobs(config.network.parallel),
       plan.packages.iter().map(|item| &item.package).collect::<Vec<_>>(),
       tracker,
     ),
@@ -166,8 +288,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
     ACTIVE_PB.clone(),
     PbStyle::Bytes.style().into(),
     |tracker| download::exec(
-      mirrors,
-      &cached_pkg,
+      download::Args::new(mirrors, &cached_pkg).jobs(config.network.parallel),
       urls.iter().filter(|value| !value.cached).map(|value| (&value.pkg, &value.url)),
       tracker,
     ),
@@ -182,6 +303,7 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
       name: i.pkg.name.clone(),
       cache_pkg,
       cache_size,
+      cellar: i.pkg.cellar.clone(),
     });
   }
 
@@ -199,87 +321,135 @@ pub async fn run(config: &Config, mirrors: &MirrorLists, query: QueryArgs) -> Re
   });
   assert!(failed.is_empty());
 
-  let local_opt_dir = config.base.local_opt();
+  // a journal left by an interrupted install is rolled back before anything is staged
+  let mut tx = Transaction::begin(&config.base.prefix, &config.base.db)?;
+  tx.stage(cached.iter().map(|pkg| unpack::staging_dir(&local_opt_dir, &pkg.name)))?;
   let unpacked = with_progess_multibar(
     ACTIVE_PB.clone(),
     PbStyle::Bytes.style().into(),
-    |tracker| unpack::exec(
-      // TODO: force in args
-      unpack::Args::new(&config.base.prefix, &local_opt_dir).force(true),
-      &cached,
-      tracker
-    ),
+    |tracker| async {
+      let unpack = unpack::exec(
+        unpack::Args::new(&config.base.prefix, &local_opt_dir).stage(true)
+          .force_relocate(config.relocation.force)
+          .placeholders(&config.relocation.placeholders),
+        &cached,
+        tracker
+      );
+      tokio::select! {
+        result = unpack => result.map_err(anyhow::Error::from),
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+      }
+    },
     (),
-  ).await.unwrap();
+  ).await;
+  let unpacked = match unpacked {
+    Ok(unpacked) => unpacked,
+    Err(e) => return Err(abort(tx, e)),
+  };
   unpacked.iter().for_each(|i| info!(message="unpacked", name=%i.name, dest=%i.dest.display()));
 
-  let linked = with_progess_bar(
+  let package_index = resolved.packages.iter().map(|pkg| (pkg.name.as_str(), pkg)).collect::<HashMap<_, _>>();
+  if let Err(e) = check_unpacked(&config.base.prefix, &owners, &package_index, &unpacked, args.overwrite) {
+    return Err(abort(tx, e));
+  }
+  let tx_ref = &mut tx;
+  let applied = with_progess_bar(
     ACTIVE_PB.clone(),
     PbStyle::Items.style().into(),
     ItemEvent::Init { max: unpacked.len() }.into(),
-    |tracker| link::exec(
-      &config.base.prefix,
-      &unpacked,
-      tracker,
-    ),
+    |tracker| async move {
+      let apply = async {
+        let mut linked = Vec::new();
+        for (i, staged) in unpacked.iter().enumerate() {
+          tracker.on_event(ItemEvent::Message { name: format!("installing {}", staged.name) });
+          let pkg = tx_ref.install_keg(&local_opt_dir, staged)?;
+          let meta = package_index.get(pkg.name.as_str()).unwrap();
+          let pkg_linked = tx_ref.activate(&pkg, meta.keg_only.is_some(), args.overwrite, ()).await?;
+          let reason = installed.get(&pkg.name)
+            .map(|installed| installed.reason)
+            .unwrap_or_else(|| {
+              if requested_names.contains(&pkg.name) {
+                InstallReason::Explicit
+              } else {
+                InstallReason::Dependency
+              }
+            });
+          tx_ref.write_installed(&InstalledPackage {
+            record: InstalledPackageRecord {
+              name: pkg.name.clone(),
+              version: pkg.version.clone(),
+              version_scheme: meta.version_scheme,
+              desc: meta.desc.clone(),
+              license: meta.license.clone(),
+              deps: meta.deps.clone(),
+              reason,
+              install_date: db::now_unix(),
+              dest: pkg.dest.clone(),
+              keg_only: meta.keg_only.clone(),
+            },
+            files: pkg_linked.files.clone(),
+            reloc: pkg.reloc.clone(),
+            unrelocated: pkg.unrelocated.clone(),
+          })?;
+          tracker.on_event(ItemEvent::Progress { current: i + 1, max: None });
+          linked.push(pkg_linked);
+        }
+        tracker.on_event(ItemEvent::Finish);
+        Ok::<_, core_lib::error::Error>(linked)
+      };
+      tokio::select! {
+        result = apply => result.map_err(anyhow::Error::from),
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+      }
+    },
     (),
-  ).await.unwrap();
-  linked.iter().for_each(|i| info!(message="linked", name=%i.name, version=%i.version));
-
-  let package_index = resolved.packages.iter().map(|pkg| (pkg.name.as_str(), pkg)).collect::<HashMap<_, _>>();
-  let unpacked_index = unpacked.iter().map(|pkg| (pkg.name.as_str(), pkg)).collect::<HashMap<_, _>>();
-  for pkg in &linked {
-    let meta = package_index.get(pkg.name.as_str()).unwrap();
-    let reloc = unpacked_index.get(pkg.name.as_str())
-      .map(|pkg| pkg.reloc.clone())
-      .unwrap_or_default();
-    let reason = installed.get(&pkg.name)
-      .map(|installed| installed.reason)
-      .unwrap_or_else(|| {
-        if requested_names.contains(&pkg.name) {
-          InstallReason::Explicit
-        } else {
-          InstallReason::Dependency
+  ).await;
+
+  match applied {
+    Ok(linked) => {
+      linked.iter().for_each(|i| info!(message="linked", name=%i.name, version=%i.version));
+      tx.commit()?;
+      for pkg in &linked {
+        for version in super::remove::prune_versions(&config.base.db, &pkg.name, config.base.keep_versions)? {
+          eprintln!("removed {} {}, only {} previous version(s) are kept", pkg.name, version, config.base.keep_versions);
         }
-      });
-    db::write_installed(&config.base.db, &InstalledPackage {
-      record: InstalledPackageRecord {
-        name: pkg.name.clone(),
-        version: pkg.version.clone(),
-        desc: meta.desc.clone(),
-        license: meta.license.clone(),
-        deps: meta.deps.clone(),
-        reason,
-        install_date: db::now_unix(),
-        dest: pkg.dest.clone(),
-      },
-      files: pkg.files.clone(),
-      reloc,
-    })?;
+      }
+    },
+    Err(e) => return Err(abort(tx, e)),
   }
   Ok(true)
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{collections::{HashMap, HashSet}, path::PathBuf};
+  use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}};
 
-  use core_lib::package::package::{InstallReason, InstalledPackageRecord, PackageVersion};
+  use core_lib::package::{arch::ArchPolicy, package::{InstallReason, InstalledPackageRecord, PackageVersion, PkgBuild}};
 
   use std::io::Cursor;
 
-  use super::{plan_packages, prompt_yes_no, review_plan, PlanAction};
+  use super::{abort, cellar_mismatches, check_unpacked, find_conflicts, plan_packages, prompt_yes_no, review_conflicts, review_plan, PlanAction};
+
+  fn arch() -> ArchPolicy {
+    ArchPolicy::new("arm64_sonoma".parse().unwrap())
+  }
+
+  fn cellar() -> &'static Path {
+    Path::new("/pb/Cellar")
+  }
 
   fn package(name: &str, version: &str, deps: &[&str]) -> PackageVersion {
     PackageVersion {
       name: name.to_string(),
       version: version.to_string(),
       revision: 0,
+      version_scheme: 0,
       desc: format!("{name} desc"),
       license: None,
       deps: deps.iter().map(|value| value.to_string()).collect(),
       prebuilds: Vec::new(),
       link_overwrite: Vec::new(),
+      keg_only: None,
     }
   }
 
@@ -287,12 +457,14 @@ mod tests {
     InstalledPackageRecord {
       name: name.to_string(),
       version: version.to_string(),
+      version_scheme: 0,
       desc: format!("{name} installed"),
       license: None,
       deps: Vec::new(),
       reason: InstallReason::Dependency,
       install_date: 0,
       dest: PathBuf::from(format!("/tmp/{name}")),
+      keg_only: None,
     }
   }
 
@@ -308,7 +480,7 @@ mod tests {
       ("bar".to_string(), installed("bar", "2.0.0")),
     ]);
 
-    let plan = plan_packages(&resolved, &requested, &installed);
+    let plan = plan_packages(&resolved, &requested, &installed, &arch(), cellar());
 
     assert_eq!(plan.packages.iter().map(|pkg| pkg.package.name.as_str()).collect::<Vec<_>>(), vec!["foo"]);
     assert_eq!(plan.packages[0].action, PlanAction::Reinstall);
@@ -326,13 +498,32 @@ mod tests {
       ("bar".to_string(), installed("bar", "1.5.0")),
     ]);
 
-    let plan = plan_packages(&resolved, &requested, &installed);
+    let plan = plan_packages(&resolved, &requested, &installed, &arch(), cellar());
 
     assert_eq!(plan.packages.iter().map(|pkg| pkg.package.name.as_str()).collect::<Vec<_>>(), vec!["foo", "bar"]);
     assert_eq!(plan.packages[1].action, PlanAction::Upgrade);
     assert!(plan.skipped_dependencies.is_empty());
   }
 
+  #[test]
+  fn downgrades_requested_roots_but_keeps_newer_dependencies() {
+    let resolved = vec![
+      package("foo", "1.9.0", &["bar"]),
+      package("bar", "2.0.0", &[]),
+    ];
+    let requested = HashSet::from(["foo".to_string()]);
+    let installed = HashMap::from([
+      ("foo".to_string(), installed("foo", "1.10.0")),
+      ("bar".to_string(), installed("bar", "2.0.1")),
+    ]);
+
+    let plan = plan_packages(&resolved, &requested, &installed, &arch(), cellar());
+
+    assert_eq!(plan.packages.iter().map(|pkg| pkg.package.name.as_str()).collect::<Vec<_>>(), vec!["foo"]);
+    assert_eq!(plan.packages[0].action, PlanAction::Downgrade);
+    assert_eq!(plan.skipped_dependencies, vec!["bar"]);
+  }
+
   #[test]
   fn deduplicates_shared_dependencies_by_exact_name() {
     let resolved = vec![
@@ -343,7 +534,7 @@ mod tests {
     ];
     let requested = HashSet::from(["foo".to_string(), "bar".to_string()]);
 
-    let plan = plan_packages(&resolved, &requested, &HashMap::new());
+    let plan = plan_packages(&resolved, &requested, &HashMap::new(), &arch(), cellar());
 
     assert_eq!(plan.packages.iter().map(|pkg| pkg.package.name.as_str()).collect::<Vec<_>>(), vec!["foo", "bar", "shared"]);
   }
@@ -371,21 +562,122 @@ mod tests {
 
   #[test]
   fn review_plan_lists_actions() {
-    let resolved = vec![
+    let mut resolved = vec![
       package("foo", "1.0.0", &["bar"]),
       package("bar", "2.0.0", &[]),
     ];
+    resolved[1].prebuilds = ["arm64_sequoia", "arm64_ventura"].into_iter().map(|arch| PkgBuild {
+      name: "bar".to_string(),
+      version: "2.0.0".to_string(),
+      arch: arch.to_string(),
+      rebuild: 0,
+      filename: format!("bar-2.0.0.{arch}.bottle.tar.gz"),
+      url: String::new(),
+      sha256: String::new(),
+      cellar: ":any".to_string(),
+    }).collect();
+    resolved[1].keg_only = Some("this is an alternate version of another formula".to_string());
     let requested = HashSet::from(["foo".to_string()]);
     let installed = HashMap::from([
       ("foo".to_string(), installed("foo", "1.0.0")),
     ]);
-    let plan = plan_packages(&resolved, &requested, &installed);
+    let plan = plan_packages(&resolved, &requested, &installed, &arch(), cellar());
     let mut output = Vec::new();
 
     review_plan(&mut output, &plan).unwrap();
 
     let output = String::from_utf8(output).unwrap();
     assert!(output.contains("reinstall root foo 1.0.0"));
-    assert!(output.contains("install   dep bar 2.0.0"));
+    assert!(output.contains("install   dep bar 2.0.0 [arm64_ventura]"));
+    assert!(output.contains("keg-only, only opt/bar is linked: this is an alternate version of another formula"));
+  }
+
+  #[test]
+  fn review_plan_shows_cellar() {
+    let mut resolved = vec![
+      package("foo", "1.0.0", &[]),
+      package("bar", "2.0.0", &[]),
+      package("baz", "3.0.0", &[]),
+    ];
+    for (pkg, cellar) in resolved.iter_mut().zip([":any_skip_relocation", "/opt/homebrew/Cellar", "/pb/Cellar"]) {
+      pkg.prebuilds = vec![PkgBuild {
+        name: pkg.name.clone(),
+        version: pkg.version.clone(),
+        arch: "arm64_sonoma".to_string(),
+        rebuild: 0,
+        filename: String::new(),
+        url: String::new(),
+        sha256: String::new(),
+        cellar: cellar.to_string(),
+      }];
+    }
+    let requested = HashSet::from(["foo".to_string(), "bar".to_string(), "baz".to_string()]);
+    let plan = plan_packages(&resolved, &requested, &HashMap::new(), &arch(), cellar());
+    let mut output = Vec::new();
+
+    review_plan(&mut output, &plan).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("install   root foo 1.0.0 [arm64_sonoma, skip relocation]"));
+    assert!(output.contains("install   root bar 2.0.0 [arm64_sonoma, built for /opt/homebrew/Cellar]"));
+    assert!(output.contains("install   root baz 3.0.0 [arm64_sonoma, skip relocation]"));
+    assert_eq!(cellar_mismatches(&plan), vec!["bar (/opt/homebrew/Cellar)"]);
+  }
+
+  #[test]
+  fn conflicts_grouped_by_owner() {
+    let prefix = std::env::temp_dir().join(format!("pacbrew-install-conflict-test-{}", std::process::id()));
+    std::fs::create_dir_all(prefix.join("bin")).unwrap();
+    for file in ["bin/foo", "bin/bar", "bin/stray"] {
+      std::fs::write(prefix.join(file), file).unwrap();
+    }
+    let owners = HashMap::from([
+      ("bin/foo".to_string(), "old".to_string()),
+      ("bin/bar".to_string(), "old".to_string()),
+    ]);
+    let foo = package("foo", "1.0.0", &[]);
+    let mut bar = package("bar", "1.0.0", &[]);
+    bar.link_overwrite = vec!["bin/stray".to_string()];
+    let links = vec![
+      (&foo, ["bin/foo", "bin/bar", "bin/stray", "bin/shared"].map(String::from).to_vec()),
+      (&bar, ["bin/stray", "bin/shared"].map(String::from).to_vec()),
+    ];
+
+    let conflicts = find_conflicts(&prefix, &owners, &links);
+    let mut output = Vec::new();
+    review_conflicts(&mut output, &conflicts).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("foo would overwrite 2 path(s) owned by old: bin/foo, bin/bar"), "{output}");
+    assert!(output.contains("foo would overwrite 1 path(s) not linked by pacbrew: bin/stray"), "{output}");
+    assert!(output.contains("bar would overwrite 2 path(s) owned by foo: bin/stray, bin/shared"), "{output}");
+    std::fs::remove_dir_all(&prefix).ok();
+  }
+
+  #[test]
+  fn conflict_after_unpack_removes_staging() {
+    use core_lib::{package::package::PackageInstalled, stage::unpack::staging_dir, transaction::Transaction};
+
+    let prefix = std::env::temp_dir().join(format!("pacbrew-install-staging-test-{}", std::process::id()));
+    let (cellar, db_root) = (prefix.join("Cellar"), prefix.join("db"));
+    std::fs::remove_dir_all(&prefix).ok();
+    std::fs::create_dir_all(prefix.join("bin")).unwrap();
+    std::fs::write(prefix.join("bin/foo"), "stray").unwrap();
+
+    let mut tx = Transaction::begin(&prefix, &db_root).unwrap();
+    tx.stage([staging_dir(&cellar, "foo")]).unwrap();
+    let staged = staging_dir(&cellar, "foo").join("foo/1.0.0");
+    std::fs::create_dir_all(staged.join("bin")).unwrap();
+    std::fs::write(staged.join("bin/foo"), "foo").unwrap();
+    let unpacked = [PackageInstalled { name: "foo".to_string(), dest: staged, version: "1.0.0".to_string(), reloc: Default::default(), unrelocated: Default::default() }];
+    let foo = package("foo", "1.0.0", &[]);
+    let package_index = HashMap::from([("foo", &foo)]);
+
+    let error = check_unpacked(&prefix, &HashMap::new(), &package_index, &unpacked, false).unwrap_err();
+    assert_eq!(abort(tx, error).to_string(), "1 path(s) are in the way, pass --overwrite to link over them");
+    assert!(!staging_dir(&cellar, "foo").exists());
+    assert!(!db_root.join("transaction.json").exists());
+    assert_eq!(std::fs::read_to_string(prefix.join("bin/foo")).unwrap(), "stray");
+    std::fs::remove_dir_all(&prefix).ok();
   }
 }
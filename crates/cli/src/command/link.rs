@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, fmt, path::{Path, PathBuf}};
+
+use core_lib::db;
+use core_lib::package::package::{InstalledPackage, InstalledPackageRecord};
+use core_lib::stage::link;
+use core_lib::transaction::Transaction;
+
+use crate::config::Config;
+
+use super::install::review_conflicts;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct LinkArgs {
+  /// link keg-only formulae into prefix as well
+  #[arg(long)]
+  pub force: bool,
+
+  /// link over files of other packages or not linked by pacbrew
+  #[arg(long)]
+  pub overwrite: bool,
+
+  /// list the symlinks to change without touching prefix
+  #[arg(long)]
+  pub dry_run: bool,
+
+  pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct UnlinkArgs {
+  /// list the symlinks to remove without touching prefix
+  #[arg(long)]
+  pub dry_run: bool,
+
+  pub names: Vec<String>,
+}
+
+/// a symlink to change in prefix, listed by `--dry-run`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SymlinkChange {
+  Link { path: String, target: PathBuf },
+  Unlink { path: String, target: PathBuf },
+}
+
+impl fmt::Display for SymlinkChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Link { path, target } => write!(f, "link   {} -> {}", path, target.display()),
+      Self::Unlink { path, target } => write!(f, "unlink {} -> {}", path, target.display()),
+    }
+  }
+}
+
+/// keg-only formulae are only linked with `force`
+fn check_keg_only(record: &InstalledPackageRecord, force: bool) -> Result<()> {
+  match &record.keg_only {
+    Some(reason) if !force => Err(anyhow!("{} is keg-only: {}, pass --force to link it anyway", record.name, reason)),
+    _ => Ok(()),
+  }
+}
+
+fn read_packages(config: &Config, names: &[String]) -> Result<Vec<InstalledPackage>> {
+  if names.is_empty() {
+    return Err(anyhow!("no package specified"));
+  }
+  names.iter().map(|name| {
+    db::read_installed(&config.base.db, name)?.ok_or_else(|| anyhow!("package not installed: {name}"))
+  }).collect()
+}
+
+/// files to link for the package, only `opt/<name>` for `keg_only`
+fn link_files(prefix: &Path, record: &InstalledPackageRecord, keg_only: bool) -> Result<Vec<String>> {
+  Ok(match keg_only {
+    true => vec![link::opt_file(&record.name)],
+    false => link::owned_files(prefix, &record.name, &record.dest)?,
+  })
+}
+
+/// `opt/<name>` is kept on unlink like `brew unlink`, so dependents linked against it keep working
+fn split_opt(name: &str, files: &[String]) -> (Vec<String>, Vec<String>) {
+  let opt = link::opt_file(name);
+  files.iter().cloned().partition(|file| *file == opt)
+}
+
+fn unlink_changes(prefix: &Path, files: &[String]) -> Vec<SymlinkChange> {
+  files.iter().filter_map(|file| {
+    let target = prefix.join(file).read_link().ok()?;
+    Some(SymlinkChange::Unlink { path: file.clone(), target })
+  }).collect()
+}
+
+fn link_changes(record: &InstalledPackageRecord, files: &[String]) -> Vec<SymlinkChange> {
+  files.iter().map(|file| {
+    SymlinkChange::Link { path: file.clone(), target: link::link_source(&record.name, &record.dest, file) }
+  }).collect()
+}
+
+fn check_conflicts(config: &Config, packages: &[(InstalledPackage, bool, Vec<String>)], overwrite: bool) -> Result<()> {
+  let prefix = &config.base.prefix;
+  let names = packages.iter().map(|(installed, _, _)| installed.record.name.clone()).collect::<Vec<_>>();
+  let owners = db::file_owners(&config.base.db)?
+    .into_iter()
+    .filter(|(_, owner)| !names.contains(owner))
+    .collect::<HashMap<_, _>>();
+  let conflicts = packages.iter()
+    .flat_map(|(installed, _, files)| link::conflicts(prefix, &installed.record.name, files, &owners, &[]))
+    .collect::<Vec<_>>();
+  if !conflicts.is_empty() {
+    review_conflicts(&mut std::io::stderr(), &conflicts)?;
+    if !overwrite {
+      return Err(anyhow!("{} path(s) are in the way, pass --overwrite to link over them", conflicts.len()));
+    }
+  }
+  Ok(())
+}
+
+/// link packages into prefix, with `relink` the recorded links are removed first
+async fn link_packages(config: &Config, args: LinkArgs, relink: bool) -> Result<()> {
+  let prefix = &config.base.prefix;
+  let mut packages = Vec::new();
+  for installed in read_packages(config, &args.names)? {
+    // relink keeps keg-only formulae at `opt/<name>` unless forced, rather than failing
+    let keg_only = match relink {
+      true => installed.record.keg_only.is_some() && !args.force,
+      false => { check_keg_only(&installed.record, args.force)?; false },
+    };
+    let files = link_files(prefix, &installed.record, keg_only)?;
+    packages.push((installed, keg_only, files));
+  }
+  check_conflicts(config, &packages, args.overwrite)?;
+
+  if args.dry_run {
+    for (installed, _, files) in &packages {
+      let mut changes = Vec::new();
+      if relink {
+        changes.extend(unlink_changes(prefix, &installed.files));
+      }
+      changes.extend(link_changes(&installed.record, files));
+      for change in changes {
+        println!("{change}");
+      }
+    }
+    return Ok(());
+  }
+
+  let mut tx = Transaction::begin(prefix, &config.base.db)?;
+  let mut applied = Ok(());
+  for (installed, keg_only, _) in &packages {
+    let pkg = installed.keg();
+    let result = async {
+      if relink {
+        tx.unlink(&installed.files)?;
+      }
+      let linked = tx.link(&pkg, *keg_only, args.overwrite, ()).await?;
+      tx.write_installed(&InstalledPackage { files: linked.files, ..installed.clone() })
+    }.await;
+    if let Err(e) = result {
+      applied = Err(e);
+      break;
+    }
+    eprintln!("{} {} {}", if relink { "relinked" } else { "linked" }, pkg.name, pkg.version);
+  }
+
+  match applied {
+    Ok(()) => tx.commit()?,
+    Err(e) => {
+      warn!(error=%e, "link failed, roll back");
+      tx.rollback()?;
+      return Err(e.into());
+    }
+  }
+  Ok(())
+}
+
+pub async fn run(config: &Config, args: LinkArgs) -> Result<()> {
+  link_packages(config, args, false).await
+}
+
+pub async fn relink(config: &Config, args: LinkArgs) -> Result<()> {
+  link_packages(config, args, true).await
+}
+
+/// remove the links of packages from prefix except `opt/<name>`, the kegs stay installed
+pub fn unlink(config: &Config, args: UnlinkArgs) -> Result<()> {
+  let prefix = &config.base.prefix;
+  let packages = read_packages(config, &args.names)?;
+
+  if args.dry_run {
+    for installed in &packages {
+      let (_, files) = split_opt(&installed.record.name, &installed.files);
+      for change in unlink_changes(prefix, &files) {
+        println!("{change}");
+      }
+    }
+    return Ok(());
+  }
+
+  let mut tx = Transaction::begin(prefix, &config.base.db)?;
+  let mut applied = Ok(());
+  for installed in &packages {
+    let (kept, files) = split_opt(&installed.record.name, &installed.files);
+    let result = tx.unlink(&files)
+      .and_then(|_| tx.write_installed(&InstalledPackage { files: kept, ..installed.clone() }));
+    if let Err(e) = result {
+      applied = Err(e);
+      break;
+    }
+    eprintln!("unlinked {} {}", installed.record.name, installed.record.version);
+  }
+
+  match applied {
+    Ok(()) => tx.commit()?,
+    Err(e) => {
+      warn!(error=%e, "unlink failed, roll back");
+      tx.rollback()?;
+      return Err(e.into());
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use core_lib::package::package::{InstallReason, InstalledPackageRecord};
+
+  use super::{check_keg_only, link_changes, split_opt, unlink_changes, SymlinkChange};
+
+  #[test]
+  fn keg_only_needs_force() {
+    let record = InstalledPackageRecord {
+      name: "openssl@3".to_string(),
+      version: "3.3.0".to_string(),
+      version_scheme: 0,
+      desc: String::new(),
+      license: None,
+      deps: Vec::new(),
+      reason: InstallReason::Dependency,
+      install_date: 0,
+      dest: PathBuf::from("/tmp/openssl@3"),
+      keg_only: Some("this is an alternate version of another formula".to_string()),
+    };
+
+    let error = check_keg_only(&record, false).unwrap_err().to_string();
+    assert!(error.contains("openssl@3 is keg-only: this is an alternate version"), "{error}");
+    assert!(check_keg_only(&record, true).is_ok());
+    assert!(check_keg_only(&InstalledPackageRecord { keg_only: None, ..record }, false).is_ok());
+  }
+
+  #[test]
+  fn unlink_keeps_opt_and_lists_changes() {
+    let prefix = std::env::temp_dir().join(format!("pacbrew-unlink-cli-test-{}", std::process::id()));
+    std::fs::remove_dir_all(&prefix).ok();
+    let keg = prefix.join("Cellar/foo/1.0");
+    std::fs::create_dir_all(keg.join("bin")).unwrap();
+    std::fs::create_dir_all(prefix.join("bin")).unwrap();
+    std::fs::create_dir_all(prefix.join("opt")).unwrap();
+    symlink::symlink_file(keg.join("bin/foo"), prefix.join("bin/foo")).unwrap();
+    symlink::symlink_dir(&keg, prefix.join("opt/foo")).unwrap();
+
+    let files = ["opt/foo", "bin/foo", "bin/missing"].map(String::from);
+    let (kept, unlinked) = split_opt("foo", &files);
+    assert_eq!(kept, vec!["opt/foo".to_string()]);
+    assert_eq!(unlinked, vec!["bin/foo".to_string(), "bin/missing".to_string()]);
+    assert_eq!(unlink_changes(&prefix, &unlinked), vec![
+      SymlinkChange::Unlink { path: "bin/foo".to_string(), target: keg.join("bin/foo") },
+    ]);
+
+    let record = InstalledPackageRecord {
+      name: "foo".to_string(),
+      version: "1.0".to_string(),
+      version_scheme: 0,
+      desc: String::new(),
+      license: None,
+      deps: Vec::new(),
+      reason: InstallReason::Explicit,
+      install_date: 0,
+      dest: keg.clone(),
+      keg_only: None,
+    };
+    let changes = link_changes(&record, &files[..2]);
+    assert_eq!(changes[0].to_string(), format!("link   opt/foo -> {}", keg.display()));
+    assert_eq!(changes[1].to_string(), format!("link   bin/foo -> {}", keg.join("bin/foo").display()));
+
+    std::fs::remove_dir_all(&prefix).ok();
+  }
+}
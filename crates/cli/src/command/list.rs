@@ -1,8 +1,7 @@
 use anyhow::Result;
 use core_lib::db::{self, InstalledVersionStatus};
-use core_lib::io::read::read_formulas;
+use core_lib::package::index::FormulaIndex;
 use core_lib::package::package::InstallReason;
-use core_lib::package::package::PackageVersion;
 
 use crate::config::Config;
 
@@ -13,36 +12,44 @@ pub struct ListArgs {
 
   #[arg(long)]
   pub outdated: bool,
+
+  /// list every installed version, the active one is marked with `*`
+  #[arg(long)]
+  pub versions: bool,
 }
 
 pub fn run(config: &Config, args: ListArgs) -> Result<()> {
   let installed = db::list_installed(&config.base.db)?;
-  let latest_versions = if args.outdated {
-    let formulas = read_formulas(config.base.formula_json())?;
-    formulas.into_iter().map(|formula| {
-      let package = PackageVersion::from(formula);
-      let latest = package.version_full();
-      (package.name, latest)
-    }).collect()
+  let index = if args.outdated {
+    Some(FormulaIndex::load(config.base.formula_json())?)
   } else {
-    std::collections::HashMap::new()
+    None
   };
 
   for pkg in installed {
     if !args.all && pkg.reason != InstallReason::Explicit {
       continue;
     }
-    if args.outdated {
-      let Some(latest) = latest_versions.get(&pkg.name) else {
+    if let Some(index) = &index {
+      let Some(latest) = index.get(&pkg.name)?.filter(|i| i.name == pkg.name).map(|i| i.pkg_version()) else {
         continue;
       };
-      if db::version_status(Some(&pkg.version), latest) != InstalledVersionStatus::Outdated {
+      if db::version_status(Some(&pkg.pkg_version()), &latest) != InstalledVersionStatus::Outdated {
         continue;
       }
       println!("{} {} -> {}", pkg.name, pkg.version, latest);
       continue;
     }
 
+    if args.versions {
+      let versions = db::list_versions(&config.base.db, &pkg.name)?
+        .into_iter()
+        .map(|i| if i.version == pkg.version { format!("{}*", i.version) } else { i.version })
+        .collect::<Vec<_>>();
+      println!("{} {}", pkg.name, versions.join(" "));
+      continue;
+    }
+
     println!("{} {}", pkg.name, pkg.version);
   }
   Ok(())
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use core_lib::{io::{fetch::{FetchReq, MirrorLists}, rank::{self, MirrorScore, ReqKind}}, package::index::FormulaIndex};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct MirrorsArgs {
+  /// send a request to every mirror and record the result before showing scores
+  #[arg(long)]
+  pub bench: bool,
+
+  /// package used to benchmark bottle downloads
+  #[arg(long, default_value = "wget")]
+  pub package: String,
+}
+
+fn format_score(score: &MirrorScore) -> String {
+  let latency = score.latency_ms.map(|i| format!("{:.0}ms", i)).unwrap_or_else(|| "-".to_string());
+  let throughput = score.throughput.map(|i| format!("{:.1}KiB/s", i / 1024.0)).unwrap_or_else(|| "-".to_string());
+  format!("{:>8} {:>14} {:>4}/{:<4} cost {:.0}", latency, throughput, score.success, score.success + score.failure, score.cost())
+}
+
+fn bench_requests(config: &Config, package: &str) -> Result<Vec<FetchReq>> {
+  let mut reqs = vec![FetchReq::Api("formula.json".to_string())];
+  if !config.base.formula_json().exists() {
+    eprintln!("formula.json not found, skip benchmarking packages (run update first)");
+    return Ok(reqs);
+  }
+  let formula = FormulaIndex::load(config.base.formula_json())?.get(package)?;
+  let arch = config.base.arch_policy()?;
+  match formula.and_then(|i| i.find_arch(&arch).cloned()) {
+    Some(pkg) => reqs.push(FetchReq::Package(pkg)),
+    None => eprintln!("package {} not found for {}, skip benchmarking packages", package, config.base.arch),
+  }
+  Ok(reqs)
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = mirrors.len(), bench = args.bench))]
+pub async fn run(config: &Config, mirrors: &MirrorLists, args: MirrorsArgs) -> Result<()> {
+  if args.bench {
+    for req in bench_requests(config, &args.package)? {
+      let kind = ReqKind::from(&req);
+      for mirror in &mirrors.lists {
+        if kind == ReqKind::Api && mirror.api_url("").is_none() {
+          continue;
+        }
+        eprintln!("bench {} {}", mirror.base_url, req);
+        let sample = rank::bench(mirror, &req, &mirrors.policy).await.unwrap_or_else(|e| {
+          warn!(error=%e, mirror=%mirror.base_url, "bench failed");
+          rank::Sample::Failure
+        });
+        mirrors.rank.record(mirror, kind, &sample);
+      }
+    }
+  }
+
+  for kind in [ReqKind::Api, ReqKind::Package] {
+    println!("{}:", match kind { ReqKind::Api => "api", ReqKind::Package => "package" });
+    for i in mirrors.rank.order(&mirrors.lists, kind) {
+      let mirror = &mirrors.lists[i];
+      if kind == ReqKind::Api && mirror.api_url("").is_none() {
+        continue;
+      }
+      println!("  {:48} {}", mirror.base_url, format_score(mirrors.rank.get(mirror).get(kind)));
+    }
+  }
+  Ok(())
+}
@@ -4,22 +4,39 @@ pub mod update;
 pub mod download;
 pub mod import;
 pub mod install;
+pub mod link;
 pub mod list;
 pub mod remove;
+pub mod switch;
 pub mod tree;
 pub mod upgrade;
 pub mod doctor;
+pub mod mirrors;
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct QueryArgs {
   pub names: Vec<String>,
 }
 
+#[derive(Debug, Clone, clap::Args)]
+pub struct InstallArgs {
+  /// link over files of other packages or not linked by pacbrew
+  #[arg(long)]
+  pub overwrite: bool,
+
+  #[command(flatten)]
+  pub query: QueryArgs,
+}
+
 #[derive(Debug, Clone, clap::Args)]
 pub struct RemoveArgs {
   #[arg(long)]
   pub force: bool,
 
+  /// remove only this version of the package, which must not be the active one
+  #[arg(long)]
+  pub version: Option<String>,
+
   pub names: Vec<String>,
 }
 
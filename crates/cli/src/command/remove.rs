@@ -4,7 +4,8 @@ use std::path::Path;
 
 use core_lib::db;
 use core_lib::error::{ErrorExt, IoErrorExt};
-use core_lib::package::package::{InstallReason, InstalledPackageRecord};
+use core_lib::package::package::{InstallReason, InstalledPackage, InstalledPackageRecord};
+use core_lib::stage::link;
 
 use crate::command::RemoveArgs;
 use crate::config::Config;
@@ -22,10 +23,41 @@ struct RemovePlan {
   reasons: HashMap<String, RemoveReason>,
 }
 
+/// remove an inactive version of `name` from db and the cellar, the active one is refused
+fn remove_version(db_root: &Path, name: &str, version: &str) -> Result<InstalledPackage> {
+  if db::read_installed(db_root, name)?.is_some_and(|active| active.record.version == version) {
+    return Err(anyhow!("{name} {version} is the active version, switch to another one first"));
+  }
+  let pkg = db::remove_installed_version(db_root, name, version)?
+    .ok_or_else(|| anyhow!("{name} {version} is not installed"))?;
+  std::fs::remove_dir_all(&pkg.record.dest)
+    .ok_not_found_none()
+    .when(("remove_dir_all", &pkg.record.dest))?;
+  Ok(pkg)
+}
+
+/// remove inactive versions of `name` beyond the newest `keep` ones, returns the versions removed
+pub fn prune_versions(db_root: &Path, name: &str, keep: usize) -> Result<Vec<String>> {
+  let mut removed = Vec::new();
+  for record in db::stale_versions(db_root, name, keep)? {
+    remove_version(db_root, name, &record.version)?;
+    removed.push(record.version);
+  }
+  Ok(removed)
+}
+
 pub fn run(config: &Config, args: RemoveArgs) -> Result<()> {
   if args.names.is_empty() {
     return Err(anyhow!("no package specified"));
   }
+  if let Some(version) = &args.version {
+    let [name] = args.names.as_slice() else {
+      return Err(anyhow!("--version takes exactly one package"));
+    };
+    remove_version(&config.base.db, name, version)?;
+    eprintln!("removed {name} {version}");
+    return Ok(());
+  }
 
   let installed = db::installed_index(&config.base.db)?;
   if installed.is_empty() {
@@ -58,13 +90,12 @@ pub fn run(config: &Config, args: RemoveArgs) -> Result<()> {
   }
 
   for name in &plan.order {
-    let Some(pkg) = db::remove_installed(&config.base.db, name)? else {
-      continue;
-    };
-    unlink_owned_files(&config.base.prefix, &pkg.files)?;
-    std::fs::remove_dir_all(&pkg.record.dest)
-      .ok_not_found_none()
-      .when(("remove_dir_all", &pkg.record.dest))?;
+    for pkg in db::remove_installed(&config.base.db, name)? {
+      link::unlink_files(&config.base.prefix, &pkg.files)?;
+      std::fs::remove_dir_all(&pkg.record.dest)
+        .ok_not_found_none()
+        .when(("remove_dir_all", &pkg.record.dest))?;
+    }
   }
 
   Ok(())
@@ -223,69 +254,13 @@ fn removal_order(
   ordered
 }
 
-fn unlink_owned_files(prefix: &Path, files: &[String]) -> core_lib::error::Result<()> {
-  for rel in files {
-    let path = prefix.join(rel);
-    let metadata = match std::fs::symlink_metadata(&path).ok_not_found() {
-      Ok(Some(metadata)) => metadata,
-      Ok(None) => continue,
-      Err(error) => {
-        return Err(core_lib::error::Error::IoFailed {
-          action: "symlink_metadata",
-          filename: path,
-          error,
-        })
-      }
-    };
-
-    if metadata.file_type().is_symlink() {
-      std::fs::remove_file(&path)
-        .ok_not_found_none()
-        .when(("remove_file", &path))?;
-      cleanup_empty_parents(prefix, &path)?;
-    } else {
-      warn!(path = %path.display(), "skip unlink non-symlink path");
-    }
-  }
-  Ok(())
-}
-
-fn cleanup_empty_parents(prefix: &Path, path: &Path) -> core_lib::error::Result<()> {
-  let mut current = path.parent();
-  while let Some(dir) = current {
-    if dir == prefix {
-      break;
-    }
-    match std::fs::remove_dir(dir) {
-      Ok(()) => {
-        current = dir.parent();
-      }
-      Err(error)
-        if matches!(
-          error.kind(),
-          std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty
-        ) =>
-      {
-        break;
-      }
-      Err(error) => {
-        return Err(core_lib::error::Error::IoFailed {
-          action: "remove_dir",
-          filename: dir.to_path_buf(),
-          error,
-        })
-      }
-    }
-  }
-  Ok(())
-}
-
 #[cfg(test)]
 mod tests {
   use std::path::PathBuf;
 
-  use super::{build_reverse_dependencies, plan_removals, RemoveReason};
-  use core_lib::package::package::{InstallReason, InstalledPackageRecord};
+  use super::{build_reverse_dependencies, plan_removals, prune_versions, remove_version, RemoveReason};
+  use core_lib::db;
+  use core_lib::package::package::{InstallReason, InstalledPackage, InstalledPackageRecord};
 
   fn installed(
     name: &str,
@@ -297,12 +272,14 @@ mod tests {
       InstalledPackageRecord {
         name: name.to_string(),
         version: "1.0.0".to_string(),
+        version_scheme: 0,
         desc: String::new(),
         license: None,
         deps: deps.iter().map(|value| value.to_string()).collect(),
         reason,
         install_date: 0,
         dest: PathBuf::from(format!("/tmp/{name}")),
+        keg_only: None,
       },
     )
   }
@@ -353,4 +330,52 @@ mod tests {
     assert_eq!(plan.reasons.get("dep"), Some(&RemoveReason::AutoPruned));
     assert!(!plan.reasons.contains_key("leaf"));
   }
+
+  fn keg(root: &std::path::Path, version: &str) -> InstalledPackage {
+    let dest = root.join("Cellar/node").join(version);
+    std::fs::create_dir_all(&dest).unwrap();
+    InstalledPackage {
+      record: InstalledPackageRecord { version: version.to_string(), dest, ..installed("node", &[], InstallReason::Explicit).1 },
+      files: Vec::new(),
+      reloc: Default::default(),
+      unrelocated: Default::default(),
+    }
+  }
+
+  #[test]
+  fn remove_single_version() {
+    let root = std::env::temp_dir().join(format!("pacbrew-remove-version-test-{}", std::process::id()));
+    let db_root = root.join("db");
+    std::fs::remove_dir_all(&root).ok();
+    db::write_installed(&db_root, &keg(&root, "20.0.0")).unwrap();
+    db::write_installed(&db_root, &keg(&root, "22.0.0")).unwrap();
+
+    let err = remove_version(&db_root, "node", "22.0.0").unwrap_err();
+    assert_eq!(err.to_string(), "node 22.0.0 is the active version, switch to another one first");
+    assert!(remove_version(&db_root, "node", "18.0.0").is_err());
+
+    remove_version(&db_root, "node", "20.0.0").unwrap();
+    assert!(!root.join("Cellar/node/20.0.0").exists());
+    assert!(root.join("Cellar/node/22.0.0").exists());
+    assert_eq!(db::list_versions(&db_root, "node").unwrap().len(), 1);
+    std::fs::remove_dir_all(&root).ok();
+  }
+
+  #[test]
+  fn prune_keeps_newest_previous_versions() {
+    let root = std::env::temp_dir().join(format!("pacbrew-prune-test-{}", std::process::id()));
+    let db_root = root.join("db");
+    std::fs::remove_dir_all(&root).ok();
+    for version in ["18.0.0", "20.0.0", "22.0.0"] {
+      db::write_installed(&db_root, &keg(&root, version)).unwrap();
+    }
+
+    assert_eq!(prune_versions(&db_root, "node", 1).unwrap(), vec!["18.0.0"]);
+    assert!(!root.join("Cellar/node/18.0.0").exists());
+    assert!(prune_versions(&db_root, "node", 1).unwrap().is_empty());
+    assert_eq!(prune_versions(&db_root, "node", 0).unwrap(), vec!["20.0.0"]);
+    assert_eq!(db::read_installed(&db_root, "node").unwrap().unwrap().record.version, "22.0.0");
+    assert!(root.join("Cellar/node/22.0.0").exists());
+    std::fs::remove_dir_all(&root).ok();
+  }
 }
@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use core_lib::db;
+use core_lib::package::package::{InstalledPackage, InstalledPackageRecord};
+use core_lib::stage::link;
+use core_lib::transaction::Transaction;
+
+use crate::config::Config;
+
+use super::install::review_conflicts;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct SwitchArgs {
+  /// link over files of other packages or not linked by pacbrew
+  #[arg(long)]
+  pub overwrite: bool,
+
+  pub name: String,
+  pub version: String,
+}
+
+fn version_not_installed(versions: &[InstalledPackageRecord], name: &str, version: &str) -> anyhow::Error {
+  let installed = versions.iter().map(|record| record.version.as_str()).collect::<Vec<_>>();
+  anyhow!("{name} {version} is not installed, installed versions: {}", installed.join(", "))
+}
+
+/// only `opt/<name>` is linked for the new version if the active one has nothing else linked,
+/// which keeps keg-only and unlinked formulae as they are
+fn opt_only(active: &InstalledPackage) -> bool {
+  let opt = link::opt_file(&active.record.name);
+  active.files.iter().all(|file| *file == opt)
+}
+
+/// make another installed version of a formula the active one, `opt/<name>` and the links are moved to it
+pub async fn run(config: &Config, args: SwitchArgs) -> Result<()> {
+  let prefix = &config.base.prefix;
+  let active = db::read_installed(&config.base.db, &args.name)?
+    .ok_or_else(|| anyhow!("package not installed: {}", args.name))?;
+  if active.record.version == args.version {
+    eprintln!("{} {} is already active", args.name, args.version);
+    return Ok(());
+  }
+  let Some(target) = db::read_installed_version(&config.base.db, &args.name, &args.version)? else {
+    return Err(version_not_installed(&db::list_versions(&config.base.db, &args.name)?, &args.name, &args.version));
+  };
+  if !target.record.dest.is_dir() {
+    return Err(anyhow!("keg of {} {} is missing at {}", args.name, args.version, target.record.dest.display()));
+  }
+
+  let keg_only = opt_only(&active);
+  let files = match keg_only {
+    true => vec![link::opt_file(&args.name)],
+    false => link::owned_files(prefix, &args.name, &target.record.dest)?,
+  };
+  let owners = db::file_owners(&config.base.db)?
+    .into_iter()
+    .filter(|(_, owner)| *owner != args.name)
+    .collect::<HashMap<_, _>>();
+  let conflicts = link::conflicts(prefix, &args.name, &files, &owners, &[]);
+  if !conflicts.is_empty() {
+    review_conflicts(&mut std::io::stderr(), &conflicts)?;
+    if !args.overwrite {
+      return Err(anyhow!("{} path(s) are in the way, pass --overwrite to link over them", conflicts.len()));
+    }
+  }
+
+  let mut tx = Transaction::begin(prefix, &config.base.db)?;
+  let result = async {
+    let linked = tx.activate(&target.keg(), keg_only, args.overwrite, ()).await?;
+    tx.write_installed(&InstalledPackage { files: linked.files, ..target.clone() })
+  }.await;
+  match result {
+    Ok(()) => tx.commit()?,
+    Err(e) => {
+      warn!(error=%e, "switch failed, roll back");
+      tx.rollback()?;
+      return Err(e.into());
+    }
+  }
+  eprintln!("switched {} from {} to {}", args.name, active.record.version, args.version);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use core_lib::package::package::{InstallReason, InstalledPackage, InstalledPackageRecord};
+
+  use super::{opt_only, version_not_installed};
+
+  fn record(version: &str) -> InstalledPackageRecord {
+    InstalledPackageRecord {
+      name: "node".to_string(),
+      version: version.to_string(),
+      version_scheme: 0,
+      desc: String::new(),
+      license: None,
+      deps: Vec::new(),
+      reason: InstallReason::Explicit,
+      install_date: 0,
+      dest: PathBuf::from(format!("/tmp/node/{version}")),
+      keg_only: None,
+    }
+  }
+
+  #[test]
+  fn switch_to_installed_version() {
+    let versions = [record("20.1.0"), record("22.3.0")];
+    let error = version_not_installed(&versions, "node", "18.0.0").to_string();
+    assert_eq!(error, "node 18.0.0 is not installed, installed versions: 20.1.0, 22.3.0");
+
+    let active = |files: &[&str]| InstalledPackage {
+      record: record("22.3.0"),
+      files: files.iter().map(|i| i.to_string()).collect(),
+      reloc: Default::default(),
+      unrelocated: Default::default(),
+    };
+    assert!(opt_only(&active(&["opt/node"])));
+    assert!(opt_only(&active(&[])));
+    assert!(!opt_only(&active(&["opt/node", "bin/node"])));
+  }
+}
@@ -1,8 +1,8 @@
 use std::collections::{HashMap, HashSet};
 
 use anyhow::{anyhow, Result};
-use core_lib::io::read::read_formulas;
-use core_lib::package::formula::Formula;
+use core_lib::package::index::FormulaIndex;
+use core_lib::package::package::PackageVersion;
 
 use crate::config::Config;
 
@@ -15,8 +15,8 @@ pub struct TreeArgs {
 }
 
 pub fn run(config: &Config, args: TreeArgs) -> Result<()> {
-  let formulas = read_formulas(config.base.formula_json())?;
-  let index = build_formula_index(&formulas);
+  let index = FormulaIndex::load(config.base.formula_json())?;
+  let formulas = index.iter().collect::<core_lib::error::Result<Vec<_>>>()?;
 
   let root = resolve_formula_name(&index, &args.name)?;
   println!("{root}");
@@ -33,50 +33,20 @@ pub fn run(config: &Config, args: TreeArgs) -> Result<()> {
   Ok(())
 }
 
-fn build_formula_index<'a>(formulas: &'a [Formula]) -> HashMap<&'a str, &'a Formula> {
-  let mut index = formulas
-    .iter()
-    .map(|formula| (formula.name.as_str(), formula))
-    .collect::<HashMap<_, _>>();
-
-  index.extend(
-    formulas
-      .iter()
-      .flat_map(|formula| formula.oldname.iter().map(move |name| (name.as_str(), formula))),
-  );
-  index.extend(
-    formulas
-      .iter()
-      .flat_map(|formula| formula.oldnames.iter().map(move |name| (name.as_str(), formula))),
-  );
-  index.extend(
-    formulas
-      .iter()
-      .flat_map(|formula| formula.aliases.iter().map(move |name| (name.as_str(), formula))),
-  );
-  index.extend(
-    formulas
-      .iter()
-      .map(|formula| (formula.full_name.as_str(), formula)),
-  );
-
-  index
-}
-
-fn resolve_formula_name(index: &HashMap<&str, &Formula>, name: &str) -> Result<String> {
+fn resolve_formula_name(index: &FormulaIndex, name: &str) -> Result<String> {
   index
-    .get(name)
-    .map(|formula| formula.name.clone())
+    .get(name)?
+    .map(|formula| formula.name)
     .ok_or_else(|| anyhow!("package not found: {name}"))
 }
 
-fn normalize_name(index: &HashMap<&str, &Formula>, name: &str) -> Option<String> {
-  index.get(name).map(|formula| formula.name.clone())
+fn normalize_name(index: &FormulaIndex, name: &str) -> Option<String> {
+  index.get(name).ok().flatten().map(|formula| formula.name)
 }
 
 fn build_dependency_graph(
-  formulas: &[Formula],
-  index: &HashMap<&str, &Formula>,
+  formulas: &[PackageVersion],
+  index: &FormulaIndex,
 ) -> HashMap<String, Vec<String>> {
   let mut graph = formulas
     .iter()
@@ -85,7 +55,7 @@ fn build_dependency_graph(
 
   for formula in formulas {
     let mut deps = formula
-      .dependencies
+      .deps
       .iter()
       .filter_map(|dependency| normalize_name(index, dependency))
       .collect::<Vec<_>>();
@@ -100,8 +70,8 @@ fn build_dependency_graph(
 }
 
 fn build_reverse_graph(
-  formulas: &[Formula],
-  index: &HashMap<&str, &Formula>,
+  formulas: &[PackageVersion],
+  index: &FormulaIndex,
 ) -> HashMap<String, Vec<String>> {
   let mut graph = formulas
     .iter()
@@ -109,7 +79,7 @@ fn build_reverse_graph(
     .collect::<HashMap<_, _>>();
 
   for formula in formulas {
-    for dependency in &formula.dependencies {
+    for dependency in &formula.deps {
       let Some(dependency) = normalize_name(index, dependency) else {
         continue;
       };
@@ -1,18 +1,27 @@
 use anyhow::Result;
-use core_lib::{io::fetch::MirrorLists, stage::update_db, ui::with_progess_bar};
+use core_lib::{io::{fetch::MirrorLists, jws}, stage::update_db, ui::with_progess_bar};
 
 use crate::{command::PbStyle, config::Config, ACTIVE_PB};
 
 
 #[tracing::instrument(level = "debug", skip_all, fields(mirrors.len=mirrors.len()))]
 pub async fn run(config: &Config, mirrors: &MirrorLists) -> Result<()> {
-  with_progess_bar(
+  let keys = jws::load_keys(&config.signature.keys)?;
+  let updated = with_progess_bar(
     ACTIVE_PB.clone(),
     Some(PbStyle::Bytes.style()),
     None,
-    |tracker| update_db::exec(mirrors, &config.base.cache, tracker),
+    |tracker| update_db::exec(
+      update_db::Args::new(mirrors, &config.base.cache)
+        .verify(&keys, config.signature.required),
+      tracker,
+    ),
     (),
   ).await.unwrap();
-  info!("update formula.json success");
+  if updated {
+    info!("update formula.json success");
+  } else {
+    info!("formula.json already up to date");
+  }
   Ok(())
 }
@@ -1,28 +1,22 @@
-use std::collections::HashMap;
-
 use anyhow::Result;
-use core_lib::{db::{self, InstalledVersionStatus}, io::{fetch::MirrorLists, read::read_formulas}, package::package::PackageVersion};
+use core_lib::{db::{self, InstalledVersionStatus}, io::fetch::MirrorLists, package::index::FormulaIndex};
 
 use crate::config::Config;
 
-use super::QueryArgs;
+use super::{InstallArgs, QueryArgs};
 
 #[tracing::instrument(level = "debug", skip_all, fields(arch = %config.base.arch))]
 pub async fn run(config: &Config, mirrors: &MirrorLists) -> Result<()> {
   let installed = db::list_installed(&config.base.db)?;
-  let formulas = read_formulas(config.base.formula_json())?;
-  let latest_versions: HashMap<_, _> = formulas.into_iter().map(|formula| {
-    let package = PackageVersion::from(formula);
-    let latest = package.version_full();
-    (package.name, latest)
-  }).collect();
+  let index = FormulaIndex::load(config.base.formula_json())?;
 
-  let outdated = installed.into_iter()
-    .filter_map(|pkg| match latest_versions.get(&pkg.name) {
-      Some(latest) if db::version_status(Some(&pkg.version), latest) == InstalledVersionStatus::Outdated => Some(pkg.name),
-      _ => None,
-    })
-    .collect::<Vec<_>>();
+  let mut outdated = Vec::new();
+  for pkg in installed {
+    let latest = index.get(&pkg.name)?.filter(|i| i.name == pkg.name).map(|i| i.pkg_version());
+    if matches!(latest, Some(latest) if db::version_status(Some(&pkg.pkg_version()), &latest) == InstalledVersionStatus::Outdated) {
+      outdated.push(pkg.name);
+    }
+  }
 
   if outdated.is_empty() {
     eprintln!("no outdated packages");
@@ -30,6 +24,6 @@ pub async fn run(config: &Config, mirrors: &MirrorLists) -> Result<()> {
   }
 
   eprintln!("upgrading {} package(s): {}", outdated.len(), outdated.join(", "));
-  super::install::run(config, mirrors, QueryArgs { names: outdated }).await?;
+  super::install::run(config, mirrors, InstallArgs { overwrite: false, query: QueryArgs { names: outdated } }).await?;
   Ok(())
 }
@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
-use core_lib::package::mirror::MirrorType;
+use core_lib::{io::fetch::NetworkPolicy, package::{arch::{ArchPolicy, BottleTag}, host::Host, mirror::MirrorType}};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Mirror {
@@ -17,6 +17,10 @@ pub struct Config {
   pub base: BaseConfig,
   #[serde(default)]
   pub network: NetworkConfig,
+  #[serde(default)]
+  pub signature: SignatureConfig,
+  #[serde(default)]
+  pub relocation: RelocationConfig,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -26,13 +30,38 @@ pub struct BaseConfig {
   #[serde(alias="cellar")]
   pub local_opt: Option<PathBuf>,
   pub db: PathBuf,
+  /// bottle tag like `arm64_sequoia`, detected from the host when `auto`
+  #[serde(default = "arch_default")]
   pub arch: String,
+  /// bottle tags to try when a formula has no bottle for `arch`, older macOS releases by default
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub arch_fallback: Option<Vec<BottleTag>>,
+  /// previous versions of a formula kept after install or upgrade, older ones are removed
+  #[serde(default = "keep_versions_default")]
+  pub keep_versions: usize,
 }
 
+pub const ARCH_AUTO: &str = "auto";
+fn arch_default() -> String { ARCH_AUTO.to_string() }
+const fn keep_versions_default() -> usize { 1 }
+
 impl BaseConfig {
   pub fn formula_json(&self) -> PathBuf { self.cache.join("formula.json") }
   pub fn local_opt(&self) -> PathBuf { self.local_opt.clone().unwrap_or_else(|| self.prefix.join("local").join("opt")) }
   pub fn cache_pkg(&self) -> PathBuf { self.cache.join("pkg") }
+  pub fn arch_tag(&self) -> core_lib::error::Result<BottleTag> {
+    match self.arch.as_str() {
+      ARCH_AUTO => Host::detect()?.tag(),
+      arch => arch.parse(),
+    }
+  }
+  pub fn arch_policy(&self) -> core_lib::error::Result<ArchPolicy> {
+    let policy = ArchPolicy::new(self.arch_tag()?);
+    Ok(match &self.arch_fallback {
+      Some(fallback) => policy.fallback(fallback.clone()),
+      None => policy,
+    })
+  }
 }
 
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
@@ -45,18 +74,80 @@ impl LogConfig {
   pub fn is_empty(&self) -> bool { self.rust_log.is_none() && self.file.is_none() }
 }
 
+/// verify the signed formula.jws.json with `keys`, which map key ids like `homebrew-1` to PEM files.
+/// without `required`, mirrors not serving the signed index fall back to the unsigned one.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SignatureConfig {
+  #[serde(default)]
+  pub required: bool,
+  #[serde(default)]
+  pub keys: BTreeMap<String, PathBuf>,
+}
+
+/// placeholders in bottles and the values to replace with, like `HOMEBREW_PERL = "/usr/bin/perl"`,
+/// which add to or override the defaults.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RelocationConfig {
+  #[serde(default)]
+  pub placeholders: BTreeMap<String, String>,
+  /// relocate bottles built for another fixed cellar anyway, paths in them may be left broken
+  #[serde(default)]
+  pub force: bool,
+}
+
+/// all durations are in seconds
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct NetworkConfig {
   #[serde(default = "retry_default")]
   pub retry: usize,
+  /// max number of packages probing or downloading at the same time
+  #[serde(default = "parallel_default")]
+  pub parallel: usize,
+  /// first backoff between retry rounds, doubled every round
+  #[serde(default = "backoff_default")]
+  pub backoff: u64,
+  #[serde(default = "backoff_max_default")]
+  pub backoff_max: u64,
+  #[serde(default = "connect_timeout_default")]
+  pub connect_timeout: u64,
+  #[serde(default = "read_timeout_default")]
+  pub read_timeout: u64,
+  /// abort a download if it receives nothing in this duration
+  #[serde(default = "stall_timeout_default")]
+  pub stall_timeout: u64,
 }
 
 impl Default for NetworkConfig {
   fn default() -> Self {
     Self {
       retry: retry_default(),
+      parallel: parallel_default(),
+      backoff: backoff_default(),
+      backoff_max: backoff_max_default(),
+      connect_timeout: connect_timeout_default(),
+      read_timeout: read_timeout_default(),
+      stall_timeout: stall_timeout_default(),
+    }
+  }
+}
+
+impl NetworkConfig {
+  pub fn policy(&self) -> NetworkPolicy {
+    NetworkPolicy {
+      retry: self.retry,
+      backoff: Duration::from_secs(self.backoff),
+      backoff_max: Duration::from_secs(self.backoff_max),
+      connect_timeout: Duration::from_secs(self.connect_timeout),
+      read_timeout: Duration::from_secs(self.read_timeout),
+      stall_timeout: Duration::from_secs(self.stall_timeout),
     }
   }
 }
 
 const fn retry_default() -> usize { 5 }
+const fn parallel_default() -> usize { 4 }
+const fn backoff_default() -> u64 { 1 }
+const fn backoff_max_default() -> u64 { 30 }
+const fn connect_timeout_default() -> u64 { 10 }
+const fn read_timeout_default() -> u64 { 30 }
+const fn stall_timeout_default() -> u64 { 30 }
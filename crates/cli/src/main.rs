@@ -3,7 +3,7 @@
 use std::{path::Path, sync::{Arc, RwLock}};
 
 use clap::Parser;
-use core_lib::{io::{fetch::MirrorLists, read::read_toml}, package::mirror::MirrorServer, ui::bar::{ActiveSuspendable, PbWriter}};
+use core_lib::{lock::{Lock, LockMode}, io::{fetch::MirrorLists, rank::MirrorRank, read::read_toml}, package::mirror::MirrorServer, ui::bar::{ActiveSuspendable, PbWriter}};
 use tracing_subscriber::fmt::format::FmtSpan;
 
 pub mod config;
@@ -13,6 +13,9 @@ pub mod command;
 pub struct Args {
   #[command(subcommand)]
   pub command: Command,
+  /// wait for the lock held by another pacbrew instead of failing
+  #[arg(long, global = true)]
+  pub wait: bool,
 }
 
 #[derive(Debug, Clone, clap::Subcommand)]
@@ -20,12 +23,32 @@ pub enum Command {
   Update,
   Download(command::QueryArgs),
   Import(command::QueryArgs),
-  Install(command::QueryArgs),
+  Install(command::InstallArgs),
   Doctor,
   Remove(command::RemoveArgs),
+  Link(command::link::LinkArgs),
+  Unlink(command::link::UnlinkArgs),
+  Relink(command::link::LinkArgs),
+  Switch(command::switch::SwitchArgs),
   List(command::list::ListArgs),
   Tree(command::tree::TreeArgs),
   Upgrade,
+  Mirrors(command::mirrors::MirrorsArgs),
+}
+
+impl Command {
+  /// commands changing db, the link tree or the download cache lock exclusively
+  pub fn lock_mode(&self) -> LockMode {
+    match self {
+      Self::Link(args) | Self::Relink(args) if args.dry_run => LockMode::Shared,
+      Self::Unlink(args) if args.dry_run => LockMode::Shared,
+      Self::Update | Self::Import(_) | Self::Install(_) | Self::Doctor | Self::Remove(_) | Self::Upgrade => LockMode::Exclusive,
+      Self::Link(_) | Self::Unlink(_) | Self::Relink(_) | Self::Switch(_) => LockMode::Exclusive,
+      // partial downloads are resumed by appending, and mirror samples are recorded
+      Self::Download(_) | Self::Mirrors(_) => LockMode::Exclusive,
+      Self::List(_) | Self::Tree(_) => LockMode::Shared,
+    }
+  }
 }
 
 lazy_static::lazy_static! {
@@ -69,15 +92,28 @@ async fn main() {
   }
   let args = Args::parse();
   info!(?config, ?args);
-  let mirrors = MirrorLists {
-    lists: config.mirror_list.iter().map(|i| MirrorServer::new(i.r#type, &i.url, i.api_url.as_deref())).collect()
+  let lock = match Lock::acquire(&config.base.db, args.command.lock_mode(), args.wait) {
+    Ok(lock) => lock,
+    Err(e @ core_lib::error::Error::Locked { .. }) if !args.wait => {
+      eprintln!("{e}, pass --wait to wait for it");
+      std::process::exit(1);
+    }
+    Err(e) => {
+      eprintln!("{e}");
+      std::process::exit(1);
+    }
   };
+  let mirrors = MirrorLists::new(
+    config.mirror_list.iter().map(|i| MirrorServer::new(i.r#type, &i.url, i.api_url.as_deref())).collect(),
+    config.network.policy(),
+  ).rank(MirrorRank::load(&config.base.cache));
   match args.command {
     Command::Update => command::update::run(&config, &mirrors).await.unwrap(),
     Command::Download(query) => command::download::run(&config, &mirrors, query).await.unwrap(),
     Command::Import(query) => command::import::run(&config, query).unwrap(),
-    Command::Install(query) => {
-      let installed = command::install::run(&config, &mirrors, query.clone()).await.unwrap();
+    Command::Install(args) => {
+      let query = args.query.clone();
+      let installed = command::install::run(&config, &mirrors, args).await.unwrap();
       if installed {
         if let Some(log_file) = config.log.file.as_ref() {
         use std::io::Write;
@@ -89,8 +125,19 @@ async fn main() {
     },
     Command::Doctor => command::doctor::run(&config).unwrap(),
     Command::Remove(args) => command::remove::run(&config, args).unwrap(),
+    Command::Link(args) => command::link::run(&config, args).await.unwrap(),
+    Command::Unlink(args) => command::link::unlink(&config, args).unwrap(),
+    Command::Relink(args) => command::link::relink(&config, args).await.unwrap(),
+    Command::Switch(args) => command::switch::run(&config, args).await.unwrap(),
     Command::List(args) => command::list::run(&config, args).unwrap(),
     Command::Tree(args) => command::tree::run(&config, args).unwrap(),
     Command::Upgrade => command::upgrade::run(&config, &mirrors).await.unwrap(),
+    Command::Mirrors(args) => command::mirrors::run(&config, &mirrors, args).await.unwrap(),
+  }
+  // other instances may be saving the scores as well unless the lock is exclusive
+  if lock.mode() == LockMode::Exclusive {
+    if let Err(e) = mirrors.rank.save() {
+      warn!(error=%e, "failed to save mirror scores");
+    }
   }
 }
@@ -1,23 +1,31 @@
-use std::{collections::HashMap, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{error::{ErrorExt, IoErrorExt, Result}, io::{read::{read_toml, write_to_file, write_toml}, relocate::RelocateType}, package::package::{InstalledPackage, InstalledPackageRecord}};
+use crate::{error::{ErrorExt, IoErrorExt, Result}, io::{read::{read_toml, write_to_file, write_toml}, relocate::RelocateType}, package::{package::{InstalledPackage, InstalledPackageRecord}, version::Version}};
 
 const LOCAL_DIR: &str = "local";
 const RECORD_FILE: &str = "desc.toml";
 const FILES_FILE: &str = "files.txt";
 const RELOCATION_FILE: &str = "reloc.txt";
+/// `active/<name>` holds the active version of `name`, which `opt/<name>` and the links point to
+const ACTIVE_DIR: &str = "active";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InstalledVersionStatus {
   Missing,
   Satisfied,
   Outdated,
+  /// the installed version is newer than the candidate
+  Newer,
 }
 
 fn local_dir(root: &Path) -> PathBuf {
   root.join(LOCAL_DIR)
 }
 
+fn active_path(root: &Path, name: &str) -> PathBuf {
+  root.join(ACTIVE_DIR).join(name)
+}
+
 fn package_dir(root: &Path, name: &str, version: &str) -> PathBuf {
   local_dir(root).join(format!("{}-{}", name, version))
 }
@@ -38,15 +46,58 @@ fn relocation_line(path: &Path, ty: RelocateType) -> Option<String> {
   match ty {
     RelocateType::Text => Some(format!("text:{}", path.display())),
     RelocateType::MachO => Some(format!("binary:{}", path.display())),
+    RelocateType::Elf => Some(format!("elf:{}", path.display())),
+    RelocateType::Binary => Some(format!("data:{}", path.display())),
     RelocateType::None => None,
   }
 }
 
+/// escape `\\`, tab and line breaks, so a field stays in its line
+fn escape_field(value: &str) -> String {
+  let mut result = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => result.push_str("\\\\"),
+      '\t' => result.push_str("\\t"),
+      '\n' => result.push_str("\\n"),
+      '\r' => result.push_str("\\r"),
+      c => result.push(c),
+    }
+  }
+  result
+}
+
+fn unescape_field(value: &str) -> String {
+  let mut result = String::with_capacity(value.len());
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      result.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('t') => result.push('\t'),
+      Some('n') => result.push('\n'),
+      Some('r') => result.push('\r'),
+      Some(c) => result.push(c),
+      None => result.push('\\'),
+    }
+  }
+  result
+}
+
+/// a string with placeholders left in `path`, `unrelocated:<path>\t<value>`, both escaped by `escape_field`
+fn unrelocated_line(path: &Path, value: &str) -> String {
+  format!("unrelocated:{}\t{}", escape_field(&path.to_string_lossy()), escape_field(value))
+}
+
 fn parse_relocation_line(line: &str) -> Option<(PathBuf, RelocateType)> {
   let (prefix, path) = line.split_once(':')?;
   let ty = match prefix {
     "text" => RelocateType::Text,
     "binary" => RelocateType::MachO,
+    "elf" => RelocateType::Elf,
+    "data" => RelocateType::Binary,
     _ => return None,
   };
   if path.is_empty() {
@@ -55,27 +106,17 @@ fn parse_relocation_line(line: &str) -> Option<(PathBuf, RelocateType)> {
   Some((PathBuf::from(path), ty))
 }
 
+fn parse_unrelocated_line(line: &str) -> Option<(PathBuf, String)> {
+  let (path, value) = line.strip_prefix("unrelocated:")?.split_once('\t')?;
+  Some((PathBuf::from(unescape_field(path)), unescape_field(value)))
+}
+
 pub fn now_unix() -> u64 {
   SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
-pub fn write_installed(root: &Path, package: &InstalledPackage) -> Result<()> {
-  let local_root = local_dir(root);
-  std::fs::create_dir_all(&local_root).when(("create_dir_all", &local_root))?;
-
-  let prefix = format!("{}-", package.record.name);
-  for entry in std::fs::read_dir(&local_root).when(("read_dir", &local_root))? {
-    let entry = entry.when(("read_dir", &local_root))?;
-    let path = entry.path();
-    if !entry.file_type().when(("file_type", &path))?.is_dir() {
-      continue;
-    }
-    let dir_name = entry.file_name().to_string_lossy().to_string();
-    if dir_name.starts_with(&prefix) && dir_name != format!("{}{}", prefix, package.record.version) {
-      std::fs::remove_dir_all(&path).when(("remove_dir_all", &path))?;
-    }
-  }
-
+/// write the record of a version, other versions and the active one are left alone
+pub fn write_record(root: &Path, package: &InstalledPackage) -> Result<()> {
   let pkg_dir = package_dir(root, &package.record.name, &package.record.version);
   std::fs::create_dir_all(&pkg_dir).when(("create_dir_all", &pkg_dir))?;
   write_toml(record_path(root, &package.record.name, &package.record.version), &package.record, true)?;
@@ -85,23 +126,46 @@ pub fn write_installed(root: &Path, package: &InstalledPackage) -> Result<()> {
     format!("{}\n", package.files.join("\n"))
   };
   write_to_file(files_path(root, &package.record.name, &package.record.version), files.as_bytes(), true)?;
-  let reloc = if package.reloc.is_empty() {
+  let lines = package.reloc.iter()
+    .filter_map(|(path, ty)| relocation_line(path, *ty))
+    .chain(package.unrelocated.iter().flat_map(|(path, values)| values.iter().map(|value| unrelocated_line(path, value))))
+    .collect::<Vec<_>>();
+  let reloc = if lines.is_empty() {
     String::new()
   } else {
-    let lines = package.reloc.iter()
-      .filter_map(|(path, ty)| relocation_line(path, *ty))
-      .collect::<Vec<_>>();
-    if lines.is_empty() {
-      String::new()
-    } else {
-      format!("{}\n", lines.join("\n"))
-    }
+    format!("{}\n", lines.join("\n"))
   };
   write_to_file(relocation_path(root, &package.record.name, &package.record.version), reloc.as_bytes(), true)?;
   Ok(())
 }
 
-pub fn list_installed(root: &Path) -> Result<Vec<InstalledPackageRecord>> {
+/// write the record of a version and make it the active one
+pub fn write_installed(root: &Path, package: &InstalledPackage) -> Result<()> {
+  write_record(root, package)?;
+  set_active(root, &package.record.name, Some(&package.record.version))
+}
+
+pub fn active_version(root: &Path, name: &str) -> Result<Option<String>> {
+  let path = active_path(root, name);
+  Ok(std::fs::read_to_string(&path).ok_not_found().when(("read_to_string", &path))?.map(|i| i.trim().to_string()))
+}
+
+/// mark `version` as the active version of `name`, or clear the mark with `None`
+pub fn set_active(root: &Path, name: &str, version: Option<&str>) -> Result<()> {
+  let path = active_path(root, name);
+  match version {
+    Some(version) => {
+      let active_root = root.join(ACTIVE_DIR);
+      std::fs::create_dir_all(&active_root).when(("create_dir_all", &active_root))?;
+      write_to_file(&path, format!("{}\n", version).as_bytes(), true)?;
+    },
+    None => std::fs::remove_file(&path).ok_not_found_none().when(("remove_file", &path))?,
+  }
+  Ok(())
+}
+
+/// records of every installed version
+fn list_records(root: &Path) -> Result<Vec<InstalledPackageRecord>> {
   let local_root = local_dir(root);
   if !local_root.exists() {
     return Ok(Vec::new());
@@ -120,7 +184,46 @@ pub fn list_installed(root: &Path) -> Result<Vec<InstalledPackageRecord>> {
     }
     result.push(read_toml(&record_file)?);
   }
-  result.sort_by(|left, right| left.name.cmp(&right.name));
+  result.sort_by(|left, right| left.name.cmp(&right.name).then_with(|| left.pkg_version().cmp(&right.pkg_version())));
+  Ok(result)
+}
+
+/// the active one of `versions` sorted oldest first, the newest if none is marked, e.g. db written before versions are kept
+fn pick_active(root: &Path, name: &str, mut versions: Vec<InstalledPackageRecord>) -> Result<Option<InstalledPackageRecord>> {
+  let active = active_version(root, name)?;
+  let index = active.and_then(|active| versions.iter().position(|record| record.version == active));
+  Ok(match index {
+    Some(index) => Some(versions.swap_remove(index)),
+    None => versions.pop(),
+  })
+}
+
+/// all installed versions of `name`, oldest first
+pub fn list_versions(root: &Path, name: &str) -> Result<Vec<InstalledPackageRecord>> {
+  Ok(list_records(root)?.into_iter().filter(|record| record.name == name).collect())
+}
+
+/// inactive versions of `name` beyond the newest `keep` ones, oldest first
+pub fn stale_versions(root: &Path, name: &str, keep: usize) -> Result<Vec<InstalledPackageRecord>> {
+  let versions = list_versions(root, name)?;
+  let Some(active) = pick_active(root, name, versions.clone())? else {
+    return Ok(Vec::new());
+  };
+  let mut inactive = versions.into_iter().filter(|record| record.version != active.version).collect::<Vec<_>>();
+  inactive.truncate(inactive.len().saturating_sub(keep));
+  Ok(inactive)
+}
+
+/// records of the active version of every package
+pub fn list_installed(root: &Path) -> Result<Vec<InstalledPackageRecord>> {
+  let mut versions = BTreeMap::<String, Vec<_>>::new();
+  for record in list_records(root)? {
+    versions.entry(record.name.clone()).or_default().push(record);
+  }
+  let mut result = Vec::new();
+  for (name, versions) in versions {
+    result.extend(pick_active(root, &name, versions)?);
+  }
   Ok(result)
 }
 
@@ -131,69 +234,91 @@ pub fn installed_index(root: &Path) -> Result<HashMap<String, InstalledPackageRe
     .collect())
 }
 
-pub fn version_status(installed_version: Option<&str>, candidate_version: &str) -> InstalledVersionStatus {
-  match installed_version {
+pub fn version_status(installed_version: Option<&Version>, candidate_version: &Version) -> InstalledVersionStatus {
+  match installed_version.map(|version| version.cmp(candidate_version)) {
     None => InstalledVersionStatus::Missing,
-    Some(version) if version == candidate_version => InstalledVersionStatus::Satisfied,
-    Some(_) => InstalledVersionStatus::Outdated,
+    Some(std::cmp::Ordering::Equal) => InstalledVersionStatus::Satisfied,
+    Some(std::cmp::Ordering::Less) => InstalledVersionStatus::Outdated,
+    Some(std::cmp::Ordering::Greater) => InstalledVersionStatus::Newer,
   }
 }
 
+/// the active version of `name`
 pub fn read_installed(root: &Path, name: &str) -> Result<Option<InstalledPackage>> {
-  let local_root = local_dir(root);
-  if !local_root.exists() {
+  let Some(record) = pick_active(root, name, list_versions(root, name)?)? else {
     return Ok(None);
-  }
+  };
+  read_installed_version(root, name, &record.version)
+}
 
-  for entry in std::fs::read_dir(&local_root).when(("read_dir", &local_root))? {
-    let entry = entry.when(("read_dir", &local_root))?;
-    let path = entry.path();
-    if !entry.file_type().when(("file_type", &path))?.is_dir() {
-      continue;
-    }
+pub fn read_installed_version(root: &Path, name: &str, version: &str) -> Result<Option<InstalledPackage>> {
+  let path = package_dir(root, name, version);
+  let record_file = path.join(RECORD_FILE);
+  if !record_file.exists() {
+    return Ok(None);
+  }
+  let record: InstalledPackageRecord = read_toml(&record_file)?;
+  let files = std::fs::read_to_string(path.join(FILES_FILE))
+    .map(|content| content.lines().map(|line| line.to_string()).collect())
+    .unwrap_or_default();
+  let content = std::fs::read_to_string(path.join(RELOCATION_FILE)).unwrap_or_default();
+  let reloc = content.lines()
+    .filter_map(parse_relocation_line)
+    .collect();
+  let mut unrelocated = std::collections::BTreeMap::<_, Vec<_>>::new();
+  for (path, value) in content.lines().filter_map(parse_unrelocated_line) {
+    unrelocated.entry(path).or_default().push(value);
+  }
+  Ok(Some(InstalledPackage { record, files, reloc, unrelocated }))
+}
 
-    let Some(dir_name) = path.file_name().map(|value| value.to_string_lossy().to_string()) else {
+/// files linked by installed packages, mapped to their owners
+pub fn file_owners(root: &Path) -> Result<HashMap<String, String>> {
+  let mut result = HashMap::new();
+  for record in list_installed(root)? {
+    let Some(installed) = read_installed(root, &record.name)? else {
       continue;
     };
-    if !dir_name.starts_with(&format!("{}-", name)) {
-      continue;
+    for file in installed.files {
+      result.insert(file, record.name.clone());
     }
-
-    let record: InstalledPackageRecord = read_toml(path.join(RECORD_FILE))?;
-    let files = std::fs::read_to_string(path.join(FILES_FILE))
-      .map(|content| content.lines().map(|line| line.to_string()).collect())
-      .unwrap_or_default();
-    let reloc = std::fs::read_to_string(path.join(RELOCATION_FILE))
-      .map(|content| {
-        content.lines()
-          .filter_map(parse_relocation_line)
-          .collect()
-      })
-      .unwrap_or_default();
-    return Ok(Some(InstalledPackage { record, files, reloc }));
   }
-
-  Ok(None)
+  Ok(result)
 }
 
-pub fn remove_installed(root: &Path, name: &str) -> Result<Option<InstalledPackage>> {
-  let Some(installed) = read_installed(root, name)? else {
+pub fn remove_installed_version(root: &Path, name: &str, version: &str) -> Result<Option<InstalledPackage>> {
+  let Some(installed) = read_installed_version(root, name, version)? else {
     return Ok(None);
   };
-  let pkg_dir = package_dir(root, &installed.record.name, &installed.record.version);
+  let pkg_dir = package_dir(root, name, version);
   std::fs::remove_dir_all(&pkg_dir)
     .ok_not_found_none()
     .when(("remove_dir_all", &pkg_dir))?;
   Ok(Some(installed))
 }
 
+/// remove every version of `name`, the active one comes first
+pub fn remove_installed(root: &Path, name: &str) -> Result<Vec<InstalledPackage>> {
+  let mut versions = list_versions(root, name)?;
+  if let Some(active) = pick_active(root, name, versions.clone())? {
+    versions.retain(|record| record.version != active.version);
+    versions.insert(0, active);
+  }
+  let mut result = Vec::new();
+  for record in versions {
+    result.extend(remove_installed_version(root, name, &record.version)?);
+  }
+  set_active(root, name, None)?;
+  Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
   use std::path::PathBuf;
   use std::time::{SystemTime, UNIX_EPOCH};
 
   use crate::io::relocate::RelocateType;
-  use crate::package::package::{InstallReason, InstalledPackage, InstalledPackageRecord};
+  use crate::package::{package::{InstallReason, InstalledPackage, InstalledPackageRecord}, version::Version};
 
   fn temp_root() -> PathBuf {
     std::env::temp_dir().join(format!(
@@ -203,7 +328,7 @@ mod tests {
     ))
   }
 
-  use super::{installed_index, list_installed, read_installed, remove_installed, version_status, write_installed, InstalledVersionStatus};
+  use super::{active_version, file_owners, installed_index, list_installed, list_versions, read_installed, read_installed_version, remove_installed, set_active, stale_versions, version_status, write_installed, write_record, InstalledVersionStatus};
 
   #[test]
   fn test_db_roundtrip() {
@@ -212,17 +337,25 @@ mod tests {
       record: InstalledPackageRecord {
         name: "wget".to_string(),
         version: "1.0.0".to_string(),
+        version_scheme: 0,
         desc: "desc".to_string(),
         license: Some("MIT".to_string()),
         deps: vec!["openssl@3".to_string()],
         reason: InstallReason::Explicit,
         install_date: 123,
         dest: PathBuf::from("/tmp/wget"),
+        keg_only: None,
       },
       files: vec!["bin/wget".to_string(), "opt/wget".to_string()],
       reloc: std::collections::BTreeMap::from([
         (PathBuf::from("bin/wget"), RelocateType::Text),
         (PathBuf::from("lib/libwget.dylib"), RelocateType::MachO),
+        (PathBuf::from("lib/libwget.so"), RelocateType::Elf),
+        (PathBuf::from("share/wget/data.bin"), RelocateType::Binary),
+      ]),
+      unrelocated: std::collections::BTreeMap::from([
+        (PathBuf::from("lib/libwget.so"), vec!["@@HOMEBREW_PREFIX@@/share/wget".to_string()]),
+        (PathBuf::from("share/wget/a\tb\\n.txt"), vec!["@@HOMEBREW_PREFIX@@\n\t\\n\\".to_string()]),
       ]),
     };
 
@@ -235,17 +368,80 @@ mod tests {
     assert_eq!(loaded.record.version, "1.0.0");
     assert_eq!(loaded.files, package.files);
     assert_eq!(loaded.reloc, package.reloc);
+    assert_eq!(loaded.unrelocated, package.unrelocated);
+    assert_eq!(file_owners(&root).unwrap().get("bin/wget").map(String::as_str), Some("wget"));
 
-    let removed = remove_installed(&root, "wget").unwrap().unwrap();
-    assert_eq!(removed.record.name, "wget");
+    let removed = remove_installed(&root, "wget").unwrap();
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].record.name, "wget");
     assert!(read_installed(&root, "wget").unwrap().is_none());
 
     let index = installed_index(&root).unwrap();
     assert!(!index.contains_key("wget"));
 
-    assert_eq!(version_status(None, "1.0.0"), InstalledVersionStatus::Missing);
-    assert_eq!(version_status(Some("1.0.0"), "1.0.0"), InstalledVersionStatus::Satisfied);
-    assert_eq!(version_status(Some("0.9.0"), "1.0.0"), InstalledVersionStatus::Outdated);
+    let v = Version::parse;
+    assert_eq!(version_status(None, &v("1.0.0")), InstalledVersionStatus::Missing);
+    assert_eq!(version_status(Some(&v("1.0.0")), &v("1.0.0")), InstalledVersionStatus::Satisfied);
+    assert_eq!(version_status(Some(&v("1.0")), &v("1.0.0")), InstalledVersionStatus::Satisfied);
+    assert_eq!(version_status(Some(&v("0.9.0")), &v("1.0.0")), InstalledVersionStatus::Outdated);
+    assert_eq!(version_status(Some(&v("1.0.0")), &v("1.0.0_1")), InstalledVersionStatus::Outdated);
+    assert_eq!(version_status(Some(&v("1.10.0")), &v("1.9.0")), InstalledVersionStatus::Newer);
+    assert_eq!(version_status(Some(&v("2.0")), &v("1.0").with_scheme(1)), InstalledVersionStatus::Outdated);
+
+    std::fs::remove_dir_all(&root).ok();
+  }
+
+  #[test]
+  fn test_db_versions() {
+    let root = temp_root();
+    let package = |name: &str, version: &str| InstalledPackage {
+      record: InstalledPackageRecord {
+        name: name.to_string(),
+        version: version.to_string(),
+        version_scheme: 0,
+        desc: String::new(),
+        license: None,
+        deps: Vec::new(),
+        reason: InstallReason::Explicit,
+        install_date: 0,
+        dest: PathBuf::from(format!("/tmp/{name}/{version}")),
+        keg_only: None,
+      },
+      files: vec![format!("opt/{name}")],
+      reloc: Default::default(),
+      unrelocated: Default::default(),
+    };
+
+    // without the active mark, e.g. db written by older pacbrew, the newest is active
+    write_record(&root, &package("node", "9.0")).unwrap();
+    write_record(&root, &package("node", "10.0")).unwrap();
+    write_installed(&root, &package("node-gyp", "1.0")).unwrap();
+    assert_eq!(active_version(&root, "node").unwrap(), None);
+    assert_eq!(read_installed(&root, "node").unwrap().unwrap().record.version, "10.0");
+
+    write_installed(&root, &package("node", "9.0")).unwrap();
+    assert_eq!(read_installed(&root, "node").unwrap().unwrap().record.version, "9.0");
+    assert_eq!(list_versions(&root, "node").unwrap().iter().map(|i| i.version.as_str()).collect::<Vec<_>>(), vec!["9.0", "10.0"]);
+    assert_eq!(list_installed(&root).unwrap().iter().map(|i| (i.name.as_str(), i.version.as_str())).collect::<Vec<_>>(), vec![("node", "9.0"), ("node-gyp", "1.0")]);
+    assert!(read_installed_version(&root, "node", "11.0").unwrap().is_none());
+
+    write_record(&root, &package("node", "8.0")).unwrap();
+    let stale = |keep| stale_versions(&root, "node", keep).unwrap().into_iter().map(|i| i.version).collect::<Vec<_>>();
+    assert_eq!(stale(0), vec!["8.0", "10.0"]);
+    assert_eq!(stale(1), vec!["8.0"]);
+    assert!(stale(2).is_empty());
+    assert!(stale_versions(&root, "python", 0).unwrap().is_empty());
+
+    // a mark left for a removed version falls back to the newest
+    set_active(&root, "node", Some("11.0")).unwrap();
+    assert_eq!(read_installed(&root, "node").unwrap().unwrap().record.version, "10.0");
+
+    set_active(&root, "node", Some("9.0")).unwrap();
+    let removed = remove_installed(&root, "node").unwrap();
+    assert_eq!(removed.iter().map(|i| i.record.version.as_str()).collect::<Vec<_>>(), vec!["9.0", "8.0", "10.0"]);
+    assert!(read_installed(&root, "node").unwrap().is_none());
+    assert_eq!(active_version(&root, "node").unwrap(), None);
+    assert_eq!(read_installed(&root, "node-gyp").unwrap().unwrap().record.version, "1.0");
 
     std::fs::remove_dir_all(&root).ok();
   }
@@ -1,5 +1,5 @@
-use crate::{io::{fetch::FetchReq, http::DownloadTask}, package::package::PackageVersion};
-use std::{path::{Path, PathBuf}, result::Result as StdResult};
+use crate::{io::{fetch::FetchReq, http::DownloadTask, oci::Challenge}, package::package::PackageVersion};
+use std::{path::{Path, PathBuf}, result::Result as StdResult, time::Duration};
 
 pub type Result<T, E=Error> = StdResult<T, E>;
 
@@ -12,6 +12,17 @@ pub enum Error {
     #[source]
     error: Option<reqwest::Error>,
   },
+  #[error("request {} to {} timed out after {:?}", .action, .url, .timeout)]
+  Timeout {
+    action: &'static str,
+    url: String,
+    timeout: Duration,
+  },
+  #[error("request to {} is unauthorized", .url)]
+  Unauthorized {
+    url: String,
+    challenge: Option<Challenge>,
+  },
   #[error("parse response of {} {} failed when {} due to {inner}", .action, .url, .reason)]
   ResponseMalformed {
     action: &'static str,
@@ -53,8 +64,49 @@ pub enum Error {
     #[source]
     error: toml::de::Error,
   },
+  #[error("checksum of {} mismatch, expect {expect} but got {actual}", .filename.to_string_lossy())]
+  ChecksumMismatch {
+    filename: PathBuf,
+    expect: String,
+    actual: String,
+  },
+  #[error("signature of {} cannot be verified: {}", .target, .reason)]
+  SignatureInvalid {
+    target: String,
+    reason: String,
+  },
+  #[error("formula index {} is malformed: {}", .filename.to_string_lossy(), .reason)]
+  IndexMalformed {
+    filename: PathBuf,
+    reason: String,
+  },
+  #[error("bottle of {} is built for cellar {}, which is not {}", .name, .expect, .actual.to_string_lossy())]
+  CellarMismatch {
+    name: String,
+    expect: String,
+    actual: PathBuf,
+  },
+  #[error("invalid path {}: {}", .path.to_string_lossy(), .reason)]
+  InvalidPath {
+    path: PathBuf,
+    reason: &'static str,
+  },
+  #[error("binary {} is malformed: {}", .filename.to_string_lossy(), .reason)]
+  BinaryMalformed {
+    filename: PathBuf,
+    reason: String,
+  },
+  #[error("{} is locked by {}", .path.to_string_lossy(), .pid.map(|pid| format!("pid {pid}")).unwrap_or_else(|| "another process".to_string()))]
+  Locked {
+    path: PathBuf,
+    pid: Option<u32>,
+  },
   #[error("malformed url {}", .0)]
   MalformedUrl(String),
+  #[error("unknown bottle tag {}", .0)]
+  UnknownBottleTag(String),
+  #[error("unsupported host {}", .0)]
+  UnsupportedHost(String),
   #[error("no available mirror for req {}", .0)]
   MirrorFailed(FetchReq),
   #[error("package not found: {} with {:?} in [{}]", .name, .arch, .avaliable.join(","))]
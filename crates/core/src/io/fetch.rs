@@ -1,23 +1,88 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, sync::OnceLock, time::{Duration, Instant}};
 
-use crate::{error::{Error, ErrorExt, Result}, package::{mirror::MirrorServer, package::PkgBuild}, ui::{bar::FeedBar, EventListener}};
+use crate::{error::{Error, ErrorExt, Result}, package::{mirror::{MirrorServer, MirrorType}, package::PkgBuild}, ui::{bar::FeedBar, event::BytesEvent, EventListener}};
 
-use super::http::DownloadTask;
+use super::{http::{DownloadTask, Fetched, Validators}, rank::{MirrorRank, ReqKind, Sample}};
+
+const GZ_SUFFIX: &str = ".gz";
+
+/// How hard we try before giving up a request.
+/// all mirrors are tried once a round, and `retry` more rounds would be taken after an exponential backoff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPolicy {
+  pub retry: usize,
+  pub backoff: Duration,
+  pub backoff_max: Duration,
+  pub connect_timeout: Duration,
+  /// time to wait for response headers
+  pub read_timeout: Duration,
+  /// abort a download when no bytes received in this duration
+  pub stall_timeout: Duration,
+}
+
+impl Default for NetworkPolicy {
+  fn default() -> Self {
+    Self {
+      retry: 5,
+      backoff: Duration::from_secs(1),
+      backoff_max: Duration::from_secs(30),
+      connect_timeout: Duration::from_secs(10),
+      read_timeout: Duration::from_secs(30),
+      stall_timeout: Duration::from_secs(30),
+    }
+  }
+}
+
+impl NetworkPolicy {
+  /// backoff before the `round`-th retry, starts from 1
+  pub fn backoff(&self, round: usize) -> Duration {
+    let factor = 1u32.checked_shl(round.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+    self.backoff.saturating_mul(factor).min(self.backoff_max)
+  }
+
+  pub async fn wait(&self, round: usize) {
+    if round == 0 {
+      return;
+    }
+    let backoff = self.backoff(round);
+    info!(round, ?backoff, "all mirrors failed, retrying");
+    tokio::time::sleep(backoff).await;
+  }
+}
 
 pub struct MirrorLists {
   pub lists: Vec<MirrorServer>,
+  pub policy: NetworkPolicy,
+  pub rank: MirrorRank,
 }
 
 impl MirrorLists {
-  pub fn url_iter<'a>(&'a self, req: FetchReq) -> Box<dyn Iterator<Item = (reqwest::Client, String)> + Send + 'a> {
+  pub fn new(lists: Vec<MirrorServer>, policy: NetworkPolicy) -> Self {
+    let lists = lists.into_iter().map(|i| i.with_policy(&policy)).collect();
+    Self { lists, policy, rank: MirrorRank::default() }
+  }
+
+  pub fn rank(self, rank: MirrorRank) -> Self {
+    Self { rank, ..self }
+  }
+
+  /// mirrors able to serve `req` with the url, best ranked first.
+  /// bottle mirrors are asked for the `.gz` of api files before the plain one.
+  pub fn url_iter<'a>(&'a self, req: FetchReq) -> Box<dyn Iterator<Item = (&'a MirrorServer, String)> + Send + 'a> {
+    let order = self.rank.order(&self.lists, ReqKind::from(&req));
+    let iter = order.into_iter().map(|i| &self.lists[i]);
     match req {
       FetchReq::Api(api) => {
-        let iter = self.lists.iter().filter_map(move |i| i.api_url(&api).map(|u| (i.client(), u)));
-        return Box::new(iter)
+        let iter = iter.filter_map(move |i| i.api_url(&api).map(|u| (i, u)))
+          .flat_map(|(i, u)| {
+            let gz = (i.server_type == MirrorType::Bottle && !u.ends_with(GZ_SUFFIX)).then(|| (i, format!("{}{}", u, GZ_SUFFIX)));
+            gz.into_iter().chain(std::iter::once((i, u)))
+          });
+        Box::new(iter)
       },
       FetchReq::Package(pkg) => {
-        let iter = self.lists.iter().map(move |i| (i.client(), i.package_url(&pkg)));
-        return Box::new(iter)
+        let iter = iter.map(move |i| (i, i.package_url(&pkg)));
+        Box::new(iter)
       },
     }
   }
@@ -51,41 +116,113 @@ impl std::fmt::Display for FetchReq {
 }
 
 
+/// `max` is none when the server does not tell the length
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct FetchState {
   pub current: u64,
-  pub max: u64,
+  pub max: Option<u64>,
 }
 
 impl FeedBar for FetchState {
   fn message(&self) -> Option<String> { None }
   fn position(&self) -> Option<u64> { Some(self.current as _) }
-  fn length(&self) -> Option<u64> { Some(self.max as _) }
+  fn length(&self) -> Option<u64> { self.max }
+  fn unbounded(&self) -> bool { self.max.is_none() }
+}
+
+impl From<FetchState> for BytesEvent {
+  fn from(state: FetchState) -> Self {
+    match state.max {
+      Some(max) => Self::Progress { current: state.current, max: Some(max) },
+      None => Self::Unbounded { current: state.current },
+    }
+  }
 }
 
 /// download json api from https://formulae.brew.sh/api/formula.json
-#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len=mirrors.lists.len(), req = %req, path = %path.as_ref().to_string_lossy()))]
-pub async fn fetch_remote<P: AsRef<Path>>(mirrors: &MirrorLists, req: FetchReq, path: P, tracker: impl EventListener<FetchState>) -> Result<()> {
+pub async fn fetch_remote<P: AsRef<Path>>(mirrors: &MirrorLists, req: FetchReq, path: P, tracker: impl EventListener<FetchState>) -> Result<Fetched> {
+  fetch_remote_cond(mirrors, req, path, None, tracker).await
+}
+
+/// [`fetch_remote`] with a conditional request, responds [`Fetched::NotModified`] when `validators` still match,
+/// they are only sent to the url they come from, and the validators returned are tagged with the url.
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len=mirrors.lists.len(), req = %req, path = %path.as_ref().to_string_lossy(), ?validators))]
+pub async fn fetch_remote_cond<P: AsRef<Path>>(mirrors: &MirrorLists, req: FetchReq, path: P, validators: Option<&Validators>, tracker: impl EventListener<FetchState>) -> Result<Fetched> {
   let filename = path.as_ref();
   if let Some(i) = path.as_ref().parent() {
     std::fs::create_dir_all(i).when(("create_dir_all", i))?;
   }
+  // bottles are addressed by sha256, so a partial download could be continued from any mirror
+  let sha256 = match &req {
+    FetchReq::Package(build) => Some(build.sha256.clone()),
+    FetchReq::Api(_) => None,
+  };
+  let resume = sha256.is_some();
+  let kind = ReqKind::from(&req);
   let mut retrying = false;
-  for (client, url) in mirrors.url_iter(req.clone()) {
-    debug!(message="try mirror", url);
-    if retrying {
-      info!(url, "download failed, retrying");
-    }
-    let mut task = DownloadTask::new(url, filename, None)?;
-    // TODO: keep partial download
-    match task.client(Some(client)).force(true).run(|e| tracker.on_event(e)).await {
-      Ok(state) => {
-        tracker.on_event(state.clone());
-        return Ok(())
-      },
-      Err(e) => {
-        warn!(error=%e, message="download failed");
-        retrying = true;
+  for round in 0..=mirrors.policy.retry {
+    mirrors.policy.wait(round).await;
+    for (mirror, url) in mirrors.url_iter(req.clone()) {
+      debug!(message="try mirror", url, round);
+      if retrying {
+        info!(url, "download failed, retrying");
+      }
+      let gz = kind == ReqKind::Api && url.ends_with(GZ_SUFFIX) && !filename.to_string_lossy().ends_with(GZ_SUFFIX);
+      // a `.gz` is decompressed by `gunzip`, not by `Content-Encoding`
+      let client = match kind == ReqKind::Api && !gz {
+        true => mirror.api_client(),
+        false => mirror.client(),
+      };
+      let sent = validators.filter(|i| i.url.as_ref() == Some(&url)).cloned();
+      let mut task = DownloadTask::new(url.clone(), filename, sha256.clone())?;
+      task.client(Some(client)).force(true).resume(resume).gunzip(gz).validators(sent)
+        .timeout(Some(mirrors.policy.read_timeout), Some(mirrors.policy.stall_timeout));
+      let registry = match &req {
+        FetchReq::Package(build) => mirror.registry().map(|i| (i, build)),
+        FetchReq::Api(_) => None,
+      };
+      if let Some((registry, build)) = registry {
+        task.bearer(registry.cached_token(&build.name));
+      }
+      let start = Instant::now();
+      let first_byte = OnceLock::new();
+      let run = |task: DownloadTask| {
+        let (start, first_byte, tracker) = (&start, &first_byte, &tracker);
+        async move {
+          task.run(|e: FetchState| {
+            first_byte.get_or_init(|| (start.elapsed(), e.current));
+            tracker.on_event(e)
+          }).await
+        }
+      };
+      let mut result = run(task.clone()).await;
+      // token expired or not negotiated yet, refresh it and try the same mirror again
+      if let (Err(Error::Unauthorized { challenge: Some(challenge), .. }), Some((registry, _))) = (&result, registry) {
+        result = match registry.authorize(challenge).await {
+          Ok(token) => run(task.bearer(Some(token)).clone()).await,
+          Err(e) => Err(e),
+        };
+      }
+      match result {
+        Ok(Fetched::Updated { state, mut validators }) => {
+          validators.url = Some(url);
+          let (latency, offset) = first_byte.get().copied().unwrap_or((start.elapsed(), 0));
+          let bytes = state.current.saturating_sub(offset);
+          mirrors.rank.record(mirror, kind, &Sample::Success { latency, bytes, elapsed: start.elapsed() });
+          tracker.on_event(state.clone());
+          return Ok(Fetched::Updated { state, validators })
+        },
+        Ok(Fetched::NotModified) => {
+          mirrors.rank.record(mirror, kind, &Sample::Success { latency: start.elapsed(), bytes: 0, elapsed: start.elapsed() });
+          return Ok(Fetched::NotModified)
+        },
+        // the mirror may not have the `.gz`, not its fault
+        Err(e) if gz => debug!(error=%e, "gz not available"),
+        Err(e) => {
+          warn!(error=%e, message="download failed");
+          mirrors.rank.record(mirror, kind, &Sample::Failure);
+          retrying = true;
+        }
       }
     }
   }
@@ -110,3 +247,72 @@ async fn test_download_db() {
   info!(len=%std::fs::metadata(&target).unwrap().len());
   // std::fs::remove_file(target).unwrap();
 }
+
+#[tokio::test]
+async fn test_fetch_encoding() {
+  use std::io::Write as _;
+  use sha2::{Digest as _, Sha256};
+  use crate::tests::stub::{serve, Response};
+  let json = br#"[{"name":"foo"}]"#;
+  let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  gz.write_all(json).unwrap();
+  let gz = gz.finish().unwrap();
+  let body = gz.clone();
+  // a mirror labeling gzip files with `Content-Encoding: gzip`
+  let base = serve(move |req| match req.path.as_str() {
+    "/foo-1.0.x86_64_linux.bottle.tar.gz" | "/api/formula.json" => Response::new(200, body.clone()).header("content-encoding", "gzip"),
+    _ => Response::new(404, Vec::new()),
+  }).await;
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)], NetworkPolicy { retry: 0, ..Default::default() });
+  let dir = std::env::temp_dir().join(format!("pacbrew-test-encoding-{}", std::process::id()));
+  let build = PkgBuild {
+    name: "foo".to_string(),
+    version: "1.0".to_string(),
+    arch: "x86_64_linux".to_string(),
+    rebuild: 0,
+    filename: "foo-1.0.x86_64_linux.bottle.tar.gz".to_string(),
+    url: String::new(),
+    sha256: format!("{:x}", Sha256::digest(&gz)),
+    cellar: String::new(),
+  };
+  let target = dir.join(&build.filename);
+  fetch_remote(&mirrors, FetchReq::Package(build), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read(&target).unwrap(), gz);
+  let target = dir.join("formula.json");
+  fetch_remote(&mirrors, FetchReq::Api("formula.json".to_string()), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read(&target).unwrap(), json);
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_fetch_validators() {
+  use crate::tests::stub::{serve, Response};
+  // any validator matches, as a weak comparison may do
+  let base = serve(move |req| match req.path.as_str() {
+    "/api/formula.json" if req.header("if-none-match").is_some() => Response::new(304, Vec::new()),
+    "/api/formula.json" => Response::new(200, b"[]".to_vec()).header("etag", "W/\"plain\""),
+    _ => Response::new(404, Vec::new()),
+  }).await;
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)], NetworkPolicy { retry: 0, ..Default::default() });
+  let dir = std::env::temp_dir().join(format!("pacbrew-test-validators-{}", std::process::id()));
+  let target = dir.join("formula.json");
+  let req = FetchReq::Api("formula.json".to_string());
+  // the mirror no longer serves the `.gz` the validators come from
+  let gz = Validators { etag: Some("\"gz\"".to_string()), last_modified: None, url: Some(format!("{}/api/formula.json.gz", base)) };
+  let Fetched::Updated { validators, .. } = fetch_remote_cond(&mirrors, req.clone(), &target, Some(&gz), ()).await.unwrap() else {
+    panic!("validators of the .gz are sent to the plain url");
+  };
+  assert_eq!(validators.url, Some(format!("{}/api/formula.json", base)));
+  assert_eq!(fetch_remote_cond(&mirrors, req, &target, Some(&validators), ()).await.unwrap(), Fetched::NotModified);
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backoff() {
+  let policy = NetworkPolicy::default();
+  assert_eq!(policy.backoff(1), Duration::from_secs(1));
+  assert_eq!(policy.backoff(2), Duration::from_secs(2));
+  assert_eq!(policy.backoff(4), Duration::from_secs(8));
+  assert_eq!(policy.backoff(6), Duration::from_secs(30));
+  assert_eq!(policy.backoff(100), Duration::from_secs(30));
+}
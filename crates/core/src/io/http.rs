@@ -1,12 +1,14 @@
 
-use std::path::PathBuf;
+use std::{future::Future, path::{Path, PathBuf}, time::Duration};
 use crate::{error::{Error, ErrorExt, Result}, ui::EventListener};
 
+use async_compression::tokio::bufread::GzipDecoder;
 use futures::StreamExt as _;
-use reqwest::{IntoUrl, Url};
-use tokio::io::AsyncWriteExt as _;
+use reqwest::{header, IntoUrl, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 
-use super::{fetch::FetchState, read::tmp_path};
+use super::{fetch::FetchState, oci::Challenge, read::tmp_path};
 
 pub trait ErrorDownloadExt<T> {
   fn when_download(self, task: &DownloadTask) -> Result<T>;
@@ -19,7 +21,9 @@ impl<T> ErrorDownloadExt<T> for Result<T, reqwest::Error> {
 }
 
 /// The download task would download url to filename, and verify sha256.
-/// it would first download to filename.tmp, then rename to filename.
+/// it would first download to filename.part, then rename to filename.
+/// when `resume` is set, an existing filename.part is continued with a `Range` request,
+/// the part may come from another mirror, so sha256 should be provided to catch a mismatch.
 #[derive(Debug)]
 pub struct DownloadTask {
   pub client: Option<reqwest::Client>,
@@ -27,6 +31,42 @@ pub struct DownloadTask {
   pub filename: PathBuf,
   pub sha256: Option<String>,
   pub force: bool,
+  pub resume: bool,
+  pub read_timeout: Option<Duration>,
+  pub stall_timeout: Option<Duration>,
+  pub bearer: Option<String>,
+  pub validators: Option<Validators>,
+  pub gunzip: bool,
+}
+
+/// `ETag` and `Last-Modified` of a response, sent back as `If-None-Match` and `If-Modified-Since`
+/// so an unchanged file responds 304 without a body.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Validators {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub etag: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub last_modified: Option<String>,
+  /// the url responded them, validators of one representation do not apply to another
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+}
+
+impl Validators {
+  pub fn from_headers(headers: &header::HeaderMap) -> Self {
+    let get = |name| headers.get(name).and_then(|i: &header::HeaderValue| i.to_str().ok()).map(|i| i.to_string());
+    Self { etag: get(header::ETAG), last_modified: get(header::LAST_MODIFIED), url: None }
+  }
+  pub fn is_empty(&self) -> bool {
+    self.etag.is_none() && self.last_modified.is_none()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fetched {
+  Updated { state: FetchState, validators: Validators },
+  /// the server responded 304 to the validators, the file is untouched
+  NotModified,
 }
 
 impl Clone for DownloadTask {
@@ -36,7 +76,13 @@ impl Clone for DownloadTask {
       url: self.url.clone(),
       filename: self.filename.clone(),
       sha256: self.sha256.clone(),
-      force: self.force.clone(),
+      force: self.force,
+      resume: self.resume,
+      read_timeout: self.read_timeout,
+      stall_timeout: self.stall_timeout,
+      bearer: self.bearer.clone(),
+      validators: self.validators.clone(),
+      gunzip: self.gunzip,
     }
   }
 }
@@ -45,7 +91,7 @@ impl DownloadTask {
   pub fn new<U: IntoUrl, P: Into<PathBuf>>(url: U, filename: P, sha256: Option<String>) -> Result<Self> {
     let url = into_url(url)?;
     let filename = filename.into();
-    Ok(Self { client: None, url, filename, sha256, force: false })
+    Ok(Self { client: None, url, filename, sha256, force: false, resume: false, read_timeout: None, stall_timeout: None, bearer: None, validators: None, gunzip: false })
   }
 
   pub fn client(&mut self, client: Option<reqwest::Client>) -> &mut Self {
@@ -58,39 +104,180 @@ impl DownloadTask {
     self
   }
 
+  pub fn resume(&mut self, resume: bool) -> &mut Self {
+    self.resume = resume;
+    self
+  }
+
+  pub fn timeout(&mut self, read_timeout: Option<Duration>, stall_timeout: Option<Duration>) -> &mut Self {
+    self.read_timeout = read_timeout;
+    self.stall_timeout = stall_timeout;
+    self
+  }
+
+  /// token for OCI registries, a 401/403 response turns into [`Error::Unauthorized`] with the challenge to refresh it
+  pub fn bearer(&mut self, bearer: Option<String>) -> &mut Self {
+    self.bearer = bearer;
+    self
+  }
+
+  /// send a conditional request, the task responds [`Fetched::NotModified`] when the server has nothing newer
+  pub fn validators(&mut self, validators: Option<Validators>) -> &mut Self {
+    self.validators = validators;
+    self
+  }
+
+  /// the response is a `.gz` of the file, decompress it before renaming to filename
+  pub fn gunzip(&mut self, gunzip: bool) -> &mut Self {
+    self.gunzip = gunzip;
+    self
+  }
+
   #[tracing::instrument(level = "trace", skip_all, fields(url = %self.url.as_str(), path = %self.filename.to_string_lossy()))]
-  pub async fn run(&self, tracker: impl EventListener<FetchState>) -> Result<FetchState> {
+  pub async fn run(&self, tracker: impl EventListener<FetchState>) -> Result<Fetched> {
     if !self.force && self.filename.exists() {
       let length = self.filename.metadata().when(("metadata", &self.filename))?.len();
-      return Ok(FetchState { current: length, max: length })
+      return Ok(Fetched::Updated { state: FetchState { current: length, max: Some(length) }, validators: Validators::default() })
+    }
+    let client = self.client.clone().unwrap_or_default();
+    let tmp_filename = tmp_path(&self.filename, ".part");
+    let mut offset = match self.resume {
+      true => tokio::fs::metadata(&tmp_filename).await.map(|i| i.len()).unwrap_or(0),
+      false => 0,
+    };
+    let resp = loop {
+      let mut req = client.get(self.url.clone());
+      if let Some(bearer) = &self.bearer {
+        req = req.bearer_auth(bearer);
+      }
+      if let Some(validators) = &self.validators {
+        if let Some(etag) = &validators.etag {
+          req = req.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+          req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+      }
+      if offset > 0 {
+        debug!(offset, "resume download");
+        req = req.header(header::RANGE, format!("bytes={}-", offset));
+      }
+      let resp = with_timeout(self.read_timeout, "get", self.url.as_str(), req.send()).await?.when_download(self)?;
+      if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        info!(url=%self.url, offset, "range not satisfiable, restart download");
+        offset = 0;
+        continue;
+      }
+      break resp;
+    };
+    if resp.status() == StatusCode::NOT_MODIFIED {
+      debug!(url=%self.url, "not modified");
+      return Ok(Fetched::NotModified);
+    }
+    if matches!(resp.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+      return Err(Error::Unauthorized { url: self.url.to_string(), challenge: Challenge::from_response(&resp) });
     }
-    let client = self.client.clone().unwrap_or_else(|| reqwest::Client::new());
-    let resp = client.get(self.url.clone()).send().await.when_download(&self)?;
     if !resp.status().is_success() {
       info!(url=%self.url, filename=%self.filename.display(), status_code=?resp.status(), "request failed");
       return Err(std::io::Error::other(format!("download from {} failed with status {}", self.url, resp.status()))).when(("dowanlod", &self.filename))?;
     }
-    let length = resp.content_length().unwrap_or(0);
-    let mut partial_len = 0;
-    let tmp_filename = tmp_path(&self.filename, ".part");
-    debug!(message="download_to", tmp_filename=%tmp_filename.display());
-    let mut file = tokio::fs::File::create(&tmp_filename).await.when(("create", &tmp_filename))?;
+    if offset > 0 && resp.status() != StatusCode::PARTIAL_CONTENT {
+      info!(url=%self.url, status_code=?resp.status(), "mirror ignored range, restart download");
+      offset = 0;
+    }
+    if offset > 0 && content_range_start(resp.headers()) != Some(offset) {
+      return Err(std::io::Error::other(format!("download from {} responds unexpected range {:?}", self.url, resp.headers().get(header::CONTENT_RANGE)))).when(("dowanlod", &self.filename))?;
+    }
+    let length = resp.content_length().map(|i| offset + i);
+    let validators = Validators::from_headers(resp.headers());
+    let mut partial_len = offset;
+    debug!(message="download_to", tmp_filename=%tmp_filename.display(), offset);
+    let mut file = if offset > 0 {
+      tokio::fs::OpenOptions::new().append(true).open(&tmp_filename).await.when(("append", &tmp_filename))?
+    } else {
+      tokio::fs::File::create(&tmp_filename).await.when(("create", &tmp_filename))?
+    };
     let mut stream = resp.bytes_stream();
-    while let Some(bytes) = stream.next().await {
-      let bytes = bytes.when_download(&self)?;
+    while let Some(bytes) = with_timeout(self.stall_timeout, "stream", self.url.as_str(), stream.next()).await? {
+      let bytes = bytes.when_download(self)?;
       partial_len += bytes.len() as u64;
       file.write_all(&bytes).await.when(("write", &tmp_filename))?;
-      // debug!(tracker=self.tracker.is_some(), partial_len);
-      tracker.on_event(FetchState { current: partial_len as u64, max: length });
+      tracker.on_event(FetchState { current: partial_len, max: length });
     }
     file.sync_all().await.when(("sync", &tmp_filename))?;
+    drop(file);
+    if let Some(expect) = &self.sha256 {
+      let actual = sha256_file(&tmp_filename).await?;
+      if &actual != expect {
+        warn!(filename=%tmp_filename.display(), expect, actual, "checksum mismatch, drop partial download");
+        tokio::fs::remove_file(&tmp_filename).await.when(("remove_file", &tmp_filename))?;
+        return Err(Error::ChecksumMismatch { filename: self.filename.clone(), expect: expect.clone(), actual });
+      }
+    }
+    let tmp_filename = match self.gunzip {
+      true => gunzip_file(&tmp_filename).await?,
+      false => tmp_filename,
+    };
     debug!(message="rename", from=%tmp_filename.display(), to=%self.filename.display());
     tokio::fs::rename(&tmp_filename, &self.filename).await.when(("rename", &self.filename))?;
-    Ok(FetchState { current: partial_len, max: length })
+    Ok(Fetched::Updated { state: FetchState { current: partial_len, max: length }, validators })
+  }
+}
+
+/// run `f` within `timeout`, none means wait forever
+pub(crate) async fn with_timeout<F: Future>(timeout: Option<Duration>, action: &'static str, url: &str, f: F) -> Result<F::Output> {
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, f).await
+      .map_err(|_| Error::Timeout { action, url: url.to_string(), timeout }),
+    None => Ok(f.await),
   }
 }
 
+/// parse start of `Content-Range: bytes <start>-<end>/<total>`
+fn content_range_start(headers: &header::HeaderMap) -> Option<u64> {
+  let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+  let range = value.trim().strip_prefix("bytes ")?;
+  let (start, _) = range.split_once('-')?;
+  start.trim().parse().ok()
+}
+
+/// decompress `path` to `path.gunzip`, and remove `path`
+async fn gunzip_file(path: &Path) -> Result<PathBuf> {
+  let target = tmp_path(path, ".gunzip");
+  let file = tokio::fs::File::open(path).await.when(("open", path))?;
+  let mut reader = GzipDecoder::new(tokio::io::BufReader::new(file));
+  let mut writer = tokio::fs::File::create(&target).await.when(("create", &target))?;
+  tokio::io::copy(&mut reader, &mut writer).await.when(("gunzip", path))?;
+  writer.sync_all().await.when(("sync", &target))?;
+  tokio::fs::remove_file(path).await.when(("remove_file", path))?;
+  Ok(target)
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+  let mut file = tokio::fs::File::open(path).await.when(("open", path))?;
+  let mut hasher = Sha256::new();
+  let mut buf = vec![0; 1024*1024];
+  loop {
+    let size = file.read(&mut buf).await.when(("read", path))?;
+    if size == 0 {
+      break;
+    }
+    hasher.update(&buf[..size]);
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
 fn into_url(url: impl IntoUrl) -> Result<Url> {
   let url_string = url.as_str().to_string();
   url.into_url().map_err(|_| Error::MalformedUrl(url_string))
 }
+
+#[test]
+fn test_content_range() {
+  let mut headers = header::HeaderMap::new();
+  assert_eq!(content_range_start(&headers), None);
+  headers.insert(header::CONTENT_RANGE, header::HeaderValue::from_static("bytes 1024-2047/2048"));
+  assert_eq!(content_range_start(&headers), Some(1024));
+  headers.insert(header::CONTENT_RANGE, header::HeaderValue::from_static("bytes */2048"));
+  assert_eq!(content_range_start(&headers), None);
+}
@@ -0,0 +1,180 @@
+//! verify signed api index like `formula.jws.json` in JWS JSON serialization.
+//!
+//! homebrew signs the raw json text in `payload`, the signing input is
+//! `{protected}.{base64url(payload)}` and the algorithm is RSA-PSS with salt length of the digest.
+//!
+//! see also:
+//!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/api.rb (verify_and_parse_jws)
+//!   https://www.rfc-editor.org/rfc/rfc7515#section-7.2
+
+use std::{collections::BTreeMap, path::Path};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rsa::{pkcs8::DecodePublicKey as _, pss::{Signature, VerifyingKey}, signature::Verifier as _, RsaPublicKey};
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::error::{Error, ErrorExt, Result};
+
+/// public keys by key id, e.g. `homebrew-1`
+pub type PublicKeys = BTreeMap<String, RsaPublicKey>;
+
+#[derive(Debug, serde::Deserialize)]
+struct Jws {
+  payload: String,
+  signatures: Vec<JwsSignature>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct JwsSignature {
+  protected: String,
+  #[serde(default)]
+  header: Option<JwsHeader>,
+  signature: String,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct JwsHeader {
+  #[serde(default)]
+  kid: Option<String>,
+  #[serde(default)]
+  alg: Option<String>,
+}
+
+/// read a PEM encoded `SubjectPublicKeyInfo`
+pub fn load_key<P: AsRef<Path>>(path: P) -> Result<RsaPublicKey> {
+  let path = path.as_ref();
+  let pem = std::fs::read_to_string(path).when(("read", path))?;
+  RsaPublicKey::from_public_key_pem(&pem)
+    .map_err(|e| Error::SignatureInvalid { target: path.display().to_string(), reason: format!("malformed public key: {}", e) })
+}
+
+/// load every key of `kid -> path`
+pub fn load_keys<'a, I: IntoIterator<Item = (&'a String, &'a P)>, P: AsRef<Path> + 'a>(keys: I) -> Result<PublicKeys> {
+  keys.into_iter().map(|(kid, path)| Ok((kid.clone(), load_key(path)?))).collect()
+}
+
+fn decode(what: &str, value: &str) -> std::result::Result<Vec<u8>, String> {
+  URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).map_err(|e| format!("malformed {}: {}", what, e))
+}
+
+fn verify_one(key: &RsaPublicKey, alg: &str, input: &[u8], signature: &[u8]) -> std::result::Result<(), String> {
+  let signature = Signature::try_from(signature).map_err(|e| e.to_string())?;
+  let result = match alg {
+    "PS256" => VerifyingKey::<Sha256>::new(key.clone()).verify(input, &signature),
+    "PS384" => VerifyingKey::<Sha384>::new(key.clone()).verify(input, &signature),
+    "PS512" => VerifyingKey::<Sha512>::new(key.clone()).verify(input, &signature),
+    _ => return Err(format!("unsupported algorithm {}", alg)),
+  };
+  result.map_err(|e| e.to_string())
+}
+
+/// verify `content` of `target` with any of `keys`, and return the signed payload.
+/// a signature with `kid` is only checked against the key of the same id, malformed ones are skipped like failed ones.
+#[tracing::instrument(level = "debug", skip_all, fields(target, keys = ?keys.keys().collect::<Vec<_>>()))]
+pub fn verify(target: &str, content: &str, keys: &PublicKeys) -> Result<String> {
+  let invalid = |reason: String| Error::SignatureInvalid { target: target.to_string(), reason };
+  if keys.is_empty() {
+    return Err(invalid("no public key configured".to_string()));
+  }
+  let jws: Jws = serde_json::from_str(content).when(("de", "Jws", None))?;
+  let payload = URL_SAFE_NO_PAD.encode(&jws.payload);
+  let mut reasons = Vec::new();
+  for sig in &jws.signatures {
+    let header = sig.header.as_ref();
+    let protected = decode("protected header", &sig.protected)
+      .and_then(|i| serde_json::from_slice::<JwsHeader>(&i).map_err(|e| format!("malformed protected header: {}", e)));
+    let protected = match protected {
+      Ok(protected) => protected,
+      Err(e) => {
+        debug!(error=e, "skip signature");
+        reasons.push(e);
+        continue;
+      },
+    };
+    let kid = protected.kid.as_ref().or_else(|| header.and_then(|i| i.kid.as_ref()));
+    let Some(alg) = protected.alg.as_ref().or_else(|| header.and_then(|i| i.alg.as_ref())) else {
+      reasons.push(format!("{:?}: no algorithm", kid));
+      continue;
+    };
+    let signature = match decode("signature", &sig.signature) {
+      Ok(signature) => signature,
+      Err(e) => {
+        debug!(error=e, ?kid, "skip signature");
+        reasons.push(format!("{:?}: {}", kid, e));
+        continue;
+      },
+    };
+    let input = format!("{}.{}", sig.protected, payload);
+    let candidates = keys.iter().filter(|(id, _)| kid.map(|kid| kid == *id).unwrap_or(true)).collect::<Vec<_>>();
+    if candidates.is_empty() {
+      reasons.push(format!("{:?}: unknown key", kid));
+    }
+    for (id, key) in candidates {
+      match verify_one(key, alg, input.as_bytes(), &signature) {
+        Ok(()) => {
+          debug!(kid=%id, alg, "signature verified");
+          return Ok(jws.payload);
+        },
+        Err(e) => reasons.push(format!("{}: {}", id, e)),
+      }
+    }
+  }
+  if jws.signatures.is_empty() {
+    reasons.push("no signature".to_string());
+  }
+  Err(invalid(reasons.join("; ")))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+  use rsa::{pss::BlindedSigningKey, signature::{RandomizedSigner as _, SignatureEncoding as _}, RsaPrivateKey};
+  use sha2::Sha512;
+
+  /// small but long enough for PS512, large keys take long to generate in debug builds
+  pub fn private_key() -> RsaPrivateKey {
+    RsaPrivateKey::new(&mut rand::thread_rng(), 1280).unwrap()
+  }
+
+  /// sign `payload` as homebrew does with PS512
+  pub fn sign(key: &RsaPrivateKey, kid: &str, payload: &str) -> String {
+    let protected = URL_SAFE_NO_PAD.encode(serde_json::json!({ "alg": "PS512" }).to_string());
+    let input = format!("{}.{}", protected, URL_SAFE_NO_PAD.encode(payload));
+    let signature = BlindedSigningKey::<Sha512>::new(key.clone()).sign_with_rng(&mut rand::thread_rng(), input.as_bytes());
+    serde_json::json!({
+      "payload": payload,
+      "signatures": [{ "protected": protected, "header": { "kid": kid }, "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()) }],
+    }).to_string()
+  }
+
+  #[test]
+  fn test_verify() {
+    use super::*;
+    let key = private_key();
+    let other = private_key();
+    let keys = PublicKeys::from([("homebrew-1".to_string(), key.to_public_key())]);
+    let jws = sign(&key, "homebrew-1", "[]");
+    assert_eq!(verify("formula.jws.json", &jws, &keys).unwrap(), "[]");
+
+    let tampered = jws.replace(r#""payload":"[]""#, r#""payload":"[1]""#);
+    assert_ne!(tampered, jws);
+    assert!(matches!(verify("formula.jws.json", &tampered, &keys), Err(Error::SignatureInvalid { .. })));
+    let forged = sign(&other, "homebrew-1", "[]");
+    assert!(matches!(verify("formula.jws.json", &forged, &keys), Err(Error::SignatureInvalid { .. })));
+    let unknown = sign(&key, "homebrew-2", "[]");
+    assert!(matches!(verify("formula.jws.json", &unknown, &keys), Err(Error::SignatureInvalid { .. })));
+    assert!(matches!(verify("formula.jws.json", &jws, &PublicKeys::new()), Err(Error::SignatureInvalid { .. })));
+
+    // malformed signatures before a valid one are skipped
+    let mut value: serde_json::Value = serde_json::from_str(&jws).unwrap();
+    let valid = value["signatures"][0].clone();
+    let mut bad_signature = valid.clone();
+    bad_signature["signature"] = "!".into();
+    let mut bad_protected = valid.clone();
+    bad_protected["protected"] = URL_SAFE_NO_PAD.encode("{").into();
+    value["signatures"] = serde_json::json!([bad_protected, bad_signature, valid]);
+    assert_eq!(verify("formula.jws.json", &value.to_string(), &keys).unwrap(), "[]");
+    value["signatures"] = serde_json::json!([bad_protected, bad_signature]);
+    assert!(matches!(verify("formula.jws.json", &value.to_string(), &keys), Err(Error::SignatureInvalid { .. })));
+  }
+}
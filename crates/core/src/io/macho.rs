@@ -0,0 +1,356 @@
+//! rewrite Mach-O files in place without Xcode tools.
+//!
+//! `install_name_tool -id -change -rpath`: every load command is copied, and commands with a changed name
+//! are rebuilt with the new name as long as the load commands still end before the first section (the headerpad).
+//!
+//! `codesign --sign -`: code hashes in every CodeDirectory of the embedded signature are computed again,
+//! the size of the file does not change, so the signature keeps its layout.
+//!
+//! see also:
+//!   https://opensource.apple.com/source/cctools/cctools-795/misc/install_name_tool.c.auto.html
+//!   https://github.com/apple-oss-distributions/xnu/blob/main/osfmk/kern/cs_blobs.h
+
+use std::path::Path;
+
+use goblin::mach::{constants::{SECTION_TYPE, S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL, S_ZEROFILL}, load_command::CommandVariant, Mach, MachO};
+use sha2::{Digest as _, Sha256, Sha384};
+
+use crate::error::{Error, Result};
+
+use super::relocate::Relocations;
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade0c02;
+const CSMAGIC_BLOBWRAPPER: u32 = 0xfade0b01;
+const CS_ADHOC: u32 = 0x2;
+const CS_SUPPORTSCODELIMIT64: u32 = 0x20300;
+const CS_HASHTYPE_SHA1: u8 = 1;
+const CS_HASHTYPE_SHA256: u8 = 2;
+const CS_HASHTYPE_SHA256_TRUNCATED: u8 = 3;
+const CS_HASHTYPE_SHA384: u8 = 4;
+
+fn malformed(filename: &Path, reason: impl ToString) -> Error {
+  Error::BinaryMalformed { filename: filename.to_owned(), reason: reason.to_string() }
+}
+
+fn read_be(data: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn c_str(data: &[u8], offset: usize) -> Option<&str> {
+  let bytes = data.get(offset..)?;
+  std::str::from_utf8(&bytes[..bytes.iter().position(|&i| i == 0)?]).ok()
+}
+
+/// `(offset, size)` of every Mach-O in a thin or fat file, None if it is not a Mach-O file
+pub fn slices(data: &[u8]) -> Option<Vec<(usize, usize)>> {
+  match Mach::parse(data).ok()? {
+    Mach::Binary(_) => Some(vec![(0, data.len())]),
+    Mach::Fat(fat) => {
+      let arches = fat.arches().ok()?;
+      let slices = arches.iter().map(|i| (i.offset as usize, i.size as usize)).collect::<Vec<_>>();
+      slices.iter().all(|(offset, size)| offset.checked_add(*size).is_some_and(|end| end <= data.len())).then_some(slices)
+    },
+  }
+}
+
+/// file offset of the first section with data, load commands must not grow beyond it
+fn headerpad_limit(macho: &MachO, len: usize) -> usize {
+  let zerofill = [S_ZEROFILL, S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL];
+  let sections = macho.segments.iter()
+    .flat_map(|segment| segment.sections().unwrap_or_default())
+    .filter(|(section, _)| section.offset > 0 && !zerofill.contains(&(section.flags & SECTION_TYPE)))
+    .map(|(section, _)| section.offset as usize);
+  let segments = macho.segments.iter()
+    .filter(|segment| segment.fileoff > 0 && segment.filesize > 0)
+    .map(|segment| segment.fileoff as usize);
+  sections.chain(segments).min().unwrap_or(len)
+}
+
+impl Relocations {
+  fn rename(&self, command: &CommandVariant, name: &str) -> Option<&str> {
+    match command {
+      CommandVariant::IdDylib(_) if self.id.0 == name => Some(&self.id.1),
+      CommandVariant::LoadDylib(_) | CommandVariant::LoadWeakDylib(_) | CommandVariant::ReexportDylib(_)
+        | CommandVariant::LazyLoadDylib(_) | CommandVariant::LoadUpwardDylib(_) => self.links.get(name).map(String::as_str),
+      CommandVariant::Rpath(_) => self.rpaths.get(name).map(String::as_str),
+      _ => None,
+    }
+  }
+
+  /// rewrite load commands of a thin Mach-O in `slice` with the names which fit in the headerpad,
+  /// returns false when nothing changed. names left keep their placeholders, which `replace_c_strings` pushes to `unrelocated` later
+  pub fn rewrite(&self, slice: &mut [u8], filename: &Path) -> Result<bool> {
+    let macho = MachO::parse(slice, 0).map_err(|e| malformed(filename, e))?;
+    if !macho.little_endian {
+      return Err(malformed(filename, "big endian mach-o"));
+    }
+    let header_size = if macho.is_64 { 32 } else { 28 };
+    let align = if macho.is_64 { 8 } else { 4 };
+    let old_size = macho.header.sizeofcmds as usize;
+    let limit = headerpad_limit(&macho, slice.len());
+    // every load command, with the rebuilt one if renamed
+    let mut entries = Vec::with_capacity(macho.load_commands.len());
+    for lc in &macho.load_commands {
+      let name_offset = match &lc.command {
+        CommandVariant::IdDylib(i) | CommandVariant::LoadDylib(i) | CommandVariant::LoadWeakDylib(i)
+          | CommandVariant::ReexportDylib(i) | CommandVariant::LazyLoadDylib(i) | CommandVariant::LoadUpwardDylib(i) => Some(i.dylib.name as usize),
+        CommandVariant::Rpath(i) => Some(i.path as usize),
+        _ => None,
+      };
+      let raw = slice.get(lc.offset..lc.offset + lc.command.cmdsize()).ok_or_else(|| malformed(filename, "load command out of range"))?;
+      let renamed = name_offset.and_then(|offset| Some((offset, self.rename(&lc.command, c_str(raw, offset)?)?)));
+      let Some((offset, name)) = renamed else {
+        entries.push((raw.to_vec(), None));
+        continue;
+      };
+      let mut command = raw[..offset].to_vec();
+      command.extend_from_slice(name.as_bytes());
+      command.push(0);
+      command.resize(command.len().next_multiple_of(align), 0);
+      let cmdsize = command.len() as u32;
+      command[4..8].copy_from_slice(&cmdsize.to_le_bytes());
+      entries.push((raw.to_vec(), Some((name.to_string(), command))));
+    }
+    drop(macho);
+    // commands not growing are always rewritten, the others in order as long as the headerpad has room
+    let mut size = entries.iter()
+      .map(|(raw, renamed)| renamed.as_ref().map_or(raw.len(), |(_, command)| command.len().min(raw.len())))
+      .sum::<usize>();
+    let mut commands = Vec::with_capacity(size);
+    let mut changed = false;
+    for (raw, renamed) in &entries {
+      let command = match renamed {
+        Some((_, command)) if command.len() <= raw.len() => Some(command),
+        Some((_, command)) if header_size + size + command.len() - raw.len() <= limit => {
+          size += command.len() - raw.len();
+          Some(command)
+        },
+        Some((name, command)) => {
+          debug!(filename=%filename.display(), name, size=command.len(), capacity=limit.saturating_sub(header_size), "load command does not fit in headerpad");
+          None
+        },
+        None => None,
+      };
+      match command {
+        Some(command) => {
+          commands.extend_from_slice(command);
+          changed = true;
+        },
+        None => commands.extend_from_slice(raw),
+      }
+    }
+    if !changed {
+      return Ok(false);
+    }
+    slice[header_size..header_size + old_size.max(commands.len())].fill(0);
+    slice[header_size..header_size + commands.len()].copy_from_slice(&commands);
+    slice[20..24].copy_from_slice(&(commands.len() as u32).to_le_bytes());
+    Ok(true)
+  }
+}
+
+/// compute code hashes of the ad-hoc signature in `slice` again, returns false if it is not signed
+pub fn resign(slice: &mut [u8], filename: &Path) -> Result<bool> {
+  let macho = MachO::parse(slice, 0).map_err(|e| malformed(filename, e))?;
+  let signature = macho.load_commands.iter().find_map(|lc| match &lc.command {
+    CommandVariant::CodeSignature(i) => Some((i.dataoff as usize, i.datasize as usize)),
+    _ => None,
+  });
+  drop(macho);
+  let Some((dataoff, datasize)) = signature else {
+    return Ok(false);
+  };
+  let blob = slice.get(dataoff..dataoff + datasize).ok_or_else(|| malformed(filename, "code signature out of range"))?;
+  if read_be(blob, 0) != Some(CSMAGIC_EMBEDDED_SIGNATURE) {
+    return Err(malformed(filename, "unknown code signature"));
+  }
+  let count = read_be(blob, 8).unwrap_or_default() as usize;
+  let mut directories = Vec::new();
+  for i in 0..count {
+    let offset = read_be(blob, 12 + i * 8 + 4).ok_or_else(|| malformed(filename, "truncated code signature"))? as usize;
+    match read_be(blob, offset) {
+      Some(CSMAGIC_CODEDIRECTORY) => directories.push(dataoff + offset),
+      Some(CSMAGIC_BLOBWRAPPER) if read_be(blob, offset + 4).unwrap_or_default() > 8 =>
+        warn!(filename=%filename.display(), "signed with a certificate, sign it again with codesign"),
+      _ => {},
+    }
+  }
+  for cd in directories {
+    let field = |offset: usize| read_be(slice, cd + offset).ok_or_else(|| malformed(filename, "truncated code directory"));
+    let (version, flags, hash_offset, code_slots) = (field(8)?, field(12)?, field(16)? as usize, field(28)? as usize);
+    let mut code_limit = field(32)? as u64;
+    if version >= CS_SUPPORTSCODELIMIT64 && code_limit == 0 {
+      code_limit = (field(56)? as u64) << 32 | field(60)? as u64;
+    }
+    let byte = |offset: usize| slice.get(cd + offset).copied().ok_or_else(|| malformed(filename, "truncated code directory"));
+    let (hash_size, hash_type, page_size) = (byte(36)? as usize, byte(37)?, byte(39)?);
+    if flags & CS_ADHOC == 0 {
+      warn!(filename=%filename.display(), flags, "code directory is not ad-hoc");
+    }
+    if page_size as u32 >= usize::BITS {
+      return Err(malformed(filename, format!("page size 2^{} is too large", page_size)));
+    }
+    let page_size = if page_size == 0 { code_limit as usize } else { 1 << page_size };
+    let code_limit = (code_limit as usize).min(slice.len());
+    for i in 0..code_slots {
+      let page = &slice[i.saturating_mul(page_size).min(code_limit)..(i + 1).saturating_mul(page_size).min(code_limit)];
+      let hash = match hash_type {
+        CS_HASHTYPE_SHA256 | CS_HASHTYPE_SHA256_TRUNCATED => Sha256::digest(page).to_vec(),
+        CS_HASHTYPE_SHA384 => Sha384::digest(page).to_vec(),
+        CS_HASHTYPE_SHA1 => {
+          warn!(filename=%filename.display(), "sha1 code directory is left as is");
+          break;
+        },
+        _ => return Err(malformed(filename, format!("unknown hash type {}", hash_type))),
+      };
+      let hash = hash.get(..hash_size).ok_or_else(|| malformed(filename, format!("hash size {} is too large", hash_size)))?;
+      let slot = cd + hash_offset + i * hash_size;
+      slice.get_mut(slot..slot + hash_size).ok_or_else(|| malformed(filename, "hash slot out of range"))?
+        .copy_from_slice(hash);
+    }
+  }
+  Ok(true)
+}
+
+/// a signed Mach-O dylib fixture of `cputype`, with the first section at 0x300 and the signature at 0x400
+#[cfg(test)]
+pub(crate) fn fixture(cputype: u32, id: &str, libs: &[&str], rpaths: &[&str]) -> Vec<u8> {
+  const SECTION: u32 = 0x300;
+  const SIGNATURE: usize = 0x400;
+  let le = |data: &mut Vec<u8>, values: &[u32]| values.iter().for_each(|i| data.extend(i.to_le_bytes()));
+  let le64 = |data: &mut Vec<u8>, values: &[u64]| values.iter().for_each(|i| data.extend(i.to_le_bytes()));
+  let name16 = |data: &mut Vec<u8>, name: &str| { data.extend(name.as_bytes()); data.extend(vec![0u8; 16 - name.len()]) };
+  let lc_str = |data: &mut Vec<u8>, cmd: u32, fixed: &[u32], name: &str| {
+    let size = (8 + fixed.len() * 4 + name.len() + 1).next_multiple_of(8);
+    le(data, &[cmd, size as u32]);
+    le(data, fixed);
+    data.extend(name.as_bytes());
+    data.resize(data.len() + size - 8 - fixed.len() * 4 - name.len(), 0);
+  };
+
+  let mut commands = Vec::new();
+  le(&mut commands, &[0x19, 72 + 80]);
+  name16(&mut commands, "__TEXT");
+  le64(&mut commands, &[0, 0x4000, 0, SIGNATURE as u64]);
+  le(&mut commands, &[5, 5, 1, 0]);
+  name16(&mut commands, "__text");
+  name16(&mut commands, "__TEXT");
+  le64(&mut commands, &[SECTION as u64, 0x10]);
+  le(&mut commands, &[SECTION, 2, 0, 0, 0x80000400, 0, 0, 0]);
+  lc_str(&mut commands, 0xd, &[24, 2, 0x10000, 0x10000], id);
+  for lib in libs {
+    lc_str(&mut commands, 0xc, &[24, 2, 0x10000, 0x10000], lib);
+  }
+  for rpath in rpaths {
+    lc_str(&mut commands, 0x8000001c, &[12], rpath);
+  }
+  let ncmds = 2 + libs.len() + rpaths.len() + 1;
+
+  let ident = b"foo\0";
+  let cd_len = 48 + ident.len() + 32;
+  let sig_len = 20 + cd_len;
+  le(&mut commands, &[0x1d, 16, SIGNATURE as u32, sig_len as u32]);
+
+  let mut data = Vec::new();
+  le(&mut data, &[0xfeedfacf, cputype, 0, 6, ncmds as u32, commands.len() as u32, 0, 0]);
+  data.extend(commands);
+  assert!(data.len() <= SECTION as usize);
+  data.resize(SECTION as usize, 0);
+  data.extend(b"\x1f\x20\x03\xd5".repeat(4));
+  data.resize(SIGNATURE, 0);
+
+  let be = |data: &mut Vec<u8>, values: &[u32]| values.iter().for_each(|i| data.extend(i.to_be_bytes()));
+  be(&mut data, &[CSMAGIC_EMBEDDED_SIGNATURE, sig_len as u32, 1, 0, 20]);
+  be(&mut data, &[CSMAGIC_CODEDIRECTORY, cd_len as u32, 0x20100, CS_ADHOC, 48 + ident.len() as u32, 48, 0, 1, SIGNATURE as u32]);
+  data.extend([32, CS_HASHTYPE_SHA256, 0, 12]);
+  be(&mut data, &[0, 0]);
+  data.extend(ident);
+  let hash = Sha256::digest(&data[..SIGNATURE]);
+  data.extend(hash);
+  data
+}
+
+/// put thin Mach-O `slices` into a fat file, every slice aligned to 4KiB
+#[cfg(test)]
+pub(crate) fn fat_fixture(slices: &[Vec<u8>]) -> Vec<u8> {
+  let mut data = Vec::new();
+  data.extend(0xcafebabeu32.to_be_bytes());
+  data.extend((slices.len() as u32).to_be_bytes());
+  let mut offset = 0x1000;
+  for slice in slices {
+    let cputype = u32::from_le_bytes(slice[4..8].try_into().unwrap());
+    for i in [cputype, 0, offset as u32, slice.len() as u32, 12] {
+      data.extend(i.to_be_bytes());
+    }
+    offset = (offset + slice.len()).next_multiple_of(0x1000);
+  }
+  for slice in slices {
+    data.resize(data.len().next_multiple_of(0x1000), 0);
+    data.extend(slice);
+  }
+  data
+}
+
+/// check the code hash of the fixture
+#[cfg(test)]
+pub(crate) fn fixture_signed(slice: &[u8]) -> bool {
+  slice[0x400 + 20 + 52..0x400 + 20 + 84] == Sha256::digest(&slice[..0x400])[..]
+}
+
+#[test]
+fn test_macho() {
+  let path = Path::new("libfoo.dylib");
+  let slice = fixture(0x0100000c, "@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib", &["/usr/lib/libSystem.B.dylib"], &["@loader_path/../lib"]);
+  assert_eq!(slices(&slice), Some(vec![(0, slice.len())]));
+  assert!(fixture_signed(&slice));
+  let other = fixture(0x01000007, "libfoo.dylib", &[], &[]);
+  assert_eq!(slices(&fat_fixture(&[slice.clone(), other.clone()])), Some(vec![(0x1000, slice.len()), (0x2000, other.len())]));
+  assert_eq!(slices(b"\x7fELF"), None);
+
+  let mut reloc = Relocations {
+    id: ("@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib".to_string(), "/pb/opt/foo/lib/libfoo.dylib".to_string()),
+    ..Default::default()
+  };
+  reloc.rpaths.insert("@loader_path/../lib".to_string(), "/pb/lib".to_string());
+  let mut data = slice.clone();
+  assert!(reloc.rewrite(&mut data, path).unwrap());
+  assert!(!fixture_signed(&data));
+  assert!(resign(&mut data, path).unwrap());
+  assert!(fixture_signed(&data));
+  let macho = MachO::parse(&data, 0).unwrap();
+  assert_eq!(macho.name, Some("/pb/opt/foo/lib/libfoo.dylib"));
+  // goblin puts the id of a dylib in place of "self"
+  assert_eq!(macho.libs, vec!["/pb/opt/foo/lib/libfoo.dylib", "/usr/lib/libSystem.B.dylib"]);
+  assert_eq!(macho.rpaths, vec!["/pb/lib"]);
+
+  // the id is left as is when it does not fit, the rpath is still rewritten
+  reloc.id.1 = format!("/{}/libfoo.dylib", "p".repeat(0x300));
+  let mut data = slice.clone();
+  assert!(reloc.rewrite(&mut data, path).unwrap());
+  let macho = MachO::parse(&data, 0).unwrap();
+  assert_eq!(macho.name, Some("@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib"));
+  assert_eq!(macho.rpaths, vec!["/pb/lib"]);
+  reloc.rpaths.clear();
+  let mut data = slice.clone();
+  assert!(!reloc.rewrite(&mut data, path).unwrap());
+  assert_eq!(data, slice);
+}
+
+#[test]
+fn test_resign_malformed() {
+  let path = Path::new("libfoo.dylib");
+  let slice = fixture(0x0100000c, "libfoo.dylib", &[], &[]);
+  let lc = slice.windows(8).position(|i| i == [0x1d, 0, 0, 0, 16, 0, 0, 0]).unwrap();
+  // the code directory is cut right after the code limit
+  let mut data = slice[..0x400 + 20 + 36].to_vec();
+  data[lc + 12..lc + 16].copy_from_slice(&(20u32 + 36).to_le_bytes());
+  assert!(matches!(resign(&mut data, path), Err(Error::BinaryMalformed { .. })));
+
+  let mut data = slice.clone();
+  data[0x400 + 20 + 39] = 64;
+  assert!(matches!(resign(&mut data, path), Err(Error::BinaryMalformed { .. })));
+  let mut data = slice.clone();
+  data[0x400 + 20 + 36] = 64;
+  assert!(matches!(resign(&mut data, path), Err(Error::BinaryMalformed { .. })));
+}
@@ -4,5 +4,9 @@ pub mod read;
 pub mod fetch;
 pub mod untar;
 pub mod relocate;
+pub mod macho;
+pub mod rank;
+pub mod oci;
+pub mod jws;
 
 pub use fetch::FetchState;
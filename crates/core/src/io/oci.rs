@@ -0,0 +1,310 @@
+//! minimal OCI distribution client for bottles hosted on ghcr.io or other registries.
+//!
+//! anonymous pull follows the token flow:
+//!   1. request without token, registry responds 401 with
+//!      `WWW-Authenticate: Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:homebrew/core/wget:pull"`
+//!   2. `GET {realm}?service={service}&scope={scope}` gives `{"token": "..."}`
+//!   3. retry with `Authorization: Bearer <token>`, and refresh the token when it responds 401/403 again.
+//!
+//! see also:
+//!   https://distribution.github.io/distribution/spec/auth/token/
+//!   https://github.com/opencontainers/image-spec/blob/main/image-index.md
+//!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/github_packages.rb
+
+use std::{collections::HashMap, sync::Mutex};
+
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+
+use crate::{error::{Error, ErrorExt, Result}, package::package::PkgBuild};
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// parsed `WWW-Authenticate: Bearer realm="..",service="..",scope=".."`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+  pub realm: String,
+  pub service: Option<String>,
+  pub scope: Option<String>,
+}
+
+impl Challenge {
+  pub fn parse(value: &str) -> Option<Self> {
+    let (scheme, params) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+      return None;
+    }
+    let mut result = HashMap::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+      let (key, tail) = rest.split_once('=')?;
+      let (value, tail) = match tail.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"')?,
+        None => tail.split_once(',').unwrap_or((tail, "")),
+      };
+      result.insert(key.trim().to_ascii_lowercase(), value.to_string());
+      rest = tail.trim_start_matches([',', ' ']);
+    }
+    Some(Self {
+      realm: result.remove("realm")?,
+      service: result.remove("service"),
+      scope: result.remove("scope"),
+    })
+  }
+
+  pub fn from_response(resp: &Response) -> Option<Self> {
+    Self::parse(resp.headers().get(header::WWW_AUTHENTICATE)?.to_str().ok()?)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Platform {
+  pub architecture: String,
+  pub os: String,
+  #[serde(rename = "os.version", default, skip_serializing_if = "Option::is_none")]
+  pub os_version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+  #[serde(default)]
+  pub media_type: String,
+  pub digest: String,
+  pub size: u64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub platform: Option<Platform>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+  /// digest without the `sha256:` prefix
+  pub fn sha256(&self) -> Option<&str> {
+    self.digest.strip_prefix("sha256:")
+  }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+  pub schema_version: u32,
+  pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+  pub schema_version: u32,
+  pub layers: Vec<Descriptor>,
+}
+
+/// homebrew pushes `foo@1.2` as `foo/1.2` and `foo++` as `fooxx`
+pub fn repository_name(name: &str) -> String {
+  name.replace('@', "/").replace('+', "x")
+}
+
+/// homebrew tags bottles with `version_full`, and `-{rebuild}` when rebuilt
+pub fn bottle_tag(build: &PkgBuild) -> String {
+  if build.rebuild == 0 {
+    build.version.clone()
+  } else {
+    format!("{}-{}", build.version, build.rebuild)
+  }
+}
+
+pub struct OciRegistry {
+  client: reqwest::Client,
+  /// e.g. https://ghcr.io/v2/homebrew/core
+  base_url: String,
+  /// tokens by scope
+  tokens: Mutex<HashMap<String, String>>,
+}
+
+impl OciRegistry {
+  pub fn new(client: reqwest::Client, base_url: &str) -> Self {
+    Self { client, base_url: base_url.trim_end_matches('/').to_string(), tokens: Mutex::new(HashMap::new()) }
+  }
+
+  pub fn scope(name: &str) -> String {
+    format!("repository:{}:pull", name)
+  }
+
+  /// `homebrew/core/wget` for `https://ghcr.io/v2/homebrew/core` and `wget`
+  pub fn full_repository(&self, name: &str) -> String {
+    let namespace = self.base_url.split_once("/v2/").map(|(_, ns)| ns).unwrap_or_default();
+    if namespace.is_empty() {
+      repository_name(name)
+    } else {
+      format!("{}/{}", namespace, repository_name(name))
+    }
+  }
+
+  pub fn blob_url(&self, name: &str, digest: &str) -> String {
+    format!("{}/{}/blobs/{}", self.base_url, repository_name(name), digest)
+  }
+
+  pub fn manifest_url(&self, name: &str, reference: &str) -> String {
+    format!("{}/{}/manifests/{}", self.base_url, repository_name(name), reference)
+  }
+
+  pub fn cached_token(&self, name: &str) -> Option<String> {
+    self.tokens.lock().unwrap().get(&Self::scope(&self.full_repository(name))).cloned()
+  }
+
+  /// ask the realm in `challenge` for a new anonymous token, and remember it.
+  #[tracing::instrument(level = "debug", skip_all, fields(realm = %challenge.realm, scope = ?challenge.scope))]
+  pub async fn authorize(&self, challenge: &Challenge) -> Result<String> {
+    #[derive(serde::Deserialize)]
+    struct TokenResp {
+      token: Option<String>,
+      access_token: Option<String>,
+    }
+    let mut query = Vec::new();
+    if let Some(service) = &challenge.service {
+      query.push(("service", service.as_str()));
+    }
+    if let Some(scope) = &challenge.scope {
+      query.push(("scope", scope.as_str()));
+    }
+    let url = &challenge.realm;
+    let resp = self.client.get(url).query(&query).send().await.when(("token", url))?;
+    let resp = resp.error_for_status().when(("token", url))?;
+    let body = resp.text().await.when(("token", url))?;
+    let token: TokenResp = serde_json::from_str(&body).when(("de", "TokenResp", Some(&body)))?;
+    let token = token.token.or(token.access_token).ok_or_else(|| Error::parse_response_error("token", url, "token"))?;
+    if let Some(scope) = &challenge.scope {
+      self.tokens.lock().unwrap().insert(scope.clone(), token.clone());
+    }
+    Ok(token)
+  }
+
+  /// send the request built by `f` with the cached token for `name`,
+  /// the token is negotiated or refreshed once when the registry responds 401/403 with a challenge.
+  pub async fn send<F: Fn(&reqwest::Client) -> RequestBuilder>(&self, name: &str, f: F) -> Result<Response> {
+    let mut token = self.cached_token(name);
+    let mut refreshed = false;
+    loop {
+      let mut req = f(&self.client);
+      if let Some(token) = &token {
+        req = req.bearer_auth(token);
+      }
+      let resp = req.send().await.when(("send", &self.base_url))?;
+      if !refreshed && matches!(resp.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        if let Some(challenge) = Challenge::from_response(&resp) {
+          debug!(status=?resp.status(), ?challenge, "refresh token");
+          token = Some(self.authorize(&challenge).await?);
+          refreshed = true;
+          continue;
+        }
+      }
+      return Ok(resp);
+    }
+  }
+
+  async fn get_json<T: serde::de::DeserializeOwned>(&self, name: &str, url: &str, accept: &str) -> Result<T> {
+    let resp = self.send(name, |client| client.get(url).header(header::ACCEPT, accept)).await?;
+    let resp = resp.error_for_status().when(("manifest", url))?;
+    let body = resp.text().await.when(("manifest", url))?;
+    serde_json::from_str(&body).when(("de", std::any::type_name::<T>(), Some(&body)))
+  }
+
+  pub async fn index(&self, name: &str, reference: &str) -> Result<ImageIndex> {
+    self.get_json(name, &self.manifest_url(name, reference), MEDIA_TYPE_INDEX).await
+  }
+
+  pub async fn manifest(&self, name: &str, digest: &str) -> Result<ImageManifest> {
+    self.get_json(name, &self.manifest_url(name, digest), MEDIA_TYPE_MANIFEST).await
+  }
+
+  /// find the layer of bottle `build` through the image index and its platform manifest.
+  #[tracing::instrument(level = "debug", skip_all, fields(name = %build.name, arch = %build.arch))]
+  pub async fn bottle_layer(&self, build: &PkgBuild) -> Result<Descriptor> {
+    let tag = bottle_tag(build);
+    let url = self.manifest_url(&build.name, &tag);
+    let index = self.index(&build.name, &tag).await?;
+    let suffix = format!(".{}", build.arch);
+    let manifest = index.manifests.iter()
+      .find(|i| i.annotations.get(ANNOTATION_REF_NAME).is_some_and(|r| r.ends_with(&suffix)))
+      .ok_or_else(|| Error::parse_response_error("manifest", &url, &build.arch))?;
+    let manifest = self.manifest(&build.name, &manifest.digest).await?;
+    manifest.layers.into_iter().next().ok_or_else(|| Error::parse_response_error("manifest", &url, "layers"))
+  }
+}
+
+#[test]
+fn test_challenge() {
+  let challenge = Challenge::parse(r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:homebrew/core/wget:pull""#).unwrap();
+  assert_eq!(challenge.realm, "https://ghcr.io/token");
+  assert_eq!(challenge.service.as_deref(), Some("ghcr.io"));
+  assert_eq!(challenge.scope.as_deref(), Some("repository:homebrew/core/wget:pull"));
+  assert_eq!(Challenge::parse(r#"Basic realm="registry""#), None);
+  assert_eq!(repository_name("openssl@3"), "openssl/3");
+  assert_eq!(repository_name("libsigc++"), "libsigcxx");
+}
+
+#[tokio::test]
+async fn test_registry() {
+  use crate::tests::stub::{serve, Response};
+  const TOKEN: &str = "stub-token";
+  use sha2::Digest as _;
+  let blob = b"bottle".to_vec();
+  let sha256 = format!("{:x}", sha2::Sha256::digest(&blob));
+  let blob_digest = format!("sha256:{}", sha256);
+  let blob_path = format!("/v2/homebrew/core/foo/1/blobs/{}", blob_digest);
+  let base = serve(move |req| {
+    let authorized = req.header("authorization") == Some(&format!("Bearer {}", TOKEN));
+    match req.path.as_str() {
+      "/token?service=stub&scope=repository%3Ahomebrew%2Fcore%2Ffoo%2F1%3Apull" => Response::json(&serde_json::json!({ "token": TOKEN })),
+      _ if !authorized => Response::new(401, b"".to_vec())
+        .header("www-authenticate", &format!(r#"Bearer realm="{}/token",service="stub",scope="repository:homebrew/core/foo/1:pull""#, req.base)),
+      "/v2/homebrew/core/foo/1/manifests/1.0-1" => Response::json(&serde_json::json!({
+        "schemaVersion": 2,
+        "manifests": [
+          { "mediaType": MEDIA_TYPE_MANIFEST, "digest": "sha256:aaaa", "size": 10, "annotations": { ANNOTATION_REF_NAME: "1.0-1.arm64_sonoma" } },
+          { "mediaType": MEDIA_TYPE_MANIFEST, "digest": "sha256:bbbb", "size": 10, "annotations": { ANNOTATION_REF_NAME: "1.0-1.x86_64_linux" } },
+        ],
+      })),
+      "/v2/homebrew/core/foo/1/manifests/sha256:bbbb" => Response::json(&serde_json::json!({
+        "schemaVersion": 2,
+        "layers": [{ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": &blob_digest, "size": 6 }],
+      })),
+      path if path == blob_path => Response::new(200, blob.clone()),
+      _ => Response::new(404, b"".to_vec()),
+    }
+  }).await;
+
+  let registry = OciRegistry::new(reqwest::Client::new(), &format!("{}/v2/homebrew/core", base));
+  let build = PkgBuild {
+    name: "foo@1".to_string(),
+    version: "1.0".to_string(),
+    arch: "x86_64_linux".to_string(),
+    rebuild: 1,
+    filename: "foo@1-1.0.x86_64_linux.bottle.1.tar.gz".to_string(),
+    url: String::new(),
+    sha256: sha256.clone(),
+    cellar: ":any".to_string(),
+  };
+  assert_eq!(registry.cached_token(&build.name), None);
+  let layer = registry.bottle_layer(&build).await.unwrap();
+  assert_eq!(layer.sha256(), Some(sha256.as_str()));
+  assert_eq!(layer.size, 6);
+  assert_eq!(registry.cached_token(&build.name).as_deref(), Some(TOKEN));
+
+  // expired token would be refreshed
+  registry.tokens.lock().unwrap().insert(OciRegistry::scope("homebrew/core/foo/1"), "expired".to_string());
+  let url = registry.blob_url(&build.name, &layer.digest);
+  let resp = registry.send(&build.name, |client| client.get(&url)).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert_eq!(resp.bytes().await.unwrap().as_ref(), b"bottle");
+  assert_eq!(registry.cached_token(&build.name).as_deref(), Some(TOKEN));
+
+  // bottle download negotiates its own token
+  use crate::{io::fetch::{fetch_remote, FetchReq, MirrorLists}, package::mirror::{MirrorServer, MirrorType}};
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Oci, &format!("{}/v2/homebrew/core", base), None)], Default::default());
+  let target = std::env::temp_dir().join(format!("pacbrew-test-oci-{}", std::process::id())).join(&build.filename);
+  fetch_remote(&mirrors, FetchReq::Package(build.clone()), &target, ()).await.unwrap();
+  assert_eq!(std::fs::read(&target).unwrap(), b"bottle");
+  std::fs::remove_dir_all(target.parent().unwrap()).unwrap();
+}
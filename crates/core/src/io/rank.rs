@@ -0,0 +1,216 @@
+//! mirror ranking: every request records latency, throughput and failure of the mirror,
+//! mirrors are then tried from the cheapest one, separately for api and package requests.
+//! scores are kept in `mirrors.toml` under cache directory.
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant}};
+
+use futures::StreamExt as _;
+use reqwest::header;
+
+use crate::{error::{Error, ErrorExt, Result}, package::mirror::MirrorServer};
+
+use super::{fetch::{FetchReq, NetworkPolicy}, http::with_timeout, read::{read_toml, write_toml}};
+
+pub const RANK_FILE: &str = "mirrors.toml";
+
+/// weight of the newest sample in the moving average
+const ALPHA: f64 = 0.3;
+/// a failed request costs as much as waiting this long
+const FAILURE_PENALTY_MS: f64 = 30_000.0;
+/// bytes to read when benchmarking throughput
+const BENCH_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReqKind {
+  Api, Package,
+}
+
+impl From<&FetchReq> for ReqKind {
+  fn from(req: &FetchReq) -> Self {
+    match req {
+      FetchReq::Api(_) => Self::Api,
+      FetchReq::Package(_) => Self::Package,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MirrorScore {
+  pub success: u64,
+  pub failure: u64,
+  /// moving average of time to first byte, in milliseconds
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub latency_ms: Option<f64>,
+  /// moving average of bytes per second
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub throughput: Option<f64>,
+}
+
+fn moving_average(old: Option<f64>, sample: f64) -> f64 {
+  match old {
+    Some(old) => old * (1.0 - ALPHA) + sample * ALPHA,
+    None => sample,
+  }
+}
+
+impl MirrorScore {
+  pub fn record(&mut self, sample: &Sample) {
+    match sample {
+      Sample::Success { latency, bytes, elapsed } => {
+        self.success += 1;
+        self.latency_ms = Some(moving_average(self.latency_ms, latency.as_secs_f64() * 1000.0));
+        let transfer = elapsed.saturating_sub(*latency).as_secs_f64();
+        if *bytes > 0 && transfer > 0.0 {
+          self.throughput = Some(moving_average(self.throughput, *bytes as f64 / transfer));
+        }
+      },
+      Sample::Failure => self.failure += 1,
+    }
+  }
+
+  /// estimated milliseconds to fetch 1MiB, lower is better.
+  /// unmeasured mirrors cost nothing so they would be tried and measured.
+  pub fn cost(&self) -> f64 {
+    let latency = self.latency_ms.unwrap_or_default();
+    let transfer = self.throughput.map(|i| BENCH_BYTES as f64 * 1000.0 / i).unwrap_or_default();
+    let total = self.success + self.failure;
+    let failure_rate = if total == 0 { 0.0 } else { self.failure as f64 / total as f64 };
+    latency + transfer + failure_rate * FAILURE_PENALTY_MS
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MirrorRecord {
+  #[serde(default)]
+  pub api: MirrorScore,
+  #[serde(default)]
+  pub package: MirrorScore,
+}
+
+impl MirrorRecord {
+  pub fn get(&self, kind: ReqKind) -> &MirrorScore {
+    match kind {
+      ReqKind::Api => &self.api,
+      ReqKind::Package => &self.package,
+    }
+  }
+  pub fn get_mut(&mut self, kind: ReqKind) -> &mut MirrorScore {
+    match kind {
+      ReqKind::Api => &mut self.api,
+      ReqKind::Package => &mut self.package,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum Sample {
+  Success { latency: Duration, bytes: u64, elapsed: Duration },
+  Failure,
+}
+
+/// scores of mirrors keyed by `base_url`
+#[derive(Debug, Default)]
+pub struct MirrorRank {
+  path: Option<PathBuf>,
+  scores: Mutex<BTreeMap<String, MirrorRecord>>,
+}
+
+impl MirrorRank {
+  /// load scores from `cache_dir`, a missing or broken file starts over
+  pub fn load<P: AsRef<Path>>(cache_dir: P) -> Self {
+    let path = cache_dir.as_ref().join(RANK_FILE);
+    let scores = if path.exists() {
+      read_toml(&path).unwrap_or_else(|e| {
+        warn!(error=%e, path=%path.display(), "drop broken mirror scores");
+        BTreeMap::new()
+      })
+    } else {
+      BTreeMap::new()
+    };
+    Self { path: Some(path), scores: Mutex::new(scores) }
+  }
+
+  pub fn save(&self) -> Result<()> {
+    let Some(path) = &self.path else {
+      return Ok(())
+    };
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent).when(("create_dir_all", parent))?;
+    }
+    write_toml(path, &*self.scores.lock().unwrap(), true)?;
+    Ok(())
+  }
+
+  pub fn record(&self, mirror: &MirrorServer, kind: ReqKind, sample: &Sample) {
+    trace!(mirror=%mirror.base_url, ?kind, ?sample, "record mirror sample");
+    self.scores.lock().unwrap().entry(mirror.base_url.clone()).or_default().get_mut(kind).record(sample);
+  }
+
+  pub fn get(&self, mirror: &MirrorServer) -> MirrorRecord {
+    self.scores.lock().unwrap().get(&mirror.base_url).cloned().unwrap_or_default()
+  }
+
+  pub fn cost(&self, mirror: &MirrorServer, kind: ReqKind) -> f64 {
+    self.scores.lock().unwrap().get(&mirror.base_url).map(|i| i.get(kind).cost()).unwrap_or_default()
+  }
+
+  /// indices of `mirrors` from the cheapest, ties keep the config order
+  pub fn order(&self, mirrors: &[MirrorServer], kind: ReqKind) -> Vec<usize> {
+    let mut result = mirrors.iter().enumerate().map(|(i, m)| (i, self.cost(m, kind))).collect::<Vec<_>>();
+    result.sort_by(|a, b| a.1.total_cmp(&b.1));
+    result.into_iter().map(|(i, _)| i).collect()
+  }
+}
+
+/// measure `mirror` with a single request, package requests would only read the first [`BENCH_BYTES`].
+pub async fn bench(mirror: &MirrorServer, req: &FetchReq, policy: &NetworkPolicy) -> Result<Sample> {
+  let url = match req {
+    FetchReq::Api(api) => mirror.api_url(api).ok_or_else(|| Error::MirrorFailed(req.clone()))?,
+    FetchReq::Package(pkg) => mirror.package_url(pkg),
+  };
+  let start = Instant::now();
+  let send = mirror.client().get(&url).header(header::RANGE, format!("bytes=0-{}", BENCH_BYTES - 1)).send();
+  let resp = with_timeout(Some(policy.read_timeout), "bench", &url, send).await?.when(("bench", &url))?;
+  let latency = start.elapsed();
+  if !resp.status().is_success() {
+    return Err(Error::RequestFailed { action: "bench", url, error: resp.error_for_status().err() });
+  }
+  let mut bytes = 0;
+  let mut stream = resp.bytes_stream();
+  while let Some(chunk) = with_timeout(Some(policy.stall_timeout), "bench", &url, stream.next()).await? {
+    bytes += chunk.when(("bench", &url))?.len() as u64;
+    if bytes >= BENCH_BYTES {
+      break;
+    }
+  }
+  Ok(Sample::Success { latency, bytes, elapsed: start.elapsed() })
+}
+
+#[test]
+fn test_rank() {
+  use super::fetch::MirrorLists;
+  use crate::package::mirror::MirrorType;
+  let mirrors = MirrorLists::new(vec![
+    MirrorServer::new(MirrorType::Bottle, "https://a.example.com", None),
+    MirrorServer::new(MirrorType::Bottle, "https://b.example.com", None),
+    MirrorServer::new(MirrorType::Bottle, "https://c.example.com", None),
+  ], Default::default());
+  let rank = MirrorRank::default();
+  assert_eq!(rank.order(&mirrors.lists, ReqKind::Package), vec![0, 1, 2]);
+
+  rank.record(&mirrors.lists[0], ReqKind::Package, &Sample::Failure);
+  let fast = Sample::Success { latency: Duration::from_millis(50), bytes: BENCH_BYTES, elapsed: Duration::from_millis(150) };
+  let slow = Sample::Success { latency: Duration::from_millis(500), bytes: BENCH_BYTES, elapsed: Duration::from_millis(2500) };
+  rank.record(&mirrors.lists[1], ReqKind::Package, &slow);
+  rank.record(&mirrors.lists[2], ReqKind::Package, &fast);
+  assert_eq!(rank.order(&mirrors.lists, ReqKind::Package), vec![2, 1, 0]);
+  // api requests are ranked apart
+  assert_eq!(rank.order(&mirrors.lists, ReqKind::Api), vec![0, 1, 2]);
+
+  let record = rank.get(&mirrors.lists[2]);
+  assert_eq!(record.package.success, 1);
+  assert_eq!(record.package.latency_ms, Some(50.0));
+  let text = toml::to_string(&*rank.scores.lock().unwrap()).unwrap();
+  let loaded: BTreeMap<String, MirrorRecord> = toml::from_str(&text).unwrap();
+  assert_eq!(loaded, *rank.scores.lock().unwrap());
+}
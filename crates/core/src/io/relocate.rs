@@ -8,23 +8,39 @@
 ///!     LC_LAZY_LOAD_DYLIB, LC_PREBOUND_DYLIB change_install_name,
 ///!   and for LC_RPATH change rpath
 ///! replace_text_in_files
+///!   files without NUL in the first 8KiB are treated as text, placeholders are matched on bytes in a single pass,
+///!   so text in any encoding is relocated, the result is streamed to a temporary file which replaces the original.
 ///! when HOMEBREW_RELOCATE_BUILD_PREFIX is set, `relocate_build_prefix` would be additionally called.
 ///!
-///! otool -l cache/a.out | grep -B3 "@@"
-///! install_name_tool -id <new_id> -change <old_lib> <new_lib> -rpath <old_path> <new_path> cache/a.out
-///! codesign --sign - --force --preserve-metadata=entitlements,requirements,flags,runtime cache/a.out
+///! load commands are rewritten and the ad-hoc signature is regenerated in process (see `io::macho`),
+///! for every slice of a fat binary, which equals to
+///!   install_name_tool -id <new_id> -change <old_lib> <new_lib> -rpath <old_path> <new_path> cache/a.out
+///!   codesign --sign - --force --preserve-metadata=entitlements,requirements,flags,runtime cache/a.out
+///! names which would make the load commands outgrow the headerpad are left as is.
+///!
+///! for ELF (linux bottles), the interpreter in PT_INTERP and the strings of DT_NEEDED, DT_SONAME,
+///! DT_RPATH and DT_RUNPATH are rewritten in place, which only works when the new value is not longer.
+///!
+///! placeholders left in the data of a binary (C string tables, Mach-O data segments, static archives)
+///! are replaced in place when the new value is not longer, the string is padded with NUL.
+///! the others are reported and recorded as unrelocated.
 ///!
 ///! see also:
 ///!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/keg_relocate.rb
 ///!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/extend/os/mac/keg_relocate.rb
 ///!   https://opensource.apple.com/source/cctools/cctools-795/misc/install_name_tool.c.auto.html
+///!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/extend/os/linux/keg_relocate.rb
 
-use std::{borrow::Cow, collections::BTreeMap, path::{Path, PathBuf}};
+use std::{borrow::Cow, collections::BTreeMap, io::Write as _, path::{Path, PathBuf}};
 
-use goblin::mach::MachO;
+use aho_corasick::{AhoCorasick, MatchKind};
+
+use goblin::{elf::{dynamic::{DT_NEEDED, DT_RPATH, DT_RUNPATH, DT_SONAME}, program_header::{PT_INTERP, PT_LOAD}, Elf}, mach::MachO};
 use memmap2::MmapOptions;
 
-use crate::error::{ErrorExt, Result};
+use crate::error::{Error, ErrorExt, Result};
+
+use super::macho;
 
 /// We found these replacement in homebrew
 /// TODO: link here
@@ -32,28 +48,85 @@ use crate::error::{ErrorExt, Result};
 /// @@HOMEBREW_CELLAR@@ => ${prefix}/Cellar
 /// @@HOMEBREW_PERL@@ => /usr/bin/perl
 /// @@HOMEBREW_JAVA@@ => ${prefix}/opt/openjdk/libexec
-/// and would like to read prefix and cellar folder from config
+/// @@HOMEBREW_REPOSITORY@@ => ${prefix}
+/// @@HOMEBREW_LIBRARY@@ => ${prefix}/Library
+/// prefix and cellar folder are read from config, and the others could be overridden by `placeholders`
 pub struct RelocationPattern {
   pub install_name: BTreeMap<String, String>,
   pub extra_name: BTreeMap<String, String>,
+  /// matches every key of `install_name` and `extra_name`, in the order of `replacements`
+  matcher: AhoCorasick,
+  replacements: Vec<String>,
+  /// finds `@@HOMEBREW_` which may start an unknown placeholder
+  token: AhoCorasick,
+}
+
+fn abs_path_str(path: &Path) -> Result<String> {
+  let abs = try_abs_path(path).ok_or_else(|| Error::InvalidPath { path: path.to_owned(), reason: "current dir does not exist" })?;
+  match abs.into_os_string().into_string() {
+    Ok(abs) => Ok(abs),
+    Err(_) => Err(Error::InvalidPath { path: path.to_owned(), reason: "not valid utf-8" }),
+  }
 }
 
 impl RelocationPattern {
-  pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(prefix: P1, cellar: P2) -> Self {
-    // TODO: unwrap 1. current dir exists, 2. utf8 system
-    let prefix = try_abs_path(&prefix).unwrap().to_str().unwrap().to_string();
-    let cellar = try_abs_path(&cellar).unwrap().to_str().unwrap().to_string();
+  pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(prefix: P1, cellar: P2) -> Result<Self> {
+    let prefix = abs_path_str(prefix.as_ref())?;
+    let cellar = abs_path_str(cellar.as_ref())?;
     let mut install_name = BTreeMap::new();
     install_name.insert("@@HOMEBREW_PREFIX@@".to_string(), prefix.clone());
     install_name.insert("@@HOMEBREW_CELLAR@@".to_string(), cellar.clone());
     let mut extra_name = BTreeMap::new();
     extra_name.insert("@@HOMEBREW_PERL@@".to_string(), "/usr/bin/perl".to_string());
     extra_name.insert("@@HOMEBREW_JAVA@@".to_string(), format!("{}/opt/openjdk/libexec", prefix));
+    extra_name.insert("@@HOMEBREW_REPOSITORY@@".to_string(), prefix.clone());
+    extra_name.insert("@@HOMEBREW_LIBRARY@@".to_string(), format!("{}/Library", prefix));
+    Ok(Self::with_names(install_name, extra_name))
+  }
+
+  /// add or override placeholders, a key could be written as `HOMEBREW_PERL` or `@@HOMEBREW_PERL@@`
+  pub fn placeholders<'a, I: IntoIterator<Item = (&'a String, &'a String)>>(self, names: I) -> Self {
+    let Self { mut install_name, mut extra_name, .. } = self;
+    for (key, value) in names {
+      let key = format!("@@{}@@", key.trim_matches('@'));
+      match install_name.get_mut(&key) {
+        Some(old) => *old = value.clone(),
+        None => { extra_name.insert(key, value.clone()); },
+      }
+    }
+    Self::with_names(install_name, extra_name)
+  }
+
+  pub fn with_names(install_name: BTreeMap<String, String>, extra_name: BTreeMap<String, String>) -> Self {
+    let (patterns, replacements): (Vec<_>, Vec<_>) = install_name.iter().chain(&extra_name)
+      .map(|(i, v)| (i.clone(), v.clone())).unzip();
+    let matcher = AhoCorasick::builder()
+      .match_kind(MatchKind::LeftmostLongest)
+      .build(&patterns)
+      .expect("placeholders are short");
+    let token = AhoCorasick::new(["@@HOMEBREW_"]).expect("single pattern");
     Self {
-      install_name, extra_name,
+      install_name, extra_name, matcher, replacements, token,
     }
   }
 
+  /// `@@HOMEBREW_*@@` tokens in `data` which are not known placeholders
+  pub fn unknown_placeholders(&self, data: &[u8]) -> Vec<String> {
+    let mut result = std::collections::BTreeSet::new();
+    for m in self.token.find_iter(data) {
+      let len = data[m.end()..].iter().take_while(|i| i.is_ascii_uppercase() || i.is_ascii_digit() || **i == b'_').count();
+      let end = m.end() + len;
+      if len == 0 || !data[end..].starts_with(b"@@") {
+        continue;
+      }
+      let token = String::from_utf8_lossy(&data[m.start()..end + 2]);
+      if !self.install_name.contains_key(token.as_ref()) && !self.extra_name.contains_key(token.as_ref()) {
+        result.insert(token.into_owned());
+      }
+    }
+    result.into_iter().collect()
+  }
+
   pub fn replace_dylib<'a>(&self, name: &'a str) -> Cow<'a, str> {
     for (i, v) in &self.install_name {
       if name.starts_with(i) {
@@ -64,19 +137,83 @@ impl RelocationPattern {
   }
 
   pub fn replace_text<'a>(&self, s: &'a str) -> Cow<'a, str> {
-    let mut t = Cow::Borrowed(s);
-    // TODO: scan str only once
-    for (i, v) in self.install_name.iter().chain(&self.extra_name) {
-      t = Cow::Owned(t.replace(i, v));
+    if !self.matcher.is_match(s) {
+      return Cow::Borrowed(s);
     }
-    if t == s {
-      Cow::Borrowed(s)
-    } else {
-      t
+    Cow::Owned(self.matcher.replace_all(s, &self.replacements))
+  }
+
+  /// write `data` to `writer` with every placeholder replaced, returns false (and writes nothing) if there is none
+  pub fn replace_bytes_to<W: std::io::Write>(&self, data: &[u8], mut writer: W) -> std::io::Result<bool> {
+    let mut last = 0;
+    for m in self.matcher.find_iter(data) {
+      writer.write_all(&data[last..m.start()])?;
+      writer.write_all(self.replacements[m.pattern()].as_bytes())?;
+      last = m.end();
+    }
+    if last == 0 {
+      return Ok(false);
+    }
+    writer.write_all(&data[last..])?;
+    Ok(true)
+  }
+}
+
+impl RelocationPattern {
+  /// replace placeholders in NUL terminated strings of `data` in place, a string gets padded with NUL when it is shorter,
+  /// those would be longer are left as is and pushed to `unrelocated`, returns false if nothing changed
+  pub fn replace_c_strings(&self, data: &mut [u8], unrelocated: &mut Vec<String>) -> bool {
+    let mut changed = false;
+    let mut pos = 0;
+    while let Some(m) = self.matcher.find(&data[pos..]) {
+      let start = pos + m.start();
+      let Some(len) = data[start..].iter().position(|&i| i == 0) else {
+        unrelocated.push(String::from_utf8_lossy(&data[start..]).into_owned());
+        break;
+      };
+      let end = start + len;
+      let mut new = Vec::with_capacity(len);
+      self.replace_bytes_to(&data[start..end], &mut new).expect("write to vec");
+      if new.len() <= len {
+        data[start..start + new.len()].copy_from_slice(&new);
+        data[start + new.len()..end].fill(0);
+        changed = true;
+      } else {
+        unrelocated.push(String::from_utf8_lossy(&data[start..end]).into_owned());
+      }
+      pos = end;
     }
+    changed
   }
 }
 
+/// same as git, a file with NUL in the first 8KiB is binary
+fn is_text(data: &[u8]) -> bool {
+  !data[..data.len().min(8192)].contains(&0)
+}
+
+/// relocate the text file `filename` whose content is `data`, through a temporary file in the same directory
+fn relocate_text(filename: &Path, data: &[u8], pattern: &RelocationPattern) -> Result<bool> {
+  if !pattern.matcher.is_match(data) {
+    return Ok(false);
+  }
+  let mut tmp_name = filename.file_name().unwrap_or_default().to_os_string();
+  tmp_name.push(".reloc");
+  let tmp = filename.with_file_name(tmp_name);
+  let result = (|| {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    pattern.replace_bytes_to(data, &mut writer)?;
+    writer.flush()?;
+    std::fs::set_permissions(&tmp, std::fs::metadata(filename)?.permissions())?;
+    std::fs::rename(&tmp, filename)
+  })();
+  if result.is_err() {
+    std::fs::remove_file(&tmp).ok();
+  }
+  result.when(("write", &tmp))?;
+  Ok(true)
+}
+
 
 pub fn try_abs_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
   let path = path.as_ref();
@@ -87,7 +224,7 @@ pub fn try_abs_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
     } else {
       match Path::new(".").canonicalize() {
         Ok(cur) => cur.join(path),
-        Err(_) => unreachable!("current dir does not exist"),
+        Err(_) => return None,
       }
     }
   };
@@ -121,17 +258,11 @@ impl Relocations {
     let mut result = Self::default();
     if let Some(name) = file.name {
       if let Cow::Owned(new_name) = pattern.replace_dylib(name) {
-        // TODO: check size
-        // let new_len = new_name.len() as u32 / 8 + 1;
-        // new_sizeofcmds = new_sizeofcmds + new_len - name.len() as u32;
         result.id = (name.to_string(), new_name);
       }
     }
+    // libs are names of LC_LOAD_DYLIB, LC_LOAD_WEAK_DYLIB, LC_REEXPORT_DYLIB, LC_LAZY_LOAD_DYLIB and LC_LOAD_UPWARD_DYLIB
     for &name in &file.libs {
-      // TODO check if these command all processed
-      // LoadCommand::LoadDyLib(dylib) | LoadCommand::LoadWeakDyLib(dylib) |
-      // LoadCommand::ReexportDyLib(dylib) | LoadCommand::LoadUpwardDylib(dylib) |
-      // LoadCommand::LazyLoadDylib(dylib) => {
       if let Cow::Owned(new_name) = pattern.replace_dylib(name) {
         result.links.insert(name.to_string(), new_name);
       }
@@ -141,88 +272,194 @@ impl Relocations {
         result.rpaths.insert(name.to_string(), new_name);
       }
     }
-    // TODO: check if processed OFile::FatFile { files, .. } | OFile::ArFile { files }
     Ok(result)
   }
 
   pub fn is_empty(&self) -> bool {
     return self.id.0.is_empty() && self.links.is_empty() && self.rpaths.is_empty()
   }
+}
 
-  pub fn apply_file<P: AsRef<Path>>(&self, filename: P) -> Result<(), std::io::Error> {
-    if self.is_empty() {
-      return Ok(())
-    }
-    trace!(filename=%filename.as_ref().display(), "patch macho file");
+/// a NUL terminated string at `offset` of an ELF file, which has room for `capacity` bytes
+#[derive(Clone, Debug)]
+pub struct ElfPatch {
+  pub offset: usize,
+  pub capacity: usize,
+  pub old: String,
+  pub new: String,
+}
 
-    let mut cmd = &mut std::process::Command::new("install_name_tool");
-    if self.id.0 != "" {
-      cmd = cmd.args(["-id", &self.id.1]);
-    }
-    for (i, v) in &self.links {
-      cmd = cmd.args(["-change", i, v])
-    }
-    for (i, v) in &self.rpaths {
-      cmd = cmd.args(["-rpath", i, v])
+#[derive(Default, Clone, Debug)]
+pub struct ElfRelocations {
+  pub patches: Vec<ElfPatch>,
+}
+
+fn c_str_at(data: &[u8], offset: usize) -> Option<&str> {
+  let bytes = data.get(offset..)?;
+  let len = bytes.iter().position(|&i| i == 0)?;
+  std::str::from_utf8(&bytes[..len]).ok()
+}
+
+impl ElfRelocations {
+  pub fn from_elf(file: &Elf, data: &[u8], pattern: &RelocationPattern) -> Self {
+    let mut result = Self::default();
+    let mut push = |offset: usize, capacity: usize, old: &str| {
+      if let Cow::Owned(new) = pattern.replace_text(old) {
+        result.patches.push(ElfPatch { offset, capacity, old: old.to_string(), new });
+      }
+    };
+    for ph in file.program_headers.iter().filter(|i| i.p_type == PT_INTERP) {
+      if let Some(interp) = c_str_at(data, ph.p_offset as usize) {
+        let offset = ph.p_offset as usize;
+        let capacity = (ph.p_filesz as usize).saturating_sub(1).min(data.len() - offset - 1).max(interp.len());
+        push(offset, capacity, interp);
+      }
     }
-    cmd = cmd.arg(filename.as_ref());
-    debug!(?cmd, "install_name_tool");
-    let result = cmd.output()?;
-    for i in String::from_utf8_lossy(&result.stderr).lines() {
-      if i.is_empty() || i.contains("invalidate the code signature") {
+    let Some(dynamic) = &file.dynamic else {
+      return result;
+    };
+    // DT_STRTAB is an address, find the file offset in loaded segments
+    let strtab = dynamic.info.strtab as u64;
+    let Some(strtab) = file.program_headers.iter()
+      .filter(|i| i.p_type == PT_LOAD && i.p_vaddr <= strtab && strtab < i.p_vaddr + i.p_filesz)
+      .map(|i| (strtab - i.p_vaddr + i.p_offset) as usize)
+      .next() else {
+      return result;
+    };
+    let mut seen = std::collections::BTreeSet::new();
+    for d in dynamic.dyns.iter().filter(|i| matches!(i.d_tag, DT_NEEDED | DT_SONAME | DT_RPATH | DT_RUNPATH)) {
+      let offset = strtab + d.d_val as usize;
+      if !seen.insert(offset) {
         continue;
       }
-      warn!("stderr: {}", i);
-    }
-    if !result.status.success() {
-      return Err(std::io::Error::other("install_name_tool"));
+      if let Some(value) = c_str_at(data, offset) {
+        push(offset, value.len(), value);
+      }
     }
+    result
+  }
 
-    let result = &mut std::process::Command::new("codesign")
-        .args(["--sign", "-", "--force", "--preserve-metadata=entitlements,requirements,flags,runtime"])
-        .arg(filename.as_ref()).output()?;
-    if !result.status.success() {
-      return Err(std::io::Error::other("codesign"));
-    }
-    for i in String::from_utf8_lossy(&result.stderr).lines() {
-      if i.is_empty() || i.contains("replacing existing signature") {
+  pub fn is_empty(&self) -> bool {
+    self.patches.is_empty()
+  }
+
+  /// patch `data` of the ELF file `filename` with the patches which fit, returns false if nothing changed.
+  /// strings too long keep their placeholders, which `replace_c_strings` pushes to `unrelocated` later
+  pub fn apply(&self, data: &mut [u8], filename: &Path) -> bool {
+    trace!(filename=%filename.display(), "patch elf file");
+    let mut changed = false;
+    for patch in &self.patches {
+      if patch.new.len() > patch.capacity {
+        debug!(old=patch.old, new=patch.new, capacity=patch.capacity, "elf string does not fit");
         continue;
       }
-      warn!("stderr: {}", i);
+      debug!(old=patch.old, new=patch.new, "patch elf string");
+      let target = &mut data[patch.offset..patch.offset + patch.capacity + 1];
+      target.fill(0);
+      target[..patch.new.len()].copy_from_slice(patch.new.as_bytes());
+      changed = true;
     }
-    Ok(())
+    changed
   }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+fn write_back(filename: &Path, data: &[u8]) -> Result<()> {
+  with_permission(filename, || std::fs::write(filename, data))
+    .when(("write", filename))?.when(("permission", filename))?;
+  Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum RelocateType {
-  MachO, Text, None
+  MachO, Elf, Text,
+  /// placeholders in the data of a binary which is neither Mach-O nor ELF
+  Binary,
+  #[default]
+  None,
 }
 
-pub fn relocate<P: AsRef<Path>>(filename: P, pattern: &RelocationPattern) -> Result<RelocateType> {
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Relocated {
+  pub ty: RelocateType,
+  /// strings with placeholders which could not be relocated in place
+  pub unrelocated: Vec<String>,
+}
+
+pub fn relocate<P: AsRef<Path>>(filename: P, pattern: &RelocationPattern) -> Result<Relocated> {
   let filename = filename.as_ref();
+  let mut result = Relocated::default();
   if !filename.exists() || filename.is_symlink() {
-    return Ok(RelocateType::None);
+    return Ok(result);
   }
   let file = std::fs::File::open(filename).when(("open", filename))?;
   let mmap = unsafe { MmapOptions::new().map(&file) }.when(("memmap", filename))?;
-  if let Ok(macho) = MachO::parse(&mmap, 0) {
-    let reloc = Relocations::from_macho(&macho, &pattern)?;
-    if !reloc.is_empty() {
+  let unknown = pattern.unknown_placeholders(&mmap);
+  if !unknown.is_empty() {
+    warn!(filename=%filename.display(), ?unknown, "unknown placeholders are left");
+  }
+  if let Some(slices) = macho::slices(&mmap) {
+    let mut data = mmap.to_vec();
+    drop(mmap);
+    let mut changed = false;
+    for (offset, size) in slices {
+      let slice = &mut data[offset..offset + size];
+      // slices may be static archives
+      let Ok(file) = MachO::parse(slice, 0) else {
+        changed |= pattern.replace_c_strings(slice, &mut result.unrelocated);
+        continue;
+      };
+      let reloc = Relocations::from_macho(&file, pattern)?;
+      drop(file);
+      let rewritten = !reloc.is_empty() && reloc.rewrite(slice, filename)?;
+      if pattern.replace_c_strings(slice, &mut result.unrelocated) | rewritten {
+        if !macho::resign(slice, filename)? {
+          debug!(filename=%filename.display(), "no code signature");
+        }
+        changed = true;
+      }
+    }
+    if changed {
       debug!(filename=%filename.display(), "reloc macho");
-      reloc.apply_file(filename).when(("otool", filename))?;
-      return Ok(RelocateType::MachO);
+      write_back(filename, &data)?;
     }
-  } else if let Ok(text) = std::fs::read_to_string(&filename) {
-    if let Cow::Owned(text) = pattern.replace_text(&text) {
+    // recorded even if nothing fits, so the placeholders left show up in db
+    if changed || !result.unrelocated.is_empty() {
+      result.ty = RelocateType::MachO;
+    }
+  } else if let Ok(elf) = Elf::parse(&mmap) {
+    let reloc = ElfRelocations::from_elf(&elf, &mmap, pattern);
+    drop(elf);
+    if !reloc.is_empty() || pattern.matcher.is_match(&mmap) {
+      let mut data = mmap.to_vec();
+      drop(mmap);
+      let patched = reloc.apply(&mut data, filename);
+      if pattern.replace_c_strings(&mut data, &mut result.unrelocated) | patched {
+        debug!(filename=%filename.display(), "reloc elf");
+        write_back(filename, &data)?;
+      }
+      // recorded even if nothing fits, so the placeholders left show up in db
+      if patched || !result.unrelocated.is_empty() {
+        result.ty = RelocateType::Elf;
+      }
+    }
+  } else if is_text(&mmap) {
+    if relocate_text(filename, &mmap, pattern)? {
       debug!(filename=%filename.display(), "reloc text");
-      with_permission(&filename, ||
-        std::fs::write(filename, text)
-      ).when(("write", &filename))?.when(("permission", &filename))?;
-      return Ok(RelocateType::Text)
+      result.ty = RelocateType::Text;
+    }
+  } else if pattern.matcher.is_match(&mmap) {
+    let mut data = mmap.to_vec();
+    drop(mmap);
+    if pattern.replace_c_strings(&mut data, &mut result.unrelocated) {
+      debug!(filename=%filename.display(), "reloc binary");
+      write_back(filename, &data)?;
+      result.ty = RelocateType::Binary;
     }
   }
-  return Ok(RelocateType::None)
+  if !result.unrelocated.is_empty() {
+    warn!(filename=%filename.display(), unrelocated=?result.unrelocated, "placeholders left, new value is longer");
+  }
+  Ok(result)
 }
 
 #[test]
@@ -230,7 +467,7 @@ fn test_relocate() {
   use crate::tests::*;
   init_logger(None);
   let tmp_dir = "cache/reloc/";
-  let pattern = RelocationPattern::new("cache", "cache/Cellar");
+  let pattern = RelocationPattern::new("cache", "cache/Cellar").unwrap();
   std::fs::create_dir_all(tmp_dir).ok();
   for file in walkdir::WalkDir::new("cache/root/opt") {
     let file = file.unwrap();
@@ -238,10 +475,188 @@ fn test_relocate() {
       let filename = Path::new(tmp_dir).join(file.file_name());
       std::fs::copy(file.path(), &filename).ok();
       let result = relocate(&filename, &pattern).unwrap();
-      if result != RelocateType::None {
+      if result != Relocated::default() {
         info!(?result, filename=%file.path().display());
       }
       std::fs::remove_file(&filename).ok();
     }
   }
 }
+
+/// a minimal ELF64 shared object with an interpreter, DT_NEEDED and DT_RUNPATH, loaded at address 0
+#[cfg(test)]
+fn elf_fixture(interp: &str, runpath: &str) -> Vec<u8> {
+  const INTERP: usize = 0x100;
+  const STRTAB: usize = 0x180;
+  const DYNAMIC: usize = 0x280;
+  const SIZE: usize = 0x300;
+  let mut data = vec![0u8; SIZE];
+  let put = |data: &mut Vec<u8>, offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+  put(&mut data, 0, b"\x7fELF\x02\x01\x01");
+  put(&mut data, 16, &3u16.to_le_bytes()); // ET_DYN
+  put(&mut data, 18, &62u16.to_le_bytes()); // EM_X86_64
+  put(&mut data, 20, &1u32.to_le_bytes());
+  put(&mut data, 32, &64u64.to_le_bytes()); // e_phoff
+  put(&mut data, 52, &64u16.to_le_bytes());
+  put(&mut data, 54, &56u16.to_le_bytes());
+  put(&mut data, 56, &3u16.to_le_bytes());
+  put(&mut data, 58, &64u16.to_le_bytes());
+  let strtab = format!("\0libc.so.6\0{}\0", runpath);
+  let dyns = [(DT_NEEDED, 1), (DT_RUNPATH, 11), (5, STRTAB as u64), (10, strtab.len() as u64), (0, 0)];
+  let phdrs = [(PT_LOAD, 0, SIZE), (PT_INTERP, INTERP, interp.len() + 1), (2, DYNAMIC, dyns.len() * 16)];
+  for (i, (ty, offset, size)) in phdrs.into_iter().enumerate() {
+    let base = 64 + i * 56;
+    put(&mut data, base, &ty.to_le_bytes());
+    put(&mut data, base + 4, &4u32.to_le_bytes());
+    for (field, value) in [offset, offset, offset, size, size, 8].into_iter().enumerate() {
+      put(&mut data, base + 8 + field * 8, &(value as u64).to_le_bytes());
+    }
+  }
+  put(&mut data, INTERP, interp.as_bytes());
+  put(&mut data, STRTAB, strtab.as_bytes());
+  for (i, (tag, value)) in dyns.into_iter().enumerate() {
+    put(&mut data, DYNAMIC + i * 16, &tag.to_le_bytes());
+    put(&mut data, DYNAMIC + i * 16 + 8, &value.to_le_bytes());
+  }
+  data
+}
+
+#[test]
+fn test_relocate_elf() {
+  let tmp_dir = std::env::temp_dir().join(format!("pacbrew-test-elf-{}", std::process::id()));
+  std::fs::create_dir_all(&tmp_dir).unwrap();
+  let filename = tmp_dir.join("libfoo.so");
+  let fixture = elf_fixture("@@HOMEBREW_PREFIX@@/lib/ld.so", "@@HOMEBREW_PREFIX@@/lib:$ORIGIN");
+
+  std::fs::write(&filename, &fixture).unwrap();
+  let pattern = RelocationPattern::new("/pb", "/pb/Cellar").unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::Elf);
+  let data = std::fs::read(&filename).unwrap();
+  assert_eq!(data.len(), fixture.len());
+  let elf = Elf::parse(&data).unwrap();
+  // the interpreter keeps its size and is padded with NUL, which the loader ignores
+  assert_eq!(elf.interpreter.map(|i| i.trim_end_matches('\0')), Some("/pb/lib/ld.so"));
+  assert_eq!(elf.libraries, vec!["libc.so.6"]);
+  assert_eq!(elf.runpaths, vec!["/pb/lib:$ORIGIN"]);
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::None);
+
+  // strings fit are still patched, the RUNPATH too long is left and reported
+  let fixture = elf_fixture("@@HOMEBREW_PREFIX@@/lib/ld.so", "@@HOMEBREW_CELLAR@@/foo/1.0/lib");
+  std::fs::write(&filename, &fixture).unwrap();
+  let pattern = RelocationPattern::new("/pb", "/home/linuxbrew/.linuxbrew/Cellar").unwrap();
+  let relocated = relocate(&filename, &pattern).unwrap();
+  assert_eq!(relocated.ty, RelocateType::Elf);
+  assert_eq!(relocated.unrelocated, vec!["@@HOMEBREW_CELLAR@@/foo/1.0/lib".to_string()]);
+  let data = std::fs::read(&filename).unwrap();
+  let elf = Elf::parse(&data).unwrap();
+  assert_eq!(elf.interpreter.map(|i| i.trim_end_matches('\0')), Some("/pb/lib/ld.so"));
+  assert_eq!(elf.runpaths, vec!["@@HOMEBREW_CELLAR@@/foo/1.0/lib"]);
+  std::fs::remove_dir_all(&tmp_dir).ok();
+}
+
+#[test]
+fn test_relocate_macho() {
+  let tmp_dir = std::env::temp_dir().join(format!("pacbrew-test-macho-{}", std::process::id()));
+  std::fs::create_dir_all(&tmp_dir).unwrap();
+  let filename = tmp_dir.join("libfoo.dylib");
+  let arm64 = macho::fixture(0x0100000c, "@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib", &["@@HOMEBREW_CELLAR@@/bar/1.0/lib/libbar.dylib"], &[]);
+  let x86_64 = macho::fixture(0x01000007, "@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib", &["/usr/lib/libSystem.B.dylib"], &[]);
+  let fixture = macho::fat_fixture(&[arm64, x86_64]);
+
+  std::fs::write(&filename, &fixture).unwrap();
+  let pattern = RelocationPattern::new("/pb", "/pb/Cellar").unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::MachO);
+  let data = std::fs::read(&filename).unwrap();
+  assert_eq!(data.len(), fixture.len());
+  let slices = macho::slices(&data).unwrap();
+  assert_eq!(slices.len(), 2);
+  let mut libs = Vec::new();
+  for (offset, size) in slices {
+    let slice = &data[offset..offset + size];
+    assert!(macho::fixture_signed(slice));
+    let file = MachO::parse(slice, 0).unwrap();
+    assert_eq!(file.name, Some("/pb/opt/foo/lib/libfoo.dylib"));
+    libs.push(file.libs[1].to_string());
+  }
+  assert_eq!(libs, vec!["/pb/Cellar/bar/1.0/lib/libbar.dylib", "/usr/lib/libSystem.B.dylib"]);
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::None);
+
+  // the id outgrows the headerpad, the lib is still relocated
+  let thin = macho::fixture(0x0100000c, "@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib", &["@@HOMEBREW_CELLAR@@/bar/1.0/lib/libbar.dylib"], &[]);
+  std::fs::write(&filename, &thin).unwrap();
+  let long = format!("/{}", "p".repeat(0x300));
+  let relocated = relocate(&filename, &RelocationPattern::new(&long, "/pb/Cellar").unwrap()).unwrap();
+  assert_eq!(relocated.ty, RelocateType::MachO);
+  assert_eq!(relocated.unrelocated, vec!["@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib".to_string()]);
+  let data = std::fs::read(&filename).unwrap();
+  assert!(macho::fixture_signed(&data));
+  let file = MachO::parse(&data, 0).unwrap();
+  assert_eq!(file.name, Some("@@HOMEBREW_PREFIX@@/opt/foo/lib/libfoo.dylib"));
+  assert_eq!(file.libs[1], "/pb/Cellar/bar/1.0/lib/libbar.dylib");
+  std::fs::remove_dir_all(&tmp_dir).ok();
+}
+
+#[test]
+fn test_relocate_text() {
+  let pattern = RelocationPattern::new("/pb", "/pb/Cellar").unwrap();
+  assert_eq!(pattern.replace_text("prefix=@@HOMEBREW_PREFIX@@\nlibdir=@@HOMEBREW_CELLAR@@/foo/lib"), "prefix=/pb\nlibdir=/pb/Cellar/foo/lib");
+  assert!(matches!(pattern.replace_text("prefix=/usr"), Cow::Borrowed(_)));
+
+  let tmp_dir = std::env::temp_dir().join(format!("pacbrew-test-text-{}", std::process::id()));
+  std::fs::create_dir_all(&tmp_dir).unwrap();
+  let filename = tmp_dir.join("foo.pl");
+  // latin-1 is not valid utf-8
+  std::fs::write(&filename, b"#!@@HOMEBREW_PERL@@\n# caf\xe9 @@HOMEBREW_PREFIX@@@@HOMEBREW_PREFIX@@\n").unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::Text);
+  assert_eq!(std::fs::read(&filename).unwrap(), b"#!/usr/bin/perl\n# caf\xe9 /pb/pb\n");
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::None);
+
+  let binary = b"\0@@HOMEBREW_PREFIX@@";
+  std::fs::write(&filename, binary).unwrap();
+  assert_eq!(relocate(&filename, &pattern).unwrap().ty, RelocateType::None);
+  assert_eq!(std::fs::read(&filename).unwrap(), binary);
+  std::fs::remove_dir_all(&tmp_dir).ok();
+}
+
+#[test]
+fn test_relocate_binary() {
+  let pattern = RelocationPattern::new("/pb", "/pb/Cellar").unwrap();
+  let mut data = b"\0prefix=@@HOMEBREW_PREFIX@@/share\0@@HOMEBREW_CELLAR@@\0@@HOMEBREW_JAVA@@\0".to_vec();
+  let mut unrelocated = Vec::new();
+  assert!(pattern.replace_c_strings(&mut data, &mut unrelocated));
+  assert_eq!(data, b"\0prefix=/pb/share\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0/pb/Cellar\0\0\0\0\0\0\0\0\0\0@@HOMEBREW_JAVA@@\0");
+  assert_eq!(unrelocated, vec!["@@HOMEBREW_JAVA@@"]);
+
+  let tmp_dir = std::env::temp_dir().join(format!("pacbrew-test-binary-{}", std::process::id()));
+  std::fs::create_dir_all(&tmp_dir).unwrap();
+  let filename = tmp_dir.join("foo.bin");
+  std::fs::write(&filename, b"\0@@HOMEBREW_PREFIX@@/etc\0@@HOMEBREW_PREFIX@@").unwrap();
+  let result = relocate(&filename, &pattern).unwrap();
+  assert_eq!(result.ty, RelocateType::Binary);
+  // not terminated by NUL
+  assert_eq!(result.unrelocated, vec!["@@HOMEBREW_PREFIX@@"]);
+  assert_eq!(std::fs::read(&filename).unwrap(), b"\0/pb/etc\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0@@HOMEBREW_PREFIX@@");
+  std::fs::remove_dir_all(&tmp_dir).ok();
+}
+
+#[test]
+fn test_placeholders() {
+  let names = BTreeMap::from([
+    ("HOMEBREW_PERL".to_string(), "/opt/perl/bin/perl".to_string()),
+    ("@@HOMEBREW_PYTHON@@".to_string(), "/usr/bin/python3".to_string()),
+  ]);
+  let pattern = RelocationPattern::new("/pb", "/pb/Cellar").unwrap().placeholders(&names);
+  assert_eq!(
+    pattern.replace_text("#!@@HOMEBREW_PERL@@ @@HOMEBREW_PYTHON@@ @@HOMEBREW_LIBRARY@@ @@HOMEBREW_RUBY@@"),
+    "#!/opt/perl/bin/perl /usr/bin/python3 /pb/Library @@HOMEBREW_RUBY@@",
+  );
+  let data = b"@@HOMEBREW_RUBY@@ @@HOMEBREW_PREFIX@@ @@HOMEBREW_ @@HOMEBREW_lower@@ @@HOMEBREW_RUBY@@";
+  assert_eq!(pattern.unknown_placeholders(data), vec!["@@HOMEBREW_RUBY@@"]);
+
+  #[cfg(unix)]
+  {
+    use std::os::unix::ffi::OsStrExt;
+    let prefix = Path::new(std::ffi::OsStr::from_bytes(b"/pb\xff"));
+    assert!(matches!(RelocationPattern::new(prefix, "/pb/Cellar"), Err(Error::InvalidPath { .. })));
+  }
+}
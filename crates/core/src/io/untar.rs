@@ -125,6 +125,21 @@ impl<R: tokio::io::AsyncRead + Unpin> ArchiveExt for tokio_tar::Archive<R> {
 }
 
 
+/// paths of entries in a tar.gz without unpacking it, with whether the entry is a dir
+pub async fn list_gz<P: AsRef<Path>>(tar: P) -> Result<Vec<(PathBuf, bool)>> {
+  let tar = tar.as_ref();
+  let file = tokio::fs::File::open(tar).await.when(("list.open", tar))?;
+  let mut archive = Archive::new(file, GzipTransformer);
+  let mut archive = archive.get().await?;
+  let mut entries = archive.entries().when(("list.read_entries", tar))?;
+  let mut result = Vec::new();
+  while let Some(entry) = entries.next().await {
+    let entry = entry.when(("list.get_entry", tar))?;
+    let entry_path = entry.path().when(("list.get_entry_path", tar))?.into_owned();
+    result.push((entry_path, entry.header().entry_type().is_dir()));
+  }
+  Ok(result)
+}
 pub async fn untar_gz<P1: AsRef<Path>, P2: AsRef<Path>>(tar: P1, dest: P2, tracker: impl EventListener<UnpackEvent>) -> Result<(usize, u64)> {
   let file = tokio::fs::File::open(tar.as_ref()).await.when(("untar.open", tar.as_ref()))?;
   let mut archive = Archive::new(file, GzipTransformer);
@@ -5,6 +5,8 @@ pub mod error;
 pub mod ui;
 
 pub mod db;
+pub mod transaction;
+pub mod lock;
 pub mod package;
 pub mod stage;
 pub mod io;
@@ -21,16 +23,41 @@ mod tests {
   pub static ARCH: &str = "arm64_sequoia";
   pub static MIRROR: (MirrorType, &str) = (MirrorType::Bottle, "https://mirrors.ustc.edu.cn/homebrew-bottles");
 
+  pub fn get_arch() -> crate::package::arch::ArchPolicy {
+    crate::package::arch::ArchPolicy::new(ARCH.parse().unwrap())
+  }
+
   pub fn get_mirrors() -> MirrorLists {
-    MirrorLists {
-      lists: vec![MirrorServer::new(MIRROR.0, MIRROR.1, None)]
-    }
+    MirrorLists::new(vec![MirrorServer::new(MIRROR.0, MIRROR.1, None)], Default::default())
   }
 
   pub fn get_formulas() -> Vec<Formula> {
     crate::io::read::read_formulas(FORMULA_FILE).unwrap()
   }
 
+  pub fn get_index() -> crate::package::index::FormulaIndex {
+    use crate::package::index::FormulaIndex;
+    FormulaIndex::from_bytes(FormulaIndex::build(get_formulas(), (0, 0)).unwrap()).unwrap()
+  }
+
+  /// a minimal formula as in formula.json, with a bottle for `arch`
+  pub fn formula_json(name: &str, version: &str, deps: &[&str], arch: &str) -> serde_json::Value {
+    serde_json::json!({
+      "name": name, "full_name": name, "tap": "homebrew/core", "oldname": null, "oldnames": [], "aliases": [],
+      "versioned_formulae": [], "desc": "", "license": null, "homepage": "",
+      "versions": { "stable": version, "head": null, "bottle": true },
+      "urls": {}, "revision": 0, "version_scheme": 0,
+      "bottle": { "stable": { "rebuild": 0, "root_url": "https://ghcr.io/v2/homebrew/core", "files": {
+        arch: { "cellar": ":any", "url": format!("https://ghcr.io/v2/homebrew/core/{}/blobs/sha256:{}", name, "0".repeat(64)), "sha256": "0".repeat(64) },
+      } } },
+      "pour_bottle_only_if": null, "keg_only": false, "keg_only_reason": null, "options": [],
+      "build_dependencies": [], "dependencies": deps, "test_dependencies": [], "recommended_dependencies": [], "optional_dependencies": [],
+      "uses_from_macos": [], "uses_from_macos_bounds": [], "requirements": [], "conflicts_with": [], "link_overwrite": [],
+      "caveats": null, "deprecated": false, "deprecation_date": null, "deprecation_reason": null,
+      "disabled": false, "disable_date": null, "disable_reason": null, "post_install_defined": false,
+    })
+  }
+
   pub fn init_logger(env_filter: Option<&str>) -> Arc<RwLock<Option<Suspendable>>> {
     use tracing_subscriber::fmt::format::FmtSpan;
     let active_pb = Arc::new(RwLock::new(None));
@@ -42,4 +69,100 @@ mod tests {
       .try_init();
     result
   }
+
+  /// a tiny http/1.1 server for tests, every connection serves a single request.
+  pub mod stub {
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::{io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader}, net::TcpListener};
+
+    pub struct Request {
+      pub method: String,
+      /// path with query
+      pub path: String,
+      pub headers: HashMap<String, String>,
+      /// e.g. http://127.0.0.1:1234
+      pub base: String,
+    }
+
+    impl Request {
+      pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.get(&name.to_ascii_lowercase())
+      }
+    }
+
+    pub struct Response {
+      pub status: u16,
+      pub headers: Vec<(String, String)>,
+      pub body: Vec<u8>,
+      /// send `content-length`, otherwise the body ends when the connection closes
+      pub length: bool,
+    }
+
+    impl Response {
+      pub fn new(status: u16, body: Vec<u8>) -> Self {
+        Self { status, headers: Vec::new(), body, length: true }
+      }
+      pub fn json(value: &serde_json::Value) -> Self {
+        Self::new(200, value.to_string().into_bytes()).header("content-type", "application/json")
+      }
+      pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+      }
+      pub fn no_length(self) -> Self {
+        Self { length: false, ..self }
+      }
+    }
+
+    /// serve `handler` on a random local port, returns the base url
+    pub async fn serve<F: Fn(&Request) -> Response + Send + Sync + 'static>(handler: F) -> String {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let base = format!("http://{}", listener.local_addr().unwrap());
+      let handler = Arc::new(handler);
+      let result = base.clone();
+      tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+          let handler = handler.clone();
+          let base = base.clone();
+          tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.read_line(&mut line).await.ok()?;
+            let mut parts = line.split_whitespace();
+            let method = parts.next()?.to_string();
+            let path = parts.next()?.to_string();
+            let mut headers = HashMap::new();
+            loop {
+              let mut line = String::new();
+              stream.read_line(&mut line).await.ok()?;
+              match line.trim_end().split_once(':') {
+                Some((k, v)) => { headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string()); },
+                None => break,
+              }
+            }
+            let length = headers.get("content-length").and_then(|i| i.parse::<usize>().ok()).unwrap_or(0);
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.ok()?;
+            let req = Request { method, path, headers, base };
+            let resp = handler(&req);
+            let mut head = format!("HTTP/1.1 {} STUB\r\nconnection: close\r\n", resp.status);
+            if resp.length {
+              head.push_str(&format!("content-length: {}\r\n", resp.body.len()));
+            }
+            for (k, v) in &resp.headers {
+              head.push_str(&format!("{}: {}\r\n", k, v));
+            }
+            head.push_str("\r\n");
+            let stream = stream.get_mut();
+            stream.write_all(head.as_bytes()).await.ok()?;
+            if req.method != "HEAD" {
+              stream.write_all(&resp.body).await.ok()?;
+            }
+            stream.shutdown().await.ok()
+          });
+        }
+      });
+      result
+    }
+  }
 }
@@ -0,0 +1,113 @@
+//! advisory lock on db and prefix, like `db.lck` of pacman.
+//!
+//! commands changing db or the link tree hold an exclusive lock, read-only ones a shared lock.
+//! the exclusive holder writes its pid into the lock file so others could tell who is holding it,
+//! the lock is released by the os when the holder exits, so a stale lock file is harmless.
+
+use std::{fs::{File, TryLockError}, io::{Read as _, Seek as _, Write as _}, path::{Path, PathBuf}};
+
+use crate::error::{Error, ErrorExt, Result};
+
+pub const LOCK_FILE: &str = "db.lck";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+  Shared,
+  Exclusive,
+}
+
+#[derive(Debug)]
+pub struct Lock {
+  file: File,
+  path: PathBuf,
+  mode: LockMode,
+}
+
+impl Lock {
+  pub fn path(db_root: &Path) -> PathBuf {
+    db_root.join(LOCK_FILE)
+  }
+
+  /// lock `db_root`, fails with `Error::Locked` when held by others, or blocks until released with `wait`
+  pub fn acquire<P: AsRef<Path>>(db_root: P, mode: LockMode, wait: bool) -> Result<Self> {
+    let db_root = db_root.as_ref();
+    std::fs::create_dir_all(db_root).when(("create_dir_all", db_root))?;
+    let path = Self::path(db_root);
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).when(("open", &path))?;
+    let result = match mode {
+      LockMode::Shared => file.try_lock_shared(),
+      LockMode::Exclusive => file.try_lock(),
+    };
+    match result {
+      Ok(()) => {},
+      Err(TryLockError::WouldBlock) if wait => {
+        let error = Error::Locked { path: path.clone(), pid: Self::holder(&path) };
+        warn!(%error, "waiting for the lock");
+        match mode {
+          LockMode::Shared => file.lock_shared(),
+          LockMode::Exclusive => file.lock(),
+        }.when(("lock", &path))?;
+      },
+      Err(TryLockError::WouldBlock) => return Err(Error::Locked { pid: Self::holder(&path), path }),
+      Err(TryLockError::Error(e)) => return Err(e).when(("lock", &path)),
+    }
+    let mut lock = Self { file, path, mode };
+    if mode == LockMode::Exclusive {
+      lock.write_pid().when(("write pid", &lock.path))?;
+    }
+    debug!(path=%lock.path.display(), ?mode, "locked");
+    Ok(lock)
+  }
+
+  pub fn mode(&self) -> LockMode {
+    self.mode
+  }
+
+  /// pid of the exclusive holder, none for shared holders
+  pub fn holder(path: &Path) -> Option<u32> {
+    let mut content = String::new();
+    File::open(path).ok()?.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+  }
+
+  fn write_pid(&mut self) -> std::io::Result<()> {
+    self.file.set_len(0)?;
+    self.file.rewind()?;
+    write!(self.file, "{}", std::process::id())?;
+    self.file.sync_data()
+  }
+}
+
+impl Drop for Lock {
+  fn drop(&mut self) {
+    if self.mode == LockMode::Exclusive {
+      self.file.set_len(0).ok();
+    }
+    self.file.unlock().ok();
+  }
+}
+
+#[test]
+fn test_lock() {
+  let root = std::env::temp_dir().join(format!("pacbrew-lock-test-{}", std::process::id()));
+  std::fs::remove_dir_all(&root).ok();
+
+  let lock = Lock::acquire(&root, LockMode::Exclusive, false).unwrap();
+  assert_eq!(lock.mode(), LockMode::Exclusive);
+  for mode in [LockMode::Exclusive, LockMode::Shared] {
+    match Lock::acquire(&root, mode, false) {
+      Err(Error::Locked { pid, .. }) => assert_eq!(pid, Some(std::process::id())),
+      other => panic!("expect locked, got {:?}", other),
+    }
+  }
+  drop(lock);
+  assert_eq!(Lock::holder(&Lock::path(&root)), None);
+
+  let shared = Lock::acquire(&root, LockMode::Shared, false).unwrap();
+  let shared2 = Lock::acquire(&root, LockMode::Shared, false).unwrap();
+  assert!(matches!(Lock::acquire(&root, LockMode::Exclusive, false), Err(Error::Locked { pid: None, .. })));
+  drop((shared, shared2));
+  Lock::acquire(&root, LockMode::Exclusive, false).unwrap();
+
+  std::fs::remove_dir_all(&root).ok();
+}
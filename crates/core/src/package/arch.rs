@@ -0,0 +1,203 @@
+//! bottle tags like `arm64_sequoia`, `sonoma`, `x86_64_linux` or `all`,
+//! and the order to try them when a formula has no bottle for the host.
+//!
+//! a macOS bottle runs on the same or a newer macOS of the same cpu,
+//! a linux bottle runs on linux of the same cpu, and `all` runs anywhere.
+
+use crate::error::{Error, Result};
+
+use super::package::PkgBuild;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cpu {
+  Arm64,
+  X86_64,
+}
+
+impl Cpu {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Cpu::Arm64 => "arm64",
+      Cpu::X86_64 => "x86_64",
+    }
+  }
+}
+
+/// macOS releases known by homebrew, ordered from old to new
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MacOS {
+  Yosemite,
+  ElCapitan,
+  Sierra,
+  HighSierra,
+  Mojave,
+  Catalina,
+  BigSur,
+  Monterey,
+  Ventura,
+  Sonoma,
+  Sequoia,
+  Tahoe,
+}
+
+impl MacOS {
+  pub const ALL: [MacOS; 12] = [
+    MacOS::Yosemite, MacOS::ElCapitan, MacOS::Sierra, MacOS::HighSierra, MacOS::Mojave, MacOS::Catalina,
+    MacOS::BigSur, MacOS::Monterey, MacOS::Ventura, MacOS::Sonoma, MacOS::Sequoia, MacOS::Tahoe,
+  ];
+
+  pub fn codename(&self) -> &'static str {
+    match self {
+      MacOS::Yosemite => "yosemite",
+      MacOS::ElCapitan => "el_capitan",
+      MacOS::Sierra => "sierra",
+      MacOS::HighSierra => "high_sierra",
+      MacOS::Mojave => "mojave",
+      MacOS::Catalina => "catalina",
+      MacOS::BigSur => "big_sur",
+      MacOS::Monterey => "monterey",
+      MacOS::Ventura => "ventura",
+      MacOS::Sonoma => "sonoma",
+      MacOS::Sequoia => "sequoia",
+      MacOS::Tahoe => "tahoe",
+    }
+  }
+
+  pub fn from_codename(s: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|i| i.codename() == s)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde_with::SerializeDisplay, serde_with::DeserializeFromStr)]
+pub enum BottleTag {
+  All,
+  MacOS { cpu: Cpu, os: MacOS },
+  Linux { cpu: Cpu },
+}
+
+impl std::fmt::Display for BottleTag {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BottleTag::All => write!(f, "all"),
+      BottleTag::MacOS { cpu: Cpu::X86_64, os } => write!(f, "{}", os.codename()),
+      BottleTag::MacOS { cpu, os } => write!(f, "{}_{}", cpu.as_str(), os.codename()),
+      BottleTag::Linux { cpu } => write!(f, "{}_linux", cpu.as_str()),
+    }
+  }
+}
+
+impl std::str::FromStr for BottleTag {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self> {
+    let unknown = || Error::UnknownBottleTag(s.to_string());
+    if s == "all" {
+      return Ok(BottleTag::All);
+    }
+    let (cpu, rest) = match s.split_once('_') {
+      Some(("arm64", rest)) => (Cpu::Arm64, rest),
+      // `x86_64` contains the separator
+      _ => match s.strip_prefix("x86_64_") {
+        Some(rest) => (Cpu::X86_64, rest),
+        None => (Cpu::X86_64, s),
+      },
+    };
+    if rest == "linux" {
+      return Ok(BottleTag::Linux { cpu });
+    }
+    MacOS::from_codename(rest).map(|os| BottleTag::MacOS { cpu, os }).ok_or_else(unknown)
+  }
+}
+
+impl BottleTag {
+  /// whether a bottle of this tag runs on `host`
+  pub fn runs_on(&self, host: &BottleTag) -> bool {
+    match (self, host) {
+      (BottleTag::All, _) => true,
+      (BottleTag::MacOS { cpu, os }, BottleTag::MacOS { cpu: host_cpu, os: host_os }) => cpu == host_cpu && os <= host_os,
+      (BottleTag::Linux { cpu }, BottleTag::Linux { cpu: host_cpu }) => cpu == host_cpu,
+      _ => false,
+    }
+  }
+}
+
+/// bottle tags to try in order, `fallback` replaces the default chain of older macOS releases
+#[derive(Debug, Clone)]
+pub struct ArchPolicy {
+  pub tag: BottleTag,
+  pub fallback: Option<Vec<BottleTag>>,
+}
+
+impl ArchPolicy {
+  pub fn new(tag: BottleTag) -> Self {
+    Self { tag, fallback: None }
+  }
+
+  pub fn fallback(self, fallback: Vec<BottleTag>) -> Self {
+    for tag in fallback.iter().filter(|i| !i.runs_on(&self.tag)) {
+      warn!(%tag, host=%self.tag, "skip fallback bottle tag not running on host");
+    }
+    Self { fallback: Some(fallback), ..self }
+  }
+
+  /// the host tag first and `all` last, tags not running on the host are dropped
+  pub fn candidates(&self) -> Vec<BottleTag> {
+    let fallback = match (&self.fallback, self.tag) {
+      (Some(fallback), _) => fallback.clone(),
+      (None, BottleTag::MacOS { cpu, os }) => MacOS::ALL.into_iter().rev()
+        .filter(|i| *i < os)
+        .map(|os| BottleTag::MacOS { cpu, os })
+        .collect(),
+      (None, _) => vec![],
+    };
+    let mut result = vec![self.tag];
+    for tag in fallback.into_iter().chain([BottleTag::All]) {
+      if tag.runs_on(&self.tag) && !result.contains(&tag) {
+        result.push(tag);
+      }
+    }
+    result
+  }
+
+  /// the first prebuild in the order of `candidates`, unknown tags never match
+  pub fn select<'a>(&self, prebuilds: &'a [PkgBuild]) -> Option<&'a PkgBuild> {
+    let candidates = self.candidates();
+    prebuilds.iter()
+      .filter_map(|i| Some((candidates.iter().position(|tag| i.arch.parse().ok() == Some(*tag))?, i)))
+      .min_by_key(|(position, _)| *position)
+      .map(|(_, i)| i)
+  }
+}
+
+impl std::fmt::Display for ArchPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.candidates().iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" -> "))
+  }
+}
+
+#[test]
+fn test_arch() {
+  let tag = |s: &str| s.parse::<BottleTag>().unwrap();
+  for s in ["all", "arm64_sequoia", "sonoma", "big_sur", "arm64_big_sur", "x86_64_linux", "arm64_linux"] {
+    assert_eq!(tag(s).to_string(), s);
+  }
+  assert_eq!(tag("x86_64_linux"), BottleTag::Linux { cpu: Cpu::X86_64 });
+  assert_eq!(tag("high_sierra"), BottleTag::MacOS { cpu: Cpu::X86_64, os: MacOS::HighSierra });
+  assert!("arm64_windows".parse::<BottleTag>().is_err());
+
+  let policy = ArchPolicy::new(tag("arm64_sonoma"));
+  assert_eq!(policy.to_string(), "arm64_sonoma -> arm64_ventura -> arm64_monterey -> arm64_big_sur -> arm64_catalina -> arm64_mojave -> arm64_high_sierra -> arm64_sierra -> arm64_el_capitan -> arm64_yosemite -> all");
+  let policy = policy.fallback(vec![tag("arm64_sequoia"), tag("sonoma"), tag("arm64_ventura")]);
+  assert_eq!(policy.to_string(), "arm64_sonoma -> arm64_ventura -> all");
+  assert_eq!(ArchPolicy::new(tag("x86_64_linux")).to_string(), "x86_64_linux -> all");
+
+  let build = |arch: &str| PkgBuild {
+    name: "foo".to_string(), version: "1.0".to_string(), arch: arch.to_string(), rebuild: 0,
+    filename: format!("foo-1.0.{}.bottle.tar.gz", arch), url: String::new(), sha256: String::new(), cellar: ":any".to_string(),
+  };
+  let prebuilds = vec![build("all"), build("arm64_monterey"), build("arm64_ventura"), build("arm64_tahoe"), build("sonoma")];
+  let policy = ArchPolicy::new(tag("arm64_sonoma"));
+  assert_eq!(policy.select(&prebuilds).unwrap().arch, "arm64_ventura");
+  assert_eq!(ArchPolicy::new(tag("arm64_tahoe")).select(&prebuilds).unwrap().arch, "arm64_tahoe");
+  assert_eq!(ArchPolicy::new(tag("x86_64_linux")).select(&prebuilds).unwrap().arch, "all");
+  assert!(ArchPolicy::new(tag("x86_64_linux")).select(&prebuilds[1..]).is_none());
+}
@@ -170,7 +170,7 @@ use serde_with::{serde_as, TryFromInto};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Versions {
-  pub stable: String, // TODO: version schema
+  pub stable: String,
   pub head: Option<String>,
   pub bottle: bool,
 }
@@ -274,6 +274,23 @@ pub struct Reason<T> {
   pub explanation: String,
 }
 
+impl Reason<KegCode> {
+  /// `KegOnlyReason#to_s` of homebrew
+  pub fn describe(&self) -> String {
+    if !self.explanation.is_empty() {
+      return self.explanation.clone();
+    }
+    match self.reason {
+      KegCode::VersionedFormula => "this is an alternate version of another formula",
+      KegCode::ProvidedByMacos => "macOS already provides this software and installing another version in parallel can cause all kinds of trouble",
+      KegCode::ShadowedByMacos => "macOS provides similar software and installing this software in parallel can cause all kinds of trouble",
+      KegCode::ShadowsXcode => "it shadows the host toolchain",
+      KegCode::ShadowsMacos => "it shadows system commands",
+      KegCode::ConflictWith => "it conflicts with another formula",
+    }.to_string()
+  }
+}
+
 impl TryFrom<Reason<KegCode>> for Reason<String> {
   type Error = serde_json::Error;
   fn try_from(value: Reason<KegCode>) -> Result<Self, Self::Error> {
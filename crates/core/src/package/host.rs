@@ -0,0 +1,155 @@
+//! detect the platform pacbrew runs on, and the bottle tag to install for it.
+
+use std::process::Command;
+
+use crate::error::{Error, Result};
+
+use super::{arch::{BottleTag, Cpu, MacOS}, version::Version};
+
+/// homebrew bottles for linux are built on ubuntu 22.04, older glibc may fail to load them
+pub const GLIBC_MIN: &str = "2.35";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostOs {
+  /// `version` is the product version like `15.1`
+  MacOS { version: String, release: Option<MacOS> },
+  Linux { glibc: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+  pub cpu: Cpu,
+  pub os: HostOs,
+}
+
+impl MacOS {
+  /// the release of a product version like `10.15.7` or `15.1`
+  pub fn from_version(version: &str) -> Option<Self> {
+    let mut parts = version.trim().split('.').map(|i| i.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    Some(match (major, minor) {
+      (10, 10) => MacOS::Yosemite,
+      (10, 11) => MacOS::ElCapitan,
+      (10, 12) => MacOS::Sierra,
+      (10, 13) => MacOS::HighSierra,
+      (10, 14) => MacOS::Mojave,
+      (10, 15) => MacOS::Catalina,
+      (11, _) => MacOS::BigSur,
+      (12, _) => MacOS::Monterey,
+      (13, _) => MacOS::Ventura,
+      (14, _) => MacOS::Sonoma,
+      (15, _) => MacOS::Sequoia,
+      (26.., _) => MacOS::Tahoe,
+      _ => return None,
+    })
+  }
+}
+
+/// the glibc version from the first line of `ldd --version`, like `ldd (Ubuntu GLIBC 2.35-0ubuntu3.8) 2.35`
+pub fn glibc_from_ldd(output: &str) -> Option<String> {
+  let line = output.lines().next()?;
+  if !line.to_ascii_lowercase().contains("libc") {
+    return None;
+  }
+  let version = line.split_whitespace().last()?;
+  version.split('.').all(|i| !i.is_empty() && i.bytes().all(|c| c.is_ascii_digit())).then(|| version.to_string())
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+  let output = Command::new(program).args(args).output().ok()?;
+  if !output.status.success() {
+    debug!(program, status=%output.status, "command failed");
+    return None;
+  }
+  Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl Host {
+  pub fn cpu_of(arch: &str) -> Option<Cpu> {
+    match arch {
+      "aarch64" | "arm64" => Some(Cpu::Arm64),
+      "x86_64" => Some(Cpu::X86_64),
+      _ => None,
+    }
+  }
+
+  #[tracing::instrument(level = "debug")]
+  pub fn detect() -> Result<Self> {
+    let (arch, os) = (std::env::consts::ARCH, std::env::consts::OS);
+    let unsupported = || Error::UnsupportedHost(format!("{} {}", arch, os));
+    let cpu = Self::cpu_of(arch).ok_or_else(unsupported)?;
+    let os = match os {
+      "macos" => {
+        let version = command_output("sw_vers", &["-productVersion"]).ok_or_else(unsupported)?.trim().to_string();
+        HostOs::MacOS { release: MacOS::from_version(&version), version }
+      },
+      "linux" => {
+        // `ldd --version` prints to stdout with glibc, musl prints to stderr and fails
+        let glibc = command_output("ldd", &["--version"]).and_then(|i| glibc_from_ldd(&i));
+        HostOs::Linux { glibc }
+      },
+      _ => return Err(unsupported()),
+    };
+    let host = Self { cpu, os };
+    debug!(?host);
+    Ok(host)
+  }
+
+  pub fn tag(&self) -> Result<BottleTag> {
+    match &self.os {
+      HostOs::MacOS { release: Some(os), .. } => Ok(BottleTag::MacOS { cpu: self.cpu, os: *os }),
+      HostOs::MacOS { version, .. } => Err(Error::UnsupportedHost(format!("{} macOS {}", self.cpu.as_str(), version))),
+      HostOs::Linux { .. } => Ok(BottleTag::Linux { cpu: self.cpu }),
+    }
+  }
+
+  /// whether the host is able to run homebrew bottles for linux, always true on macOS
+  pub fn glibc_supported(&self) -> bool {
+    match &self.os {
+      HostOs::Linux { glibc: Some(glibc) } => Version::parse(glibc) >= Version::parse(GLIBC_MIN),
+      HostOs::Linux { glibc: None } => false,
+      HostOs::MacOS { .. } => true,
+    }
+  }
+}
+
+impl std::fmt::Display for Host {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.os {
+      HostOs::MacOS { version, release } => {
+        write!(f, "{} macOS {}", self.cpu.as_str(), version)?;
+        match release {
+          Some(release) => write!(f, " ({})", release.codename()),
+          None => Ok(()),
+        }
+      },
+      HostOs::Linux { glibc } => write!(f, "{} linux with glibc {}", self.cpu.as_str(), glibc.as_deref().unwrap_or("unknown")),
+    }
+  }
+}
+
+#[test]
+fn test_host() {
+  assert_eq!(MacOS::from_version("10.15.7"), Some(MacOS::Catalina));
+  assert_eq!(MacOS::from_version("15.1"), Some(MacOS::Sequoia));
+  assert_eq!(MacOS::from_version("14"), Some(MacOS::Sonoma));
+  assert_eq!(MacOS::from_version("26.0.1"), Some(MacOS::Tahoe));
+  assert_eq!(MacOS::from_version("10.9"), None);
+  assert_eq!(MacOS::from_version(""), None);
+
+  assert_eq!(glibc_from_ldd("ldd (Ubuntu GLIBC 2.35-0ubuntu3.8) 2.35\nCopyright (C) 2022\n").as_deref(), Some("2.35"));
+  assert_eq!(glibc_from_ldd("ldd (GNU libc) 2.39\n").as_deref(), Some("2.39"));
+  assert_eq!(glibc_from_ldd("musl libc (x86_64)\nVersion 1.2.4\n"), None);
+
+  let host = Host { cpu: Cpu::Arm64, os: HostOs::MacOS { version: "15.1".to_string(), release: Some(MacOS::Sequoia) } };
+  assert_eq!(host.tag().unwrap().to_string(), "arm64_sequoia");
+  assert_eq!(host.to_string(), "arm64 macOS 15.1 (sequoia)");
+  let host = Host { cpu: Cpu::X86_64, os: HostOs::Linux { glibc: Some("2.31".to_string()) } };
+  assert_eq!(host.tag().unwrap().to_string(), "x86_64_linux");
+  assert!(!host.glibc_supported());
+  assert!(Host { os: HostOs::Linux { glibc: Some("2.39".to_string()) }, ..host }.glibc_supported());
+
+  let host = Host::detect().unwrap();
+  assert!(host.tag().is_ok(), "{}", host);
+}
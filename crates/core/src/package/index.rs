@@ -0,0 +1,352 @@
+//! pre-indexed formula database, built from formula.json by `update_db`.
+//!
+//! parsing the whole formula.json takes seconds, so packages are kept in a binary file to mmap:
+//!
+//! ```text
+//! header:  magic, version, package count, name count, source length, source mtime
+//! offsets: [u64; package count + 1], bounds of every package in the data section
+//! names:   [(name offset u32, name length u32, package u32); name count], sorted by name
+//! strings: names referred by the name table
+//! data:    bincode of every PackageVersion
+//! ```
+//!
+//! names cover `name`, `full_name`, `aliases`, `oldname` and `oldnames`,
+//! so a lookup is a binary search, and only the package found is decoded.
+
+use std::{collections::HashMap, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use memmap2::Mmap;
+
+use crate::{error::{Error, ErrorExt, Result}, io::read::{read_formulas, tmp_path, write_to_file}};
+
+use super::{formula::Formula, package::PackageVersion};
+
+pub const INDEX_SUFFIX: &str = ".idx";
+const MAGIC: &[u8; 8] = b"PBIDX\0\0\0";
+/// bump when the layout or [`PackageVersion`] changes
+const VERSION: u32 = 4;
+const HEADER_LEN: usize = 8 + 4 + 4 + 4 + 8 + 8;
+const NAME_LEN: usize = 12;
+
+enum Data {
+  Mmap(Mmap),
+  Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for Data {
+  type Target = [u8];
+  fn deref(&self) -> &[u8] {
+    match self {
+      Data::Mmap(i) => i,
+      Data::Owned(i) => i,
+    }
+  }
+}
+
+pub struct FormulaIndex {
+  data: Data,
+  filename: PathBuf,
+  count: usize,
+  names: usize,
+  source: (u64, u64),
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+  u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// length and mtime in nanoseconds of formula.json, to tell a stale index
+fn source_of(path: &Path) -> Result<(u64, u64)> {
+  let meta = path.metadata().when(("metadata", path))?;
+  let mtime = meta.modified().ok()
+    .and_then(|i| i.duration_since(UNIX_EPOCH).ok())
+    .map(|i| i.as_nanos() as u64).unwrap_or_default();
+  Ok((meta.len(), mtime))
+}
+
+/// the index of `formula_json`
+pub fn index_path(formula_json: &Path) -> PathBuf {
+  tmp_path(formula_json, INDEX_SUFFIX)
+}
+
+impl FormulaIndex {
+  /// encode `formulas`, `source` is the length and mtime of the formula.json they come from
+  pub fn build(formulas: Vec<Formula>, source: (u64, u64)) -> Result<Vec<u8>> {
+    let mut names = HashMap::new();
+    let mut packages = Vec::with_capacity(formulas.len());
+    for (i, formula) in formulas.into_iter().enumerate() {
+      let i = i as u32;
+      // later ones win, as the lookups this replaces did
+      names.insert(formula.name.clone(), i);
+      names.extend(formula.oldname.iter().map(|n| (n.clone(), i)));
+      names.extend(formula.oldnames.iter().map(|n| (n.clone(), i)));
+      names.extend(formula.aliases.iter().map(|n| (n.clone(), i)));
+      names.insert(formula.full_name.clone(), i);
+      packages.push(PackageVersion::from(formula));
+    }
+    let mut names = names.into_iter().collect::<Vec<_>>();
+    names.sort();
+
+    let mut data = Vec::new();
+    let mut offsets = Vec::with_capacity(packages.len() + 1);
+    for package in &packages {
+      offsets.push(data.len() as u64);
+      bincode::serialize_into(&mut data, package).map_err(|e| Error::IndexMalformed { filename: PathBuf::new(), reason: e.to_string() })?;
+    }
+    offsets.push(data.len() as u64);
+
+    let mut strings = Vec::<u8>::new();
+    let mut table = Vec::<u8>::with_capacity(names.len() * NAME_LEN);
+    for (name, i) in &names {
+      table.extend((strings.len() as u32).to_le_bytes());
+      table.extend((name.len() as u32).to_le_bytes());
+      table.extend(i.to_le_bytes());
+      strings.extend(name.as_bytes());
+    }
+
+    let mut result = Vec::with_capacity(HEADER_LEN + offsets.len() * 8 + table.len() + strings.len() + data.len());
+    result.extend(MAGIC);
+    result.extend(VERSION.to_le_bytes());
+    result.extend((packages.len() as u32).to_le_bytes());
+    result.extend((names.len() as u32).to_le_bytes());
+    result.extend(source.0.to_le_bytes());
+    result.extend(source.1.to_le_bytes());
+    offsets.iter().for_each(|i| result.extend(i.to_le_bytes()));
+    result.extend(table);
+    result.extend(strings);
+    result.extend(data);
+    Ok(result)
+  }
+
+  /// parse formula.json and write its index next to it
+  #[tracing::instrument(level = "debug", skip_all, fields(formula_json = %formula_json.as_ref().display()))]
+  pub fn write<P: AsRef<Path>>(formula_json: P, formulas: Vec<Formula>) -> Result<PathBuf> {
+    let formula_json = formula_json.as_ref();
+    let filename = index_path(formula_json);
+    let data = Self::build(formulas, source_of(formula_json)?)?;
+    write_to_file(&filename, &data, true)?;
+    debug!(len = data.len(), filename = %filename.display(), "index written");
+    Ok(filename)
+  }
+
+  fn from_data(data: Data, filename: PathBuf) -> Result<Self> {
+    let malformed = |reason: &str| Error::IndexMalformed { filename: filename.clone(), reason: reason.to_string() };
+    if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+      return Err(malformed("bad magic"));
+    }
+    if read_u32(&data, 8) != VERSION {
+      return Err(malformed("version mismatch"));
+    }
+    let count = read_u32(&data, 12) as usize;
+    let names = read_u32(&data, 16) as usize;
+    let source = (read_u64(&data, 20), read_u64(&data, 28));
+    let strings_offset = count.checked_add(1)
+      .and_then(|i| i.checked_mul(8))
+      .and_then(|i| i.checked_add(HEADER_LEN))
+      .and_then(|i| i.checked_add(names.checked_mul(NAME_LEN)?))
+      .filter(|i| *i <= data.len())
+      .ok_or_else(|| malformed("truncated"))?;
+    let result = Self { data, filename: filename.clone(), count, names, source };
+    // validated once here, so lookups index the tables without checks
+    let strings_len = result.strings_len();
+    if strings_len > result.data.len() - strings_offset {
+      return Err(malformed("truncated"));
+    }
+    for i in 0..names {
+      let (offset, len, package) = result.name_entry_raw(i);
+      if offset + len > strings_len || package >= count {
+        return Err(malformed("name out of range"));
+      }
+    }
+    if (0..count).any(|i| result.offset(i) > result.offset(i + 1)) {
+      return Err(malformed("offsets not sorted"));
+    }
+    if result.offset(count) != (result.data.len() - result.data_offset()) as u64 {
+      return Err(malformed("truncated"));
+    }
+    Ok(result)
+  }
+
+  pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+    Self::from_data(Data::Owned(data), PathBuf::new())
+  }
+
+  /// mmap an index file
+  pub fn open<P: AsRef<Path>>(filename: P) -> Result<Self> {
+    let filename = filename.as_ref();
+    let file = std::fs::File::open(filename).when(("open", filename))?;
+    let mmap = unsafe { Mmap::map(&file) }.when(("memmap", filename))?;
+    Self::from_data(Data::Mmap(mmap), filename.to_owned())
+  }
+
+  /// open the index of `formula_json`, it is rebuilt when missing, broken or older than formula.json
+  #[tracing::instrument(level = "debug", skip_all, fields(formula_json = %formula_json.as_ref().display()))]
+  pub fn load<P: AsRef<Path>>(formula_json: P) -> Result<Self> {
+    let formula_json = formula_json.as_ref();
+    let filename = index_path(formula_json);
+    let source = source_of(formula_json)?;
+    if filename.exists() {
+      match Self::open(&filename) {
+        Ok(index) if index.source == source => return Ok(index),
+        Ok(_) => info!(filename=%filename.display(), "index outdated, rebuilding"),
+        Err(e) => warn!(error=%e, "index broken, rebuilding"),
+      }
+    }
+    let data = Self::build(read_formulas(formula_json)?, source)?;
+    if let Err(e) = write_to_file(&filename, &data, true) {
+      warn!(error=%e, "failed to save index");
+    }
+    Self::from_data(Data::Owned(data), filename)
+  }
+
+  fn data_offset(&self) -> usize {
+    HEADER_LEN + (self.count + 1) * 8 + self.names * NAME_LEN + self.strings_len()
+  }
+
+  fn offset(&self, i: usize) -> u64 {
+    read_u64(&self.data, HEADER_LEN + i * 8)
+  }
+
+  fn strings_offset(&self) -> usize {
+    HEADER_LEN + (self.count + 1) * 8 + self.names * NAME_LEN
+  }
+
+  fn strings_len(&self) -> usize {
+    match self.names {
+      0 => 0,
+      n => {
+        let (offset, len, _) = self.name_entry_raw(n - 1);
+        offset + len
+      }
+    }
+  }
+
+  fn name_entry_raw(&self, i: usize) -> (usize, usize, usize) {
+    let base = HEADER_LEN + (self.count + 1) * 8 + i * NAME_LEN;
+    (read_u32(&self.data, base) as usize, read_u32(&self.data, base + 4) as usize, read_u32(&self.data, base + 8) as usize)
+  }
+
+  fn name_entry(&self, i: usize) -> (&[u8], usize) {
+    let (offset, len, package) = self.name_entry_raw(i);
+    let start = self.strings_offset() + offset;
+    (&self.data[start..start + len], package)
+  }
+
+  pub fn len(&self) -> usize {
+    self.count
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.count == 0
+  }
+
+  /// decode the `i`-th package
+  pub fn package(&self, i: usize) -> Result<PackageVersion> {
+    if i >= self.count {
+      return Err(Error::IndexMalformed { filename: self.filename.clone(), reason: format!("package {i} out of range") });
+    }
+    let base = self.data_offset();
+    let (start, end) = (self.offset(i) as usize, self.offset(i + 1) as usize);
+    bincode::deserialize(&self.data[base + start..base + end])
+      .map_err(|e| Error::IndexMalformed { filename: self.filename.clone(), reason: e.to_string() })
+  }
+
+  fn position(&self, name: &str) -> Option<usize> {
+    let (mut lo, mut hi) = (0, self.names);
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      match self.name_entry(mid).0.cmp(name.as_bytes()) {
+        std::cmp::Ordering::Less => lo = mid + 1,
+        std::cmp::Ordering::Greater => hi = mid,
+        std::cmp::Ordering::Equal => return Some(self.name_entry(mid).1),
+      }
+    }
+    None
+  }
+
+  /// find by name, full name, alias or old name
+  pub fn get(&self, name: &str) -> Result<Option<PackageVersion>> {
+    self.position(name).map(|i| self.package(i)).transpose()
+  }
+
+  /// like [`Self::get`], but missing is an error
+  pub fn find(&self, name: &str) -> Result<PackageVersion> {
+    self.get(name)?.ok_or_else(|| Error::package_not_found(name))
+  }
+
+  /// every package, in the order of formula.json
+  pub fn iter(&self) -> impl Iterator<Item = Result<PackageVersion>> + '_ {
+    (0..self.count).map(|i| self.package(i))
+  }
+}
+
+#[test]
+fn test_index() {
+  use crate::tests::formula_json;
+  let mut bar = formula_json("bar", "2.0", &[], "x86_64_linux");
+  bar["aliases"] = serde_json::json!(["baz"]);
+  bar["oldnames"] = serde_json::json!(["bar-old"]);
+  bar["revision"] = serde_json::json!(1);
+  let formulas: Vec<Formula> = serde_json::from_value(serde_json::json!([
+    formula_json("foo", "1.0", &["bar"], "x86_64_linux"),
+    bar,
+  ])).unwrap();
+  let index = FormulaIndex::from_bytes(FormulaIndex::build(formulas, (0, 0)).unwrap()).unwrap();
+  assert_eq!(index.len(), 2);
+  assert_eq!(index.find("foo").unwrap().deps, vec!["bar"]);
+  for name in ["bar", "baz", "bar-old"] {
+    assert_eq!(index.find(name).unwrap().version_full(), "2.0_1");
+  }
+  assert!(index.get("qux").unwrap().is_none());
+  assert_eq!(index.iter().map(|i| i.unwrap().name).collect::<Vec<_>>(), vec!["foo", "bar"]);
+
+  let data = FormulaIndex::build(serde_json::from_value(serde_json::json!([
+    formula_json("foo", "1.0", &[], "x86_64_linux"),
+  ])).unwrap(), (0, 0)).unwrap();
+  let corrupt = |offset: usize, bytes: &[u8]| {
+    let mut data = data.clone();
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    FormulaIndex::from_bytes(data)
+  };
+  let names_offset = HEADER_LEN + 2 * 8;
+  for (offset, bytes) in [
+    // package count and name count
+    (12, &u32::MAX.to_le_bytes()[..]),
+    (16, &u32::MAX.to_le_bytes()[..]),
+    // offsets
+    (HEADER_LEN, &u64::MAX.to_le_bytes()[..]),
+    (HEADER_LEN + 8, &u64::MAX.to_le_bytes()[..]),
+    // name offset, name length and package of the first name
+    (names_offset, &u32::MAX.to_le_bytes()[..]),
+    (names_offset + 4, &u32::MAX.to_le_bytes()[..]),
+    (names_offset + 8, &1u32.to_le_bytes()[..]),
+  ] {
+    assert!(matches!(corrupt(offset, bytes), Err(Error::IndexMalformed { .. })), "corrupted at {offset}");
+  }
+  assert!(matches!(FormulaIndex::from_bytes(data[..data.len() - 1].to_vec()), Err(Error::IndexMalformed { .. })));
+
+  let dir = std::env::temp_dir().join(format!("pacbrew-test-index-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("formula.json");
+  std::fs::write(&path, serde_json::json!([formula_json("foo", "1.0", &[], "x86_64_linux")]).to_string()).unwrap();
+  assert_eq!(FormulaIndex::load(&path).unwrap().len(), 1);
+  assert!(index_path(&path).exists());
+  assert_eq!(FormulaIndex::open(index_path(&path)).unwrap().find("foo").unwrap().name, "foo");
+  // formula.json changed, the index is rebuilt
+  std::fs::write(&path, serde_json::json!([formula_json("foo", "1.0", &[], "x86_64_linux"), formula_json("bar", "1.0", &[], "x86_64_linux")]).to_string()).unwrap();
+  assert_eq!(FormulaIndex::load(&path).unwrap().len(), 2);
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_index_version() {
+  use crate::tests::formula_json;
+  let formula: Formula = serde_json::from_value(formula_json("foo", "1.0", &[], "x86_64_linux")).unwrap();
+  let len = bincode::serialize(&PackageVersion::from(formula)).unwrap().len();
+  // bincode is not self-describing, an index of the old layout would misdecode
+  assert_eq!((VERSION, len), (4, 362), "PackageVersion layout changed, bump VERSION and update this test");
+}
@@ -1,3 +1,5 @@
+use crate::{error::{ErrorExt, Result}, io::{fetch::NetworkPolicy, oci::{self, OciRegistry}}};
+
 use super::package::PkgBuild;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -10,6 +12,10 @@ pub struct MirrorServer {
   pub base_url: String,
   pub api_base_url: Option<String>,
   client: reqwest::Client,
+  /// decompresses responses by `Content-Encoding`, only for api files
+  api_client: reqwest::Client,
+  /// token and manifests of OCI mirrors
+  registry: Option<OciRegistry>,
 }
 
 impl MirrorServer {
@@ -17,35 +23,43 @@ impl MirrorServer {
     if server_type == MirrorType::Ghcr {
       warn!("should not use ghcr with custom base_url, please use MirrorServer::ghcr() instead");
     }
+    Self::build(server_type, base_url, api_base_url, &NetworkPolicy::default())
+  }
+  pub fn ghcr() -> Self {
+    Self::build(MirrorType::Ghcr, "https://ghcr.io/v2/homebrew/core/", Some("https://formulae.brew.sh/api/"), &NetworkPolicy::default())
+  }
+
+  fn build(server_type: MirrorType, base_url: &str, api_base_url: Option<&str>, policy: &NetworkPolicy) -> Self {
+    let client = Self::build_client(server_type, policy, false);
+    let api_client = Self::build_client(server_type, policy, true);
+    let registry = match server_type {
+      MirrorType::Ghcr | MirrorType::Oci => Some(OciRegistry::new(client.clone(), base_url)),
+      MirrorType::Bottle => None,
+    };
     Self {
       server_type,
       base_url: base_url.to_string(),
       api_base_url: api_base_url.map(|s| s.to_string()),
-      client: Self::build_client(server_type),
+      client,
+      api_client,
+      registry,
     }
   }
-  pub fn ghcr() -> Self {
-    Self {
-      server_type: MirrorType::Ghcr,
-      api_base_url: Some("https://formulae.brew.sh/api/".to_string()),
-      base_url: "https://ghcr.io/v2/homebrew/core/".to_string(),
-      client: Self::build_client(MirrorType::Ghcr),
-    }
+
+  /// rebuild the client to apply timeouts in `policy`
+  pub fn with_policy(self, policy: &NetworkPolicy) -> Self {
+    Self::build(self.server_type, &self.base_url, self.api_base_url.as_deref(), policy)
   }
 
-  fn build_client(server_type: MirrorType) -> reqwest::Client {
-    let builder = reqwest::Client::builder();
+  /// bottles must be kept byte by byte for sha256 and resuming, so `decompress` is only set for api files
+  fn build_client(server_type: MirrorType, policy: &NetworkPolicy, decompress: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder().connect_timeout(policy.connect_timeout);
+    let builder = match decompress {
+      true => builder,
+      false => builder.no_gzip().no_brotli().no_zstd(),
+    };
     let builder = match server_type {
-      MirrorType::Ghcr => {
-        use reqwest::header;
-        let mut headers = header::HeaderMap::new();
-        let mut auth_value = header::HeaderValue::from_static("Bearer QQ==");
-        auth_value.set_sensitive(true);
-        headers.insert(header::AUTHORIZATION, auth_value);
-        builder
-          .user_agent("pacbrew/0.1")
-          .default_headers(headers)
-      },
+      MirrorType::Ghcr => builder.user_agent("pacbrew/0.1"),
       MirrorType::Oci | MirrorType::Bottle => builder.user_agent("Wget/1.21.3"),
     };
     builder.build().expect("build client")
@@ -61,7 +75,7 @@ impl MirrorServer {
 
   pub fn package_url(&self, build: &PkgBuild) -> String {
     match self.server_type {
-      MirrorType::Oci | MirrorType::Ghcr => format!("{}/{}/blobs/sha256:{}", self.base_url.trim_end_matches('/'), build.name.replace("@", "/").replace("+", "x"), build.sha256),
+      MirrorType::Oci | MirrorType::Ghcr => format!("{}/{}/blobs/sha256:{}", self.base_url.trim_end_matches('/'), oci::repository_name(&build.name), build.sha256),
       MirrorType::Bottle => format!("{}/{}", self.base_url.trim_end_matches('/'), build.filename),
     }
   }
@@ -69,6 +83,23 @@ impl MirrorServer {
   pub fn client(&self) -> reqwest::Client {
     self.client.clone()
   }
+
+  /// client for api files, see `build_client`
+  pub fn api_client(&self) -> reqwest::Client {
+    self.api_client.clone()
+  }
+
+  pub fn registry(&self) -> Option<&OciRegistry> {
+    self.registry.as_ref()
+  }
+
+  /// send a request for package `build`, OCI mirrors would negotiate a token first.
+  pub async fn send<F: Fn(&reqwest::Client) -> reqwest::RequestBuilder>(&self, build: &PkgBuild, f: F) -> Result<reqwest::Response> {
+    match &self.registry {
+      Some(registry) => registry.send(&build.name, f).await,
+      None => f(&self.client).send().await.when(("send", &self.base_url)),
+    }
+  }
 }
 
 #[test]
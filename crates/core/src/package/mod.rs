@@ -1,3 +1,7 @@
 pub mod formula;
 pub mod package;
 pub mod mirror;
+pub mod index;
+pub mod version;
+pub mod arch;
+pub mod host;
@@ -1,7 +1,7 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
 
-use super::formula::Formula;
-use crate::io::relocate::RelocateType;
+use super::{arch::ArchPolicy, formula::Formula, version::Version};
+use crate::io::relocate::{try_abs_path, RelocateType};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Package {
@@ -17,22 +17,59 @@ pub struct Package {
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct PkgBuild {
   pub name: String,
+  /// `version_full`, which tags the bottle in OCI registries
+  #[serde(default)]
+  pub version: String,
   pub arch: String,
   pub rebuild: u32,
   pub filename: String,
   pub url: String,
   pub sha256: String,
+  /// `Bottle.cellar`, `:any`, `:any_skip_relocation` or the fixed cellar the bottle is built for
+  #[serde(default)]
+  pub cellar: String,
+}
+
+impl PkgBuild {
+  pub fn pour_mode<P: AsRef<Path>>(&self, cellar: P) -> PourMode {
+    PourMode::new(&self.cellar, cellar.as_ref())
+  }
+}
+
+/// how a bottle is poured into our cellar, decided by the cellar it is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PourMode {
+  /// `:any`, placeholders are replaced
+  Relocate,
+  /// `:any_skip_relocation`, or built for the cellar we use
+  SkipRelocation,
+  /// built for another fixed cellar, paths in it cannot be relocated
+  CellarMismatch,
+}
+
+impl PourMode {
+  pub fn new(bottle_cellar: &str, cellar: &Path) -> Self {
+    match bottle_cellar {
+      // empty for caches before the cellar is recorded
+      "" | ":any" => Self::Relocate,
+      ":any_skip_relocation" => Self::SkipRelocation,
+      path if try_abs_path(cellar).is_some_and(|cellar| cellar == Path::new(path)) => Self::SkipRelocation,
+      _ => Self::CellarMismatch,
+    }
+  }
 }
 
 impl std::fmt::Debug for PkgBuild {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("PkgBuild")
       .field("name", &self.name)
+      .field("version", &self.version)
       .field("arch", &self.arch)
       .field("rebuild", &self.rebuild)
       .field("filename", &self.filename)
       // .field("url", &self.url)
       .field("sha256", &self.sha256)
+      .field("cellar", &self.cellar)
       .finish()
   }
 }
@@ -42,11 +79,16 @@ pub struct PackageVersion {
   pub name: String,
   pub version: String,
   pub revision: u32,
+  #[serde(default)]
+  pub version_scheme: usize,
   pub desc: String,
   pub license: Option<String>,
   pub deps: Vec<String>,
   pub prebuilds: Vec<PkgBuild>,
   pub link_overwrite: Vec<String>,
+  /// why the formula is keg-only, which is not linked into prefix besides `opt/<name>`
+  #[serde(default)]
+  pub keg_only: Option<String>,
 }
 
 impl From<Formula> for PackageVersion {
@@ -56,6 +98,7 @@ impl From<Formula> for PackageVersion {
       .map(|(arch, meta, bottle)|
         PkgBuild {
           name: f.name.clone(),
+          version: version_full.clone(),
           arch: arch.to_string(),
           rebuild: meta.rebuild,
           filename: if meta.rebuild == 0 {
@@ -64,18 +107,21 @@ impl From<Formula> for PackageVersion {
             format!("{}-{}.{}.bottle.{}.tar.gz", f.name, version_full, arch, meta.rebuild)
           },
           url: bottle.url.clone(),
-          sha256: bottle.sha256.clone()
+          sha256: bottle.sha256.clone(),
+          cellar: bottle.cellar.clone(),
         })
       .collect::<Vec<_>>();
     Self {
       name: f.name,
       version: f.versions.stable,
       revision: f.revision,
+      version_scheme: f.version_scheme,
       desc: f.desc,
       license: f.license,
       deps: f.dependencies,
       prebuilds: tar,
       link_overwrite: f.link_overwrite,
+      keg_only: f.keg_only.then(|| f.keg_only_reason.map(|i| i.describe()).unwrap_or_default()),
     }
   }
 }
@@ -93,10 +139,14 @@ impl PackageVersion {
     }
   }
 
-  pub fn find_arch(&self, arch: &str) -> Option<&PkgBuild> {
-    // TODO: arch to enum, and fallback
-    self.prebuilds.iter().find(|i| i.arch == arch)
-      .or_else(|| self.prebuilds.iter().find(|i| i.arch == "all"))
+  /// comparable version with `version_scheme` and revision
+  pub fn pkg_version(&self) -> Version {
+    Version::new(&self.version, self.revision, self.version_scheme)
+  }
+
+  /// the bottle picked by `arch`, which may be a fallback tag
+  pub fn find_arch(&self, arch: &ArchPolicy) -> Option<&PkgBuild> {
+    arch.select(&self.prebuilds)
   }
 }
 
@@ -104,7 +154,8 @@ impl PackageVersion {
 pub struct PackageUrl {
   pub name: String,
   pub pkg_url: String,
-  pub pkg_size: u64,
+  /// none when no mirror tells the size, the download would run without a length
+  pub pkg_size: Option<u64>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -112,6 +163,9 @@ pub struct PackageCache {
   pub name: String,
   pub cache_pkg: PathBuf,
   pub cache_size: u64,
+  /// see `PkgBuild::cellar`
+  #[serde(default)]
+  pub cellar: String,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -120,6 +174,9 @@ pub struct PackageInstalled {
   pub dest: PathBuf,
   pub version: String,
   pub reloc: BTreeMap<PathBuf, RelocateType>,
+  /// strings with placeholders left in each file, see `Relocated::unrelocated`
+  #[serde(default)]
+  pub unrelocated: BTreeMap<PathBuf, Vec<String>>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -141,12 +198,24 @@ pub enum InstallReason {
 pub struct InstalledPackageRecord {
   pub name: String,
   pub version: String,
+  #[serde(default)]
+  pub version_scheme: usize,
   pub desc: String,
   pub license: Option<String>,
   pub deps: Vec<String>,
   pub reason: InstallReason,
   pub install_date: u64,
   pub dest: PathBuf,
+  /// see `PackageVersion::keg_only`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub keg_only: Option<String>,
+}
+
+impl InstalledPackageRecord {
+  /// comparable version, `version` is the full version with revision
+  pub fn pkg_version(&self) -> Version {
+    Version::parse(&self.version).with_scheme(self.version_scheme)
+  }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -154,4 +223,31 @@ pub struct InstalledPackage {
   pub record: InstalledPackageRecord,
   pub files: Vec<String>,
   pub reloc: BTreeMap<PathBuf, RelocateType>,
+  /// strings with placeholders left in each file, see `Relocated::unrelocated`
+  #[serde(default)]
+  pub unrelocated: BTreeMap<PathBuf, Vec<String>>,
+}
+
+impl InstalledPackage {
+  /// the keg of this version in the cellar
+  pub fn keg(&self) -> PackageInstalled {
+    PackageInstalled {
+      name: self.record.name.clone(),
+      dest: self.record.dest.clone(),
+      version: self.record.version.clone(),
+      reloc: self.reloc.clone(),
+      unrelocated: self.unrelocated.clone(),
+    }
+  }
+}
+
+#[test]
+fn test_pour_mode() {
+  let cellar = Path::new("/pb/Cellar");
+  assert_eq!(PourMode::new(":any", cellar), PourMode::Relocate);
+  assert_eq!(PourMode::new("", cellar), PourMode::Relocate);
+  assert_eq!(PourMode::new(":any_skip_relocation", cellar), PourMode::SkipRelocation);
+  assert_eq!(PourMode::new("/pb/Cellar", cellar), PourMode::SkipRelocation);
+  assert_eq!(PourMode::new("/pb/Cellar", Path::new("/pb/./Cellar/")), PourMode::SkipRelocation);
+  assert_eq!(PourMode::new("/opt/homebrew/Cellar", cellar), PourMode::CellarMismatch);
 }
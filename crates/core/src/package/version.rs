@@ -0,0 +1,252 @@
+//! version ordering as homebrew does.
+//!
+//! a version is split into tokens of numbers and words, where well known words are ranked
+//! `alpha < beta < pre < rc < (release) < p < .post`, so `1.0rc1 < 1.0 < 1.0p1` and `1.0 == 1.0.0`.
+//! `version_scheme` is compared before the version, and the `_revision` suffix after it.
+//!
+//! see also:
+//!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/version.rb
+//!   https://github.com/Homebrew/brew/blob/master/Library/Homebrew/pkg_version.rb
+
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+  Alpha,
+  Beta,
+  Pre,
+  Rc,
+  Patch,
+  Post,
+  Numeric,
+  String,
+}
+
+impl Kind {
+  fn is_composite(self) -> bool {
+    !matches!(self, Kind::Numeric | Kind::String)
+  }
+
+  fn is_prerelease(self) -> bool {
+    matches!(self, Kind::Alpha | Kind::Beta | Kind::Pre | Kind::Rc)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+  kind: Kind,
+  value: String,
+  /// the number of a numeric token, or the number after a word like `rc2`
+  rev: u64,
+}
+
+impl Token {
+  fn is_numeric(&self) -> bool {
+    self.kind == Kind::Numeric
+  }
+}
+
+/// length of `prefix` followed by at least `min_digits` digits at the start of `s`
+fn prefix_digits(s: &str, prefix: &str, min_digits: usize) -> Option<usize> {
+  let rest = s.strip_prefix(prefix)?;
+  let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+  (digits >= min_digits).then_some(prefix.len() + digits)
+}
+
+fn digits_of(s: &str) -> u64 {
+  let digits = s.trim_start_matches(|c: char| !c.is_ascii_digit());
+  let digits = &digits[..digits.bytes().take_while(u8::is_ascii_digit).count()];
+  digits.parse().unwrap_or(if digits.is_empty() { 0 } else { u64::MAX })
+}
+
+/// split a lowercase version into tokens, the first alternative matched wins like a regex union
+fn tokenize(s: &str) -> Vec<Token> {
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < s.len() {
+    let rest = &s[i..];
+    let first = rest.as_bytes()[0];
+    let matched = if first.is_ascii_digit() {
+      Some((Kind::Numeric, rest.bytes().take_while(u8::is_ascii_digit).count()))
+    } else if first.is_ascii_alphabetic() || first == b'.' {
+      prefix_digits(rest, "alpha", 0).or_else(|| prefix_digits(rest, "a", 1)).map(|n| (Kind::Alpha, n))
+        .or_else(|| prefix_digits(rest, "beta", 0).or_else(|| prefix_digits(rest, "b", 1)).map(|n| (Kind::Beta, n)))
+        .or_else(|| prefix_digits(rest, "pre", 0).map(|n| (Kind::Pre, n)))
+        .or_else(|| prefix_digits(rest, "rc", 0).map(|n| (Kind::Rc, n)))
+        .or_else(|| prefix_digits(rest, "p", 0).map(|n| (Kind::Patch, n)))
+        .or_else(|| prefix_digits(rest, ".post", 1).map(|n| (Kind::Post, n)))
+        .or_else(|| {
+          let n = rest.bytes().take_while(u8::is_ascii_alphabetic).count();
+          (n > 0).then_some((Kind::String, n))
+        })
+    } else {
+      None
+    };
+    match matched {
+      Some((kind, n)) => {
+        let value = &rest[..n];
+        tokens.push(Token { kind, value: value.to_string(), rev: digits_of(value) });
+        i += n;
+      }
+      None => i += rest.chars().next().map(char::len_utf8).unwrap_or(1),
+    }
+  }
+  tokens
+}
+
+/// compare two tokens, `None` is the padding after the shorter version
+fn cmp_token(a: Option<&Token>, b: Option<&Token>) -> Ordering {
+  match (a, b) {
+    (None, None) => Ordering::Equal,
+    (None, Some(b)) => cmp_token(Some(b), None).reverse(),
+    (Some(a), None) => match a.kind {
+      Kind::Numeric if a.rev == 0 => Ordering::Equal,
+      Kind::Numeric => Ordering::Greater,
+      kind if kind.is_prerelease() => Ordering::Less,
+      _ => Ordering::Greater,
+    },
+    (Some(a), Some(b)) => match (a.kind, b.kind) {
+      (Kind::Numeric, Kind::Numeric) => a.rev.cmp(&b.rev),
+      (Kind::Numeric, _) => Ordering::Greater,
+      (_, Kind::Numeric) => Ordering::Less,
+      (x, y) if x == y && x.is_composite() => a.rev.cmp(&b.rev),
+      (x, y) if x.is_composite() && y.is_composite() => x.cmp(&y),
+      _ => a.value.cmp(&b.value),
+    },
+  }
+}
+
+/// a comparable `version_scheme`, version and revision of a package
+#[derive(Debug, Clone)]
+pub struct Version {
+  pub scheme: usize,
+  pub version: String,
+  pub revision: u32,
+  tokens: Vec<Token>,
+}
+
+impl Version {
+  pub fn new(version: &str, revision: u32, scheme: usize) -> Self {
+    Self {
+      scheme,
+      version: version.to_string(),
+      revision,
+      tokens: tokenize(&version.to_ascii_lowercase()),
+    }
+  }
+
+  /// parse `version_full` like `1.2.3_1`, the scheme is 0
+  pub fn parse(version_full: &str) -> Self {
+    match version_full.rsplit_once('_') {
+      Some((version, revision)) if !version.is_empty() && !revision.is_empty() && revision.bytes().all(|i| i.is_ascii_digit()) =>
+        Self::new(version, revision.parse().unwrap_or(u32::MAX), 0),
+      _ => Self::new(version_full, 0, 0),
+    }
+  }
+
+  pub fn with_scheme(mut self, scheme: usize) -> Self {
+    self.scheme = scheme;
+    self
+  }
+
+  pub fn is_head(&self) -> bool {
+    self.version.starts_with("HEAD")
+  }
+
+  fn cmp_version(&self, other: &Self) -> Ordering {
+    if self.version == other.version {
+      return Ordering::Equal;
+    }
+    match (self.is_head(), other.is_head()) {
+      (true, false) => return Ordering::Greater,
+      (false, true) => return Ordering::Less,
+      _ => {}
+    }
+    let max = self.tokens.len().max(other.tokens.len());
+    let (mut l, mut r) = (0, 0);
+    while l < max {
+      let a = self.tokens.get(l);
+      let b = other.tokens.get(r);
+      let numeric = (a.is_some_and(Token::is_numeric), b.is_some_and(Token::is_numeric));
+      match cmp_token(a, b) {
+        Ordering::Equal => {
+          l += 1;
+          r += 1;
+        }
+        // a number against a word, e.g. `1.0a` and `1.0.1`, only a non-zero number decides
+        _ if numeric == (true, false) => {
+          if cmp_token(a, None) == Ordering::Greater {
+            return Ordering::Greater;
+          }
+          l += 1;
+        }
+        _ if numeric == (false, true) => {
+          if cmp_token(b, None) == Ordering::Greater {
+            return Ordering::Less;
+          }
+          r += 1;
+        }
+        ordering => return ordering,
+      }
+    }
+    Ordering::Equal
+  }
+}
+
+impl std::fmt::Display for Version {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.revision == 0 {
+      write!(f, "{}", self.version)
+    } else {
+      write!(f, "{}_{}", self.version, self.revision)
+    }
+  }
+}
+
+impl Ord for Version {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.scheme.cmp(&other.scheme)
+      .then_with(|| self.cmp_version(other))
+      .then_with(|| self.revision.cmp(&other.revision))
+  }
+}
+
+impl PartialOrd for Version {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for Version {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Version {}
+
+#[test]
+fn test_version() {
+  let v = Version::parse;
+  let ordered = [
+    "0.9", "1.0a1", "1.0alpha2", "1.0b1", "1.0beta10", "1.0pre1", "1.0rc1", "1.0rc2", "1.0",
+    "1.0_1", "1.0p1", "1.0.1", "1.1", "1.10", "2.0", "20240101", "HEAD-abc1234",
+  ];
+  for pair in ordered.windows(2) {
+    assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+    assert!(v(pair[1]) > v(pair[0]), "{} > {}", pair[1], pair[0]);
+  }
+  assert_eq!(v("1.0"), v("1.0.0"));
+  assert_eq!(v("1.0-RC1"), v("1.0rc1"));
+  assert!(v("1.0a") < v("1.0.1"));
+  assert!(v("2.0.0.post1") > v("2.0.0"));
+
+  let p = v("1.2.3_4");
+  assert_eq!((p.version.as_str(), p.revision, p.to_string()), ("1.2.3", 4, "1.2.3_4".to_string()));
+  assert_eq!(v("1_2_3").version, "1_2");
+  assert_eq!(v("_1").version, "_1");
+
+  // version_scheme beats the version, e.g. a formula going back to an older upstream
+  assert!(v("1.0").with_scheme(1) > v("2.0"));
+  assert!(v("2.0").with_scheme(1) > v("1.0").with_scheme(1));
+}
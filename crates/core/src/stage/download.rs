@@ -1,5 +1,6 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
 
+use futures::StreamExt as _;
 use indicatif::ProgressStyle;
 
 use crate::{error::{ErrorExt as _, Result}, io::{fetch::{fetch_remote, FetchReq, MirrorLists}, FetchState}, package::package::{PackageCache, PackageUrl, PkgBuild}, ui::{bar::{FeedBar, FeedMulti}, event::{BytesEvent, DetailEvent}, EventListener}};
@@ -58,63 +59,114 @@ pub async fn step<P: AsRef<Path>>(mirrors: &MirrorLists, pkg: &PkgBuild, cache_p
   Ok(target)
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(cache_path = %cache_path.as_ref().display(), mirrors.len = mirrors.lists.len()))]
-pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = (&'a PkgBuild, &'a PackageUrl)>>(
-  mirrors: &MirrorLists,
-  cache_path: P,
+pub struct Args<'a> {
+  pub mirrors: &'a MirrorLists,
+  pub cache_path: &'a Path,
+  pub jobs: usize,
+}
+impl<'a> Args<'a> {
+  pub fn new<P: AsRef<Path> + 'a>(mirrors: &'a MirrorLists, cache_path: &'a P) -> Self {
+    Self { mirrors, cache_path: cache_path.as_ref(), jobs: 1 }
+  }
+  pub fn jobs(self, jobs: usize) -> Self {
+    Self { jobs: jobs.max(1), ..self }
+  }
+}
+
+/// download at most `args.jobs` packages at the same time.
+/// once a task failed, pending tasks are skipped while running tasks are left to finish,
+/// so no half written file is left besides the `.part` of the failed one, which could be resumed next time.
+#[tracing::instrument(level = "debug", skip_all, fields(cache_path = %args.cache_path.display(), mirrors.len = args.mirrors.lists.len(), jobs = args.jobs))]
+pub async fn exec<'a, I: IntoIterator<Item = (&'a PkgBuild, &'a PackageUrl)>>(
+  args: Args<'_>,
   pkgs: I,
   tracker: impl EventListener<DetailEvent<u64, u64>>
 ) -> Result<Vec<PackageCache>> {
   use DetailEvent::*;
   use crate::ui::event::Event::*;
-  let mut result = Vec::new();
   let pkgs = pkgs.into_iter().collect::<Vec<_>>();
-  let mut total_size = 0;
-  let mut downloaded_size = 0;
-  for &(_, url) in &pkgs {
-    total_size += url.pkg_size;
-  }
-  tracker.on_event(Overall(Init { max: total_size }));
+  // the overall length is only known when every package size is known
+  let total_size = pkgs.iter().map(|(_, url)| url.pkg_size).sum::<Option<u64>>();
+  let progress = pkgs.iter().map(|_| AtomicU64::new(0)).collect::<Vec<_>>();
+  let aborted = AtomicBool::new(false);
+  tracker.on_event(Overall(match total_size {
+    Some(max) => Init { max },
+    None => Unbounded { current: 0 },
+  }));
+  let tracker = &tracker;
+  let progress = &progress;
+  let aborted = &aborted;
+  let mut tasks = Vec::new();
   for (i, (pkg, url)) in pkgs.into_iter().enumerate() {
-    tracker.on_event(Overall(Message { name: format!("now [{}] {}", i, pkg.name) }));
-    tracker.on_event(Item(i, Init { max: url.pkg_size }));
-    tracker.on_event(Item(i, Message { name: pkg.filename.clone() }));
-    let value = step(mirrors, pkg, &cache_path, |e: FetchState| {
-      tracker.on_event(Item(i, Progress { current: e.current, max: Some(e.max) }));
-      tracker.on_event(Overall(Progress { current: downloaded_size + e.current, max: None }));
-    }).await?;
-    downloaded_size += url.pkg_size;
-    tracker.on_event(Item(i, Finish));
-    let cache_size = std::fs::metadata(&value).when(("metadata", &value))?.len();
-    if cache_size != url.pkg_size {
-      warn!(cache_size, url.pkg_size, "size not match");
+    tasks.push(async move {
+      if aborted.load(Ordering::Acquire) {
+        return Ok(None);
+      }
+      tracker.on_event(Overall(Message { name: format!("now [{}] {}", i, pkg.name) }));
+      tracker.on_event(Item(i, match url.pkg_size {
+        Some(max) => Init { max },
+        None => Unbounded { current: 0 },
+      }));
+      tracker.on_event(Item(i, Message { name: pkg.filename.clone() }));
+      let value = step(args.mirrors, pkg, args.cache_path, |e: FetchState| {
+        progress[i].store(e.current, Ordering::Release);
+        tracker.on_event(Item(i, e.into()));
+        tracker.on_event(Overall(Progress { current: progress.iter().map(|p| p.load(Ordering::Acquire)).sum(), max: None }));
+      }).await;
+      tracker.on_event(Item(i, Finish));
+      let value = value?;
+      let cache_size = std::fs::metadata(&value).when(("metadata", &value))?.len();
+      progress[i].store(cache_size, Ordering::Release);
+      if url.pkg_size.is_some_and(|i| i != cache_size) {
+        warn!(cache_size, ?url.pkg_size, "size not match");
+      }
+      Ok(Some((i, PackageCache {
+        name: pkg.name.clone(),
+        cache_pkg: value,
+        cache_size,
+        cellar: pkg.cellar.clone(),
+      })))
+    });
+  }
+  let mut tasks = futures::stream::iter(tasks).buffer_unordered(args.jobs);
+
+  let mut result = Vec::new();
+  let mut error = None;
+  while let Some(value) = tasks.next().await {
+    match value {
+      Ok(Some(value)) => result.push(value),
+      Ok(None) => {},
+      Err(e) => {
+        warn!(error=%e, "download failed, skip pending tasks");
+        aborted.store(true, Ordering::Release);
+        error.get_or_insert(e);
+      }
     }
-    result.push(PackageCache {
-      name: pkg.name.clone(),
-      cache_pkg: value,
-      cache_size,
-    })
   }
   tracker.on_event(DetailEvent::Overall(BytesEvent::Finish));
-  Ok(result)
+  if let Some(e) = error {
+    return Err(e);
+  }
+  result.sort_by_key(|(i, _)| *i);
+  Ok(result.into_iter().map(|(_, value)| value).collect())
 }
 
 #[tokio::test]
 pub async fn test_download() {
   use crate::tests::*;
   let cache_dir = CACHE_PATH;
-  let arch = ARCH;
+  let arch = &get_arch();
   std::fs::create_dir_all(cache_dir).ok();
   let active_pb = crate::tests::init_logger(Some("warn"));
   let mirrors = get_mirrors();
   let query = ["wget"];
-  let formulas = crate::io::read::read_formulas(crate::tests::FORMULA_FILE).unwrap();
-  let resolved = super::resolve::exec(&formulas, query, ()).await.unwrap().packages;
+  let index = crate::tests::get_index();
+  let resolved = super::resolve::exec(&index, query, ()).await.unwrap().packages;
   let urls = super::probe::exec(super::probe::Args::new(arch, &mirrors).cache(&cache_dir, false), &resolved, ()).await.unwrap();
   warn!("start downloading");
   let result = crate::ui::with_progess_multibar(active_pb, None, |tracker| async {
     let tmp = urls.iter().map(|i| (&i.pkg, &i.url)).collect::<Vec<_>>();
-    exec(&mirrors, cache_dir, tmp, tracker).await
+    exec(Args::new(&mirrors, &cache_dir).jobs(4), tmp, tracker).await
   }, ()).await.unwrap();
   info!(len=result.len());
   assert_eq!(result.len(), resolved.len());
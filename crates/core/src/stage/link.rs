@@ -1,6 +1,8 @@
-use std::path::Path;
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, sync::LazyLock};
 
-use crate::{error::{ErrorExt, Result}, package::package::{PackageInstalled, PackageLinked}, ui::{event::ItemEvent, EventListener}};
+use regex::Regex;
+
+use crate::{error::{ErrorExt, IoErrorExt, Result}, io::untar::list_gz, package::package::{PackageInstalled, PackageLinked}, ui::{event::ItemEvent, EventListener}};
 
 pub fn symlink_dir<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q, force: bool) -> std::io::Result<()> {
   let link = link.as_ref();
@@ -61,87 +63,311 @@ pub fn symlink_file<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q, force:
 ///   mime-info pixmaps sounds postgresql
 /// ].freeze
 /// ```
-///
-/// strategies
-/// - `:skip_dir`: direct children with type of file should be symlink
-/// - `:mkpath`: every file recursively should be symlink
-/// - `:link`: direct files and dirs should be symlink, this is the default value
-///
-pub fn shared_dir() -> Vec<String> {
-  vec![
-    "bin", // :skip_dir
-    "sbin", // :skip_dir
-    "etc", // :mkpath
-    "include", // :link
-    // include:mkpath => %r{^postgresql@\d+/}
-    "lib/pkgconfig", "lib/cmake", "lib/dtrace", // :mkpath
-    "lib", // :link
-    // lib:skip_file => "charset.alias"
-    // lib:mkpath => /^gdk-pixbuf/, "ghc", /^gio/, /^lua/, /^mecab/, /^node/, /^ocaml/, /^perl5/, "php", %r{^postgresql@\d+/}, /^python[23]\.\d+/, /^R/, /^ruby/
-    "share",
-    // share:info => INFOFILE_RX
-    // share:skip_file => "locale/locale.alias", %r{^icons/.*/icon-theme\.cache$}
-    // share:mkpath => LOCALEDIR_RX, %r{^icons/}, /^zsh/, /^fish/, %r{^lua/}, %r{^guile/}, %r{^postgresql@\d+/}, *SHARE_PATHS
-    "Frameworks", // :link
-    // Frameworks:mkpath => %r{[^/]*\.framework(/Versions)?$}
-  ].iter().map(|i| i.to_string()).collect()
-}
+const LOCALEDIR_RX: &str = r"(locale|man)/([a-z]{2}|C|POSIX)(_[A-Z]{2})?(\.[a-zA-Z\-0-9]+(@.+)?)?";
+const INFOFILE_RX: &str = r"info/([^.].*?\.info|dir)$";
+const SHARE_PATHS: &[&str] = &[
+  "aclocal", "doc", "info", "java", "locale", "man",
+  "man/man1", "man/man2", "man/man3", "man/man4",
+  "man/man5", "man/man6", "man/man7", "man/man8",
+  "man/cat1", "man/cat2", "man/cat3", "man/cat4",
+  "man/cat5", "man/cat6", "man/cat7", "man/cat8",
+  "applications", "gnome", "gnome/help", "icons",
+  "mime-info", "pixmaps", "sounds", "postgresql",
+];
 
+/// what to do with an entry in a linked dir, the result of `link_dir` blocks in `Keg#link`
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Strategy {
-  File, // :skip_dir
-  Recursively, // :mkpath
-  Child, // :link
+  /// `:link`, symlink the entry, a dir is linked as a whole
+  Link,
+  /// `:mkpath`, create the dir in prefix and link its children
+  Mkpath,
+  /// `:skip_dir`, files are linked but dirs are skipped
+  SkipDir,
+  /// `:skip_file`, the file is not linked
+  SkipFile,
+  /// `:info`, info pages are linked except `dir`
+  Info,
 }
 
-pub fn list_dir<P: AsRef<Path>, Q: AsRef<str>>(base: P, path: Q, stretegy: Strategy) -> Result<Vec<String>> {
-  let base = base.as_ref();
-  let path = path.as_ref();
-  debug!(path=%path, "list_dir");
-  if !base.join(path).exists() { return Ok(vec![]) }
-  let mut result = Vec::new();
-  for i in std::fs::read_dir(base.join(path)).when(("list_dir.read_dir", &Path::new(path)))? {
-    let i = i.when(("list_dir.read_dir_entry", &Path::new(path)))?;
-    let file_type = i.file_type().when(("list_dir.file_type", &Path::new(path)))?;
-    let name = i.file_name().to_string_lossy().to_string();
-    let relative_name = format!("{}/{}", path, name);
-    if file_type.is_dir() {
-      if stretegy == Strategy::Recursively {
-        result.extend(list_dir(base, relative_name, stretegy)?);
-      } else if stretegy == Strategy::Child {
-        result.push(relative_name)
+/// strategies of entries in `dir` of a keg, matched against the path relative to `dir`
+pub struct LinkRule {
+  pub dir: &'static str,
+  pub rules: Vec<(Regex, Strategy)>,
+  pub default: Strategy,
+}
+
+impl LinkRule {
+  fn new(dir: &'static str, rules: &[(&str, Strategy)], default: Strategy) -> Self {
+    let rules = rules.iter().map(|(pattern, strategy)| (Regex::new(pattern).expect("link rule"), *strategy)).collect();
+    Self { dir, rules, default }
+  }
+
+  pub fn strategy(&self, relative: &str) -> Strategy {
+    self.rules.iter()
+      .find(|(pattern, _)| pattern.is_match(relative))
+      .map(|(_, strategy)| *strategy)
+      .unwrap_or(self.default)
+  }
+
+  /// walk `relative` in `keg/dir` like `Keg#link_dir`, push paths to link relative to keg.
+  /// dirs which are real dirs in `prefix` already are walked into rather than linked.
+  fn walk<K: KegTree + ?Sized>(&self, keg: &K, prefix: Option<&Path>, relative: &str, result: &mut Vec<String>) -> Result<()> {
+    let dir = if relative.is_empty() { self.dir.to_string() } else { format!("{}/{}", self.dir, relative) };
+    for (name, is_dir) in keg.entries(&dir)? {
+      let relative = if relative.is_empty() { name.clone() } else { format!("{}/{}", relative, name) };
+      let target = format!("{}/{}", self.dir, relative);
+      if !is_dir {
+        if name == ".DS_Store" {
+          continue;
+        }
+        // python rewrites cached objects, which would be in the way of next link
+        if (name.ends_with(".pyc") || name.ends_with(".pyo")) && relative.contains("/site-packages/") {
+          continue;
+        }
+        match self.strategy(&relative) {
+          Strategy::SkipFile => continue,
+          Strategy::Info if name == "dir" => continue,
+          _ => result.push(target),
+        }
+      } else {
+        if prefix.is_some_and(|prefix| is_real_dir(&prefix.join(&target))) {
+          self.walk(keg, prefix, &relative, result)?;
+          continue;
+        }
+        // apps are not put in path
+        if name.ends_with(".app") {
+          continue;
+        }
+        match self.strategy(&relative) {
+          Strategy::SkipDir => continue,
+          Strategy::Mkpath => self.walk(keg, prefix, &relative, result)?,
+          _ => result.push(target),
+        }
       }
-    } else if stretegy != Strategy::Recursively {
-      result.push(relative_name)
     }
+    Ok(())
+  }
+}
+
+/// a keg to be linked, unpacked in cellar or listed from a bottle
+pub trait KegTree {
+  /// names of entries in `dir` relative to keg, sorted, with whether it is a dir, symlinks are not dirs
+  fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>>;
+}
+
+impl KegTree for Path {
+  fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>> {
+    let dir = self.join(dir);
+    if !is_real_dir(&dir) {
+      return Ok(Vec::new());
+    }
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(&dir).when(("read_dir", &dir))? {
+      let entry = entry.when(("read_dir", &dir))?;
+      let file_type = entry.file_type().when(("file_type", &entry.path()))?;
+      result.push((entry.file_name().to_string_lossy().to_string(), file_type.is_dir()));
+    }
+    result.sort();
+    Ok(result)
+  }
+}
+
+/// entries of a bottle, keyed by paths relative to the keg, so the link plan is known before unpack
+#[derive(Debug, Clone, Default)]
+pub struct KegListing(BTreeMap<String, bool>);
+
+impl KegListing {
+  /// paths in bottles are `<name>/<version>/...`
+  pub fn new<I: IntoIterator<Item = (PathBuf, bool)>>(entries: I) -> Self {
+    let mut result = BTreeMap::new();
+    for (path, is_dir) in entries {
+      let components = path.components().skip(2).map(|i| i.as_os_str().to_string_lossy().to_string()).collect::<Vec<_>>();
+      if components.is_empty() {
+        continue;
+      }
+      // parents may be implied
+      for i in 1..components.len() {
+        result.insert(components[..i].join("/"), true);
+      }
+      result.insert(components.join("/"), is_dir);
+    }
+    Self(result)
+  }
+
+  pub async fn from_bottle<P: AsRef<Path>>(path: P) -> Result<Self> {
+    Ok(Self::new(list_gz(path).await?))
+  }
+}
+
+impl KegTree for KegListing {
+  fn entries(&self, dir: &str) -> Result<Vec<(String, bool)>> {
+    let prefix = format!("{}/", dir);
+    Ok(self.0.range(prefix.clone()..)
+      .take_while(|(path, _)| path.starts_with(&prefix))
+      .filter_map(|(path, is_dir)| {
+        let name = &path[prefix.len()..];
+        (!name.contains('/')).then(|| (name.to_string(), *is_dir))
+      })
+      .collect())
+  }
+}
+
+fn is_real_dir(path: &Path) -> bool {
+  path.symlink_metadata().is_ok_and(|i| i.is_dir())
+}
+
+fn exact(paths: &[&str]) -> String {
+  format!("^({})$", paths.iter().map(|i| regex::escape(i)).collect::<Vec<_>>().join("|"))
+}
+
+/// the rules of `Keg#link` in the order homebrew links them
+pub static LINK_RULES: LazyLock<Vec<LinkRule>> = LazyLock::new(|| {
+  use Strategy::*;
+  vec![
+    LinkRule::new("etc", &[], Mkpath),
+    LinkRule::new("bin", &[], SkipDir),
+    LinkRule::new("sbin", &[], SkipDir),
+    LinkRule::new("include", &[(r"^postgresql@\d+", Mkpath)], Link),
+    LinkRule::new("share", &[
+      (INFOFILE_RX, Info),
+      (r"^locale/locale\.alias$", SkipFile),
+      (r"^icons/.*/icon-theme\.cache$", SkipFile),
+      (LOCALEDIR_RX, Mkpath),
+      (r"^icons/", Mkpath),
+      (r"^zsh", Mkpath),
+      (r"^fish", Mkpath),
+      (r"^lua/", Mkpath),
+      (r"^guile/", Mkpath),
+      (r"^postgresql@\d+", Mkpath),
+      (&exact(SHARE_PATHS), Mkpath),
+    ], Link),
+    LinkRule::new("lib", &[
+      (r"^charset\.alias$", SkipFile),
+      // pkg-config, cmake and language folders are real dirs shared by kegs
+      (&exact(&["pkgconfig", "cmake", "dtrace", "ghc", "php"]), Mkpath),
+      (r"^gdk-pixbuf", Mkpath),
+      (r"^gio", Mkpath),
+      (r"^lua", Mkpath),
+      (r"^mecab", Mkpath),
+      (r"^node", Mkpath),
+      (r"^ocaml", Mkpath),
+      (r"^perl5", Mkpath),
+      (r"^postgresql@\d+", Mkpath),
+      (r"^python[23]\.\d+", Mkpath),
+      (r"^R", Mkpath),
+      (r"^ruby", Mkpath),
+    ], Link),
+    // Foo.framework and Foo.framework/Versions are shared, so versions of several kegs could be linked
+    LinkRule::new("Frameworks", &[(r"[^/]*\.framework(/Versions)?$", Mkpath)], Link),
+  ]
+});
+
+/// paths relative to `keg` to be linked into `prefix`, see `LINK_RULES`
+pub fn collect_link_targets<K: KegTree + ?Sized>(keg: &K, prefix: Option<&Path>) -> Result<Vec<String>> {
+  let mut result = Vec::new();
+  for rule in LINK_RULES.iter() {
+    rule.walk(keg, prefix, "", &mut result)?;
   }
   Ok(result)
 }
 
-fn collect_link_targets(opt_path: &Path) -> Result<Vec<String>> {
-  let mut to_link = Vec::new();
-  to_link.extend(list_dir(opt_path, "bin", Strategy::File)?);
-  to_link.extend(list_dir(opt_path, "lib/pkgconfig", Strategy::Recursively)?);
-  to_link.extend(list_dir(opt_path, "lib/cmake", Strategy::Recursively)?);
-  to_link.extend(list_dir(opt_path, "lib/dtrace", Strategy::Recursively)?);
-  to_link.extend(list_dir(opt_path, "lib", Strategy::Child)?);
-  to_link = to_link.into_iter().filter(|i| !(i == "lib/pkgconfig" || i == "lib/cmake" || i == "lib/dtrace")).collect();
-  to_link.extend(list_dir(opt_path, "include", Strategy::Child)?);
-  Ok(to_link)
+/// a path in prefix which `name` would link over
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+  pub name: String,
+  pub path: String,
+  /// the package owning `path` in db, none for files not linked by us
+  pub owner: Option<String>,
+}
+
+/// `Formula#link_overwrite?`, paths are relative to prefix and could be a dir or with `*`
+pub fn link_overwrite(patterns: &[String], path: &str) -> bool {
+  patterns.iter().any(|pattern| {
+    pattern == path
+      || path.starts_with(&format!("{}/", pattern.trim_end_matches('/')))
+      || Regex::new(&format!("^{}$", regex::escape(pattern).replace(r"\*", ".*?"))).is_ok_and(|i| i.is_match(path))
+  })
+}
+
+/// paths in `targets` of `name` already taken in `prefix`, `owners` maps paths to packages owning them.
+/// paths of other packages are always conflicts, while paths owned by nobody could be taken with `link_overwrite`.
+pub fn conflicts(prefix: &Path, name: &str, targets: &[String], owners: &HashMap<String, String>, link_overwrite_patterns: &[String]) -> Vec<Conflict> {
+  targets.iter()
+    .filter(|path| prefix.join(path).symlink_metadata().is_ok())
+    .filter_map(|path| {
+      let owner = match owners.get(path) {
+        Some(owner) if owner == name => return None,
+        Some(owner) => Some(owner.clone()),
+        None if link_overwrite(link_overwrite_patterns, path) => return None,
+        None => None,
+      };
+      Some(Conflict { name: name.to_string(), path: path.clone(), owner })
+    })
+    .collect()
+}
+
+/// remove empty directories from the parent of `path` up to `prefix`
+pub fn cleanup_empty_parents(prefix: &Path, path: &Path) -> Result<()> {
+  let mut current = path.parent();
+  while let Some(dir) = current {
+    if dir == prefix {
+      break;
+    }
+    match std::fs::remove_dir(dir) {
+      Ok(()) => {
+        current = dir.parent();
+      }
+      Err(error) if matches!(error.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty) => break,
+      Err(error) => return Err(error).when(("remove_dir", dir)),
+    }
+  }
+  Ok(())
 }
 
-pub fn owned_files(name: &str, dest: &Path) -> Result<Vec<String>> {
-  let mut files = vec![format!("opt/{}", name)];
-  files.extend(collect_link_targets(dest)?);
+/// remove the symlinks of `files` in prefix and the directories left empty, other files are left alone
+pub fn unlink_files(prefix: &Path, files: &[String]) -> Result<()> {
+  for file in files {
+    let path = prefix.join(file);
+    let Some(metadata) = std::fs::symlink_metadata(&path).ok_not_found().when(("symlink_metadata", &path))? else {
+      continue;
+    };
+    if metadata.file_type().is_symlink() {
+      info!(file, "unlinking");
+      symlink::remove_symlink_auto(&path).when(("remove_symlink", &path))?;
+      cleanup_empty_parents(prefix, &path)?;
+    } else {
+      warn!(path=%path.display(), "skip unlink non-symlink path");
+    }
+  }
+  Ok(())
+}
+
+/// where `file` owned by the keg at `dest` should point to, `opt/<name>` points to the keg itself
+pub fn link_source(name: &str, dest: &Path, file: &str) -> PathBuf {
+  match file == opt_file(name) {
+    true => dest.to_owned(),
+    false => dest.join(file),
+  }
+}
+
+/// `opt/<name>`, the only link of keg-only formulae
+pub fn opt_file(name: &str) -> String {
+  format!("opt/{}", name)
+}
+
+pub fn owned_files(prefix: &Path, name: &str, dest: &Path) -> Result<Vec<String>> {
+  let mut files = vec![opt_file(name)];
+  files.extend(collect_link_targets(dest, Some(prefix))?);
   Ok(files)
 }
 
 #[tracing::instrument(level = "debug", skip(prefix, opt_path, tracker), fields(prefix = %prefix.as_ref().display(), opt_path = %opt_path.as_ref().display()))]
 pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(prefix: P, opt_path: Q, tracker: impl EventListener<ItemEvent>) -> Result<()> {
-  // TODO: generate list of link and check conflict before unpack
+  // conflicts should be checked with `conflicts` before, symlinks in the way are replaced
   let opt_path = opt_path.as_ref();
   let prefix = prefix.as_ref().canonicalize().when(("step.prefix", &prefix.as_ref()))?;
-  let to_link = collect_link_targets(opt_path)?;
+  let to_link = collect_link_targets(opt_path, Some(&prefix))?;
   tracker.on_event(ItemEvent::Init { max: to_link.len() });
   for (i, file) in to_link.into_iter().enumerate() {
     info!(file, "linking");
@@ -162,6 +388,19 @@ pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(prefix: P, opt_path: Q, tracke
   Ok(())
 }
 
+/// link `opt/<name>` to the keg, which is all linked for keg-only formulae
+pub fn link_opt<P: AsRef<Path>>(prefix: P, pkg: &PackageInstalled) -> Result<PackageLinked> {
+  let opt_dir = prefix.as_ref().join("opt");
+  std::fs::create_dir_all(&opt_dir).when(("create_dir_all", &opt_dir))?;
+  symlink_dir(&pkg.dest, opt_dir.join(&pkg.name), true).when(("symlink_dir", &pkg.dest))?;
+  Ok(PackageLinked {
+    name: pkg.name.clone(),
+    dest: pkg.dest.clone(),
+    version: pkg.version.clone(),
+    files: vec![opt_file(&pkg.name)],
+  })
+}
+
 pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = &'a PackageInstalled>>(
   prefix: P,
   pkgs: I,
@@ -173,7 +412,7 @@ pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = &'a PackageInstalle
   let mut result = Vec::new();
   for (i, pkg) in pkgs.into_iter().enumerate() {
     tracker.on_event(ItemEvent::Message { name: format!("linking {}", pkg.name) });
-    let files = owned_files(&pkg.name, &pkg.dest)?;
+    let files = owned_files(prefix, &pkg.name, &pkg.dest)?;
     symlink_dir(&pkg.dest, opt_dir.join(&pkg.name), true).ok();
     tracker.on_event(ItemEvent::Progress { current: i, max: None });
     step(prefix, &pkg.dest, ()).await?;
@@ -189,19 +428,38 @@ pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = &'a PackageInstalle
   Ok(result)
 }
 
-pub fn guess_installed(path: &Path) -> Option<PackageInstalled> {
-  let mut versions = std::fs::read_dir(path).ok()?
+/// every version in the formula dir `path` of a cellar, oldest first, the staging dir is skipped
+pub fn installed_versions(path: &Path) -> Vec<PackageInstalled> {
+  let Some(name) = path.file_name().map(|i| i.to_string_lossy().to_string()) else {
+    return Vec::new();
+  };
+  let Ok(entries) = std::fs::read_dir(path) else {
+    return Vec::new();
+  };
+  let mut versions = entries
     .filter_map(|v| v.ok())
-    .filter_map(|v| Some((v.metadata().ok()?.created().ok()?, v)))
+    .filter(|v| v.file_name() != super::unpack::STAGING_DIR && v.file_type().is_ok_and(|i| i.is_dir()))
+    .filter_map(|v| Some((v.metadata().ok()?.created().ok()?, v.file_name().into_string().ok()?)))
     .collect::<Vec<_>>();
-  versions.sort_by_key(|i| i.0);
-  let version = versions.last()?.1.file_name().into_string().ok()?;
-  Some(PackageInstalled {
-    name: path.file_name()?.to_string_lossy().to_string(),
+  versions.sort();
+  versions.into_iter().map(|(_, version)| PackageInstalled {
+    name: name.clone(),
     dest: path.join(&version),
     version,
     reloc: Default::default(),
-  })
+    unrelocated: Default::default(),
+  }).collect()
+}
+
+/// the version `opt/<name>` in `prefix` points to
+pub fn linked_version(prefix: &Path, name: &str) -> Option<String> {
+  let target = std::fs::read_link(prefix.join(opt_file(name))).ok()?;
+  target.file_name()?.to_str().map(str::to_string)
+}
+
+/// the last created version in the formula dir `path`
+pub fn guess_installed(path: &Path) -> Option<PackageInstalled> {
+  installed_versions(path).pop()
 }
 
 pub fn list_installed(cellar_dir: &Path) -> Result<Vec<PackageInstalled>> {
@@ -236,3 +494,77 @@ async fn test_link() {
   let result = exec(PREFIX_PATH, &pkgs, ()).await.unwrap();
   assert_eq!(result.len(), pkgs.len())
 }
+
+#[test]
+fn test_link_rules() {
+  let root = std::env::temp_dir().join(format!("pacbrew-link-test-{}", std::process::id()));
+  let keg = root.join("Cellar/foo/1.0");
+  let prefix = root.join("prefix");
+  std::fs::remove_dir_all(&root).ok();
+  for file in [
+    "bin/foo", "bin/libexec/foo-helper", "sbin/food", "etc/foo/foo.conf",
+    "include/foo.h", "include/foo/bar.h", "include/postgresql@16/server/pg_config.h",
+    "lib/libfoo.dylib", "lib/charset.alias", "lib/pkgconfig/foo.pc", "lib/foo/plugin.so",
+    "lib/python3.12/site-packages/foo/__init__.py", "lib/python3.12/site-packages/foo/__init__.pyc",
+    "share/man/man1/foo.1", "share/locale/de/LC_MESSAGES/foo.mo", "share/locale/locale.alias",
+    "share/info/foo.info", "share/info/dir", "share/zsh/site-functions/_foo", "share/foo/data",
+    "share/icons/hicolor/icon-theme.cache", "share/icons/hicolor/48x48/foo.png", "share/Foo.app/Contents/Info.plist",
+    "share/.DS_Store", "Frameworks/Foo.framework/Versions/A/Foo",
+  ] {
+    let path = keg.join(file);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, file).unwrap();
+  }
+  symlink::symlink_file("Versions/A/Foo", keg.join("Frameworks/Foo.framework/Foo")).unwrap();
+
+  let expected = [
+    "etc/foo/foo.conf", "bin/foo", "sbin/food", "include/foo", "include/foo.h", "include/postgresql@16/server/pg_config.h",
+    "share/foo", "share/icons/hicolor/48x48/foo.png", "share/info/foo.info",
+    "share/locale/de/LC_MESSAGES/foo.mo", "share/man/man1/foo.1", "share/zsh/site-functions/_foo",
+    "lib/foo", "lib/libfoo.dylib", "lib/pkgconfig/foo.pc", "lib/python3.12/site-packages/foo/__init__.py",
+    "Frameworks/Foo.framework/Foo", "Frameworks/Foo.framework/Versions/A",
+  ];
+  assert_eq!(collect_link_targets(keg.as_path(), None).unwrap(), expected);
+
+  // a real dir in prefix is walked into instead of being replaced by a link
+  std::fs::create_dir_all(prefix.join("share/foo")).unwrap();
+  let targets = collect_link_targets(keg.as_path(), Some(&prefix)).unwrap();
+  assert!(targets.contains(&"share/foo/data".to_string()));
+  assert!(!targets.contains(&"share/foo".to_string()));
+
+  // the same plan from the bottle listing
+  let listing = KegListing::new(expected.iter().chain(&["share/locale/locale.alias", "lib/charset.alias", "bin/libexec/foo-helper"])
+    .map(|i| (Path::new("foo/1.0").join(i), false))
+    .chain([(PathBuf::from("foo/1.0/include/foo/"), true), (PathBuf::from("foo/1.0/share/foo/"), true)]));
+  let mut listed = collect_link_targets(&listing, None).unwrap();
+  listed.sort();
+  let mut expected = expected.to_vec();
+  expected.sort();
+  assert_eq!(listed, expected);
+
+  std::fs::remove_dir_all(&root).ok();
+}
+
+#[test]
+fn test_conflicts() {
+  let prefix = std::env::temp_dir().join(format!("pacbrew-conflict-test-{}", std::process::id()));
+  std::fs::remove_dir_all(&prefix).ok();
+  for file in ["bin/foo", "bin/bar", "bin/baz", "lib/python3.12/site-packages/six.py"] {
+    std::fs::create_dir_all(prefix.join(file).parent().unwrap()).unwrap();
+    std::fs::write(prefix.join(file), file).unwrap();
+  }
+  let owners = HashMap::from([
+    ("bin/foo".to_string(), "foo".to_string()),
+    ("bin/bar".to_string(), "bar".to_string()),
+  ]);
+  let targets = ["bin/foo", "bin/bar", "bin/baz", "bin/qux", "lib/python3.12/site-packages/six.py"].map(String::from);
+  let overwrite = ["bin/bar", "lib/python3.*/site-packages/six.py"].map(String::from);
+  assert_eq!(conflicts(&prefix, "foo", &targets, &owners, &overwrite), vec![
+    Conflict { name: "foo".to_string(), path: "bin/bar".to_string(), owner: Some("bar".to_string()) },
+    Conflict { name: "foo".to_string(), path: "bin/baz".to_string(), owner: None },
+  ]);
+  assert!(link_overwrite(&["lib/python3.12".to_string()], "lib/python3.12/site-packages/six.py"));
+  assert!(!link_overwrite(&["bin/ba".to_string()], "bin/bar"));
+
+  std::fs::remove_dir_all(&prefix).ok();
+}
@@ -1,34 +1,56 @@
-use std::path::Path;
+use std::{path::Path, sync::atomic::{AtomicUsize, Ordering}, time::Instant};
 
+use futures::StreamExt as _;
 use reqwest::header;
 
-use crate::{error::{Error, ErrorExt, Result}, io::fetch::{FetchReq, MirrorLists}, package::package::{PackageUrl, PackageVersion, PkgBuild}, ui::{event::ItemEvent, EventListener}};
+use crate::{error::{Error, ErrorExt, Result}, io::{fetch::{FetchReq, MirrorLists, NetworkPolicy}, http::with_timeout, rank::{ReqKind, Sample}}, package::{arch::ArchPolicy, mirror::MirrorServer, package::{PackageUrl, PackageVersion, PkgBuild}}, ui::{event::ItemEvent, EventListener}};
 
+/// size of `pkg` on `mirror`, OCI mirrors tell it in the manifest, and others in `Content-Length` of a HEAD request.
+async fn size(mirror: &MirrorServer, pkg: &PkgBuild, url: &str, policy: &NetworkPolicy) -> Result<Option<u64>> {
+  if let Some(registry) = mirror.registry() {
+    let layer = with_timeout(Some(policy.read_timeout), "manifest", url, registry.bottle_layer(pkg)).await??;
+    if layer.sha256() != Some(&pkg.sha256) {
+      warn!(name=%pkg.name, digest=%layer.digest, sha256=%pkg.sha256, "bottle digest in manifest mismatch");
+    }
+    return Ok(Some(layer.size));
+  }
+  let resp = with_timeout(Some(policy.read_timeout), "head", url, mirror.send(pkg, |client| client.head(url))).await??;
+  let resp = resp.error_for_status().when(("head", url))?;
+  let Some(size) = resp.headers().get(header::CONTENT_LENGTH) else {
+    debug!(url, "no content length");
+    return Ok(None);
+  };
+  let size = size
+    .to_str().map_err(Error::parse_response("head", url, "CONTENT_LENGTH.to_str"))?
+    .parse::<u64>().map_err(Error::parse_response("head", url, "CONTENT_LENGTH.parse"))?;
+  Ok(Some(size))
+}
+
+/// find the first mirror which serves `pkg`.
+/// the size is only advisory, so the download proceeds without it if no mirror responds,
+/// and the download would retry mirrors on its own.
 #[tracing::instrument(level = "trace", skip_all, fields(mirrors.len = mirrors.len(), package = %pkg.name, arch = %pkg.arch))]
 pub async fn step(mirrors: &MirrorLists, pkg: &PkgBuild) -> Result<PackageUrl> {
   trace!(?pkg);
   let req = FetchReq::Package(pkg.clone());
-  for (client, url) in mirrors.url_iter(req.clone()) {
-    let result: Result<_> = async move {
-      let resp = client.head(&url).send().await.when(("head", &url))?;
-      let size = resp.headers()
-        .get(header::CONTENT_LENGTH).ok_or_else(|| Error::parse_response_error("head", &url, "CONTENT_LENGTH"))?
-        .to_str().map_err(Error::parse_response("head", &url, "CONTENT_LENGTH.to_str"))?
-        .parse::<u64>().map_err(Error::parse_response("head", &url, "CONTENT_LENGTH.parse"))?;
-      Ok(PackageUrl {
-        name: pkg.name.clone(),
-        pkg_url: url.to_string(),
-        pkg_size: size,
-      })
-    }.await;
-    match result {
-      Ok(url) => return Ok(url),
-      _ => {
-        warn!(?result, "failed to head");
+  let mut fallback = None;
+  for (mirror, url) in mirrors.url_iter(req.clone()) {
+    let start = Instant::now();
+    match size(mirror, pkg, &url, &mirrors.policy).await {
+      Ok(pkg_size) => {
+        mirrors.rank.record(mirror, ReqKind::Package, &Sample::Success { latency: start.elapsed(), bytes: 0, elapsed: start.elapsed() });
+        return Ok(PackageUrl { name: pkg.name.clone(), pkg_url: url, pkg_size })
+      },
+      Err(e) => {
+        warn!(error=%e, url, "failed to probe");
+        mirrors.rank.record(mirror, ReqKind::Package, &Sample::Failure);
+        fallback.get_or_insert(url);
       }
     }
   }
-  Err(Error::MirrorFailed(req))
+  let pkg_url = fallback.ok_or(Error::MirrorFailed(req))?;
+  info!(name=%pkg.name, "size unknown, download without it");
+  Ok(PackageUrl { name: pkg.name.clone(), pkg_url, pkg_size: None })
 }
 
 pub struct Value {
@@ -38,14 +60,18 @@ pub struct Value {
 }
 
 pub struct Args<'a> {
-  pub arch: &'a str,
+  pub arch: &'a ArchPolicy,
   pub mirrors: &'a MirrorLists,
   pub cache_dir: Option<&'a Path>,
   pub filter_cached: bool,
+  pub jobs: usize,
 }
 impl<'a> Args<'a> {
-  pub fn new(arch: &'a str, mirrors: &'a MirrorLists) -> Self {
-    Self { arch, mirrors, cache_dir: None, filter_cached: false }
+  pub fn new(arch: &'a ArchPolicy, mirrors: &'a MirrorLists) -> Self {
+    Self { arch, mirrors, cache_dir: None, filter_cached: false, jobs: 1 }
+  }
+  pub fn jobs(self, jobs: usize) -> Self {
+    Self { jobs: jobs.max(1), ..self }
   }
   pub fn cache<P: AsRef<Path> + 'a>(mut self, cache_dir: &'a P, filter_cached: bool) -> Self {
     self.cache_dir = self.cache_dir.or(Some(cache_dir.as_ref()));
@@ -63,50 +89,106 @@ pub async fn exec<'a, I>(
 where
   I: IntoIterator<Item = &'a PackageVersion> + Clone,
 {
-  let mut result = Vec::new();
   let urls = packages.clone().into_iter().map(|package| {
-    package.find_arch(args.arch).ok_or_else(|| Error::package_arch_not_found(package, args.arch))
+    package.find_arch(args.arch).ok_or_else(|| Error::package_arch_not_found(package, &args.arch.to_string()))
   }).collect::<Result<Vec<_>, _>>()?;
-  for (i, (info, pkg)) in packages.into_iter().zip(urls).enumerate() {
-    tracker.on_event(ItemEvent::Progress { current: i, max: None });
-    tracker.on_event(ItemEvent::Message { name: format!("probing {}", info.name) });
-    // TODO: check part?
-    let (url, cached) = match args.cache_dir.map(|i| i.join(&pkg.filename)) {
-      Some(target) if target.exists() => {
-        if args.filter_cached { continue }
-        (PackageUrl {
-          name: info.name.clone(),
-          pkg_url: target.to_string_lossy().to_string(),
-          pkg_size: target.metadata().when(("metadata", &target))?.len(),
-        }, true)
-      },
-      _ => (step(args.mirrors, pkg).await?, false),
-    };
-    result.push(Value {
-      pkg: pkg.clone(),
-      url,
-      cached,
-    })
+  let max = urls.len();
+  let finished = AtomicUsize::new(0);
+  let tracker = &tracker;
+  let finished = &finished;
+  let mut tasks = Vec::new();
+  for (info, pkg) in packages.into_iter().zip(urls) {
+    let args = &args;
+    tasks.push(async move {
+      // TODO: check part?
+      let (url, cached) = match args.cache_dir.map(|i| i.join(&pkg.filename)) {
+        Some(target) if target.exists() => {
+          if args.filter_cached { return Ok(None) }
+          (PackageUrl {
+            name: info.name.clone(),
+            pkg_url: target.to_string_lossy().to_string(),
+            pkg_size: Some(target.metadata().when(("metadata", &target))?.len()),
+          }, true)
+        },
+        _ => {
+          tracker.on_event(ItemEvent::Message { name: format!("probing {}", info.name) });
+          (step(args.mirrors, pkg).await?, false)
+        },
+      };
+      let current = finished.fetch_add(1, Ordering::AcqRel) + 1;
+      tracker.on_event(ItemEvent::Progress { current, max: Some(max) });
+      Ok(Some(Value {
+        pkg: pkg.clone(),
+        url,
+        cached,
+      }))
+    });
   }
+  // buffered keeps the order of packages
+  let result = futures::stream::iter(tasks).buffered(args.jobs)
+    .collect::<Vec<Result<_>>>().await
+    .into_iter().collect::<Result<Vec<_>>>()?;
   tracker.on_event(ItemEvent::Message { name: format!("probe finished") });
   tracker.on_event(ItemEvent::Finish);
-  Ok(result)
+  Ok(result.into_iter().flatten().collect())
 }
 
 #[tokio::test]
 async fn test_probe() {
   use crate::tests::*;
-  let arch = ARCH;
+  let arch = &get_arch();
   let cache_dir = CACHE_PATH;
   let active_pb = init_logger(None);
   let mirrors = get_mirrors();
   let query = ["llvm"];
-  let formulas = crate::io::read::read_formulas(crate::tests::FORMULA_FILE).unwrap();
-  let resolved = super::resolve::exec(&formulas, query, ()).await.unwrap().packages;
+  let index = crate::tests::get_index();
+  let resolved = super::resolve::exec(&index, query, ()).await.unwrap().packages;
   let result = crate::ui::with_progess_bar(active_pb, None, Some(ItemEvent::Init { max: resolved.len() }), |tracker| async {
     exec(Args::new(arch, &mirrors).cache(&cache_dir, false), &resolved, tracker).await
   }, ()).await.unwrap();
-  info!(len=result.len(), sum=result.iter().map(|i| i.url.pkg_size).sum::<u64>());
+  info!(len=result.len(), sum=result.iter().filter_map(|i| i.url.pkg_size).sum::<u64>());
   assert_eq!(result.len(), resolved.len());
   assert_eq!(result.iter().map(|i| &i.url.name).collect::<Vec<_>>(), resolved.iter().map(|i| &i.name).collect::<Vec<_>>());
 }
+
+#[tokio::test]
+async fn test_probe_size() {
+  use crate::{io::fetch::fetch_remote, package::mirror::MirrorType, tests::stub::{serve, Response}};
+  let base = serve(|req| match req.path.as_str() {
+    "/v2/homebrew/core/foo/manifests/1.0" => Response::json(&serde_json::json!({
+      "schemaVersion": 2,
+      "manifests": [{ "digest": "sha256:aaaa", "size": 10, "annotations": { crate::io::oci::ANNOTATION_REF_NAME: "1.0.x86_64_linux" } }],
+    })),
+    "/v2/homebrew/core/foo/manifests/sha256:aaaa" => Response::json(&serde_json::json!({
+      "schemaVersion": 2,
+      "layers": [{ "digest": "sha256:f2d6b3bd", "size": 1234 }],
+    })),
+    "/bottles/foo-1.0.x86_64_linux.bottle.tar.gz" => Response::new(200, b"bottle".to_vec()).no_length(),
+    _ => Response::new(404, b"".to_vec()),
+  }).await;
+  let pkg = PkgBuild {
+    name: "foo".to_string(),
+    version: "1.0".to_string(),
+    arch: "x86_64_linux".to_string(),
+    rebuild: 0,
+    filename: "foo-1.0.x86_64_linux.bottle.tar.gz".to_string(),
+    url: String::new(),
+    sha256: format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(b"bottle")),
+    cellar: ":any".to_string(),
+  };
+  let oci = MirrorLists::new(vec![MirrorServer::new(MirrorType::Oci, &format!("{}/v2/homebrew/core", base), None)], Default::default());
+  assert_eq!(step(&oci, &pkg).await.unwrap().pkg_size, Some(1234));
+
+  let bottle = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &format!("{}/bottles", base), None)], Default::default());
+  let url = step(&bottle, &pkg).await.unwrap();
+  assert_eq!(url.pkg_size, None);
+  assert_eq!(url.pkg_url, format!("{}/bottles/{}", base, pkg.filename));
+
+  // unknown size is reported as unbounded progress
+  let target = std::env::temp_dir().join(format!("pacbrew-test-probe-{}", std::process::id())).join(&pkg.filename);
+  let states = std::sync::Mutex::new(Vec::new());
+  fetch_remote(&bottle, FetchReq::Package(pkg.clone()), &target, |e| states.lock().unwrap().push(e)).await.unwrap();
+  assert!(states.lock().unwrap().iter().all(|e| e.max.is_none()));
+  assert_eq!(std::fs::read(&target).unwrap(), b"bottle");
+  std::fs::remove_dir_all(target.parent().unwrap()).unwrap();
+}
@@ -1,18 +1,18 @@
 use std::{borrow::Borrow, collections::{HashMap, HashSet, VecDeque}, time::Duration};
 
-///! query would find in Vec<Formula> to get correspond Package
+///! query would find in FormulaIndex to get correspond Package
 ///! with there dependences.
 
-use crate::{error::Result, package::{formula::Formula, package::PackageVersion}, ui::{event::ItemEvent, EventListener}};
+use crate::{error::Result, package::{index::FormulaIndex, package::PackageVersion}, ui::{event::ItemEvent, EventListener}};
 
 pub struct Value {
   pub names: Vec<String>,
   pub packages: Vec<PackageVersion>,
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(formulas.len=formulas.len()))]
+#[tracing::instrument(level = "debug", skip_all, fields(index.len=index.len()))]
 pub async fn exec<'a, S, I>(
-  formulas: &[Formula],
+  index: &FormulaIndex,
   query: I,
   tracker: impl EventListener<ItemEvent>
 ) -> Result<Value>
@@ -20,14 +20,9 @@ where
   S: Borrow<str> + ?Sized + 'a,
   I: IntoIterator<Item = &'a S>,
 {
-  let mut queue = VecDeque::from_iter(query.into_iter().map(|i| i.borrow()));
-  let mut direct_names = queue.iter().map(|&i| (i, i)).collect::<HashMap<_,_>>();
-  let mut visited = HashSet::<&str>::new();
-  let mut formula_index = formulas.iter().map(|f| (f.name.as_str(), f)).collect::<HashMap<_, _>>();
-  formula_index.extend(formulas.iter().flat_map(|f| f.oldname.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().flat_map(|f| f.oldnames.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().flat_map(|f| f.aliases.iter().map(move |name| (name.as_str(), f))));
-  formula_index.extend(formulas.iter().map(|f| (f.full_name.as_str(), f)));
+  let mut queue = VecDeque::from_iter(query.into_iter().map(|i| i.borrow().to_string()));
+  let mut direct_names = queue.iter().map(|i| (i.clone(), i.clone())).collect::<HashMap<_,_>>();
+  let mut visited = HashSet::<String>::new();
   let mut collected = Vec::new();
 
   let mut i = 0;
@@ -35,32 +30,32 @@ where
     i += 1;
     tracker.on_event(ItemEvent::Progress { current: i, max: Some(i + queue.len()) });
     tracker.on_event(ItemEvent::Message { name: format!("resolving {}", item) });
-    let formula = *formula_index.get(item).ok_or_else(|| crate::error::Error::package_not_found(item))?;
-    if direct_names.contains_key(item) {
-      direct_names.insert(item, &formula.full_name);
+    let package = index.find(&item)?;
+    if let Some(name) = direct_names.get_mut(&item) {
+      name.clone_from(&package.name);
     }
-    if visited.contains(formula.name.as_str()) {
+    if visited.contains(&package.name) {
       continue;
     }
-    visited.insert(&formula.name);
+    visited.insert(package.name.clone());
     // TODO: warn about cyclic dep here;
-    let deps = formula.dependencies.iter().filter(|i| !visited.contains(i.as_str())).map(|d| d.as_str()).collect::<Vec<_>>();
+    let deps = package.deps.iter().filter(|i| !visited.contains(*i)).cloned().collect::<Vec<_>>();
     if !deps.is_empty() {
-      debug!(deps.from=formula.name, deps.to=deps.join(","));
+      debug!(deps.from=package.name, deps.to=deps.join(","));
     }
     queue.extend(deps);
-    collected.push(formula.clone());
+    collected.push(package);
     // TODO: better parking method
     tokio::time::sleep(Duration::from_millis(0)).await;
   }
   tracker.on_event(ItemEvent::Message { name: format!("resolve finished") });
   tracker.on_event(ItemEvent::Finish);
-  let mut direct_names = direct_names.values().map(|i| i.to_string()).collect::<Vec<_>>();
+  let mut direct_names = direct_names.into_values().collect::<Vec<_>>();
   direct_names.sort();
-  // TODO: convert formula to package
+  direct_names.dedup();
   Ok(Value {
     names: direct_names,
-    packages: collected.into_iter().map(|f| f.into()).collect(),
+    packages: collected,
   })
 }
 
@@ -69,11 +64,11 @@ async fn test_resolve() {
   use crate::tests::*;
   let active_pb = init_logger(None);
   let query = ["wget", "llvm", "python", "ffmpeg"];
-  let formulas = get_formulas();
+  let index = get_index();
 
   let init = ItemEvent::Init { max: query.len() };
   let result = crate::ui::with_progess_bar(active_pb.clone(), None, Some(init), |tracker| async move {
-    exec(&formulas, query, tracker).await
+    exec(&index, query, tracker).await
   }, ()).await.unwrap();
 
   info!(names=result.names.join(","));
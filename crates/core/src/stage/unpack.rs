@@ -1,32 +1,37 @@
-use std::{ffi::OsString, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use std::{collections::BTreeMap, ffi::OsString, path::{Path, PathBuf}, sync::{Arc, Mutex}};
 
-use crate::{error::{ErrorExt, IoErrorExt, Result}, io::{relocate::{relocate, RelocateType, RelocationPattern}, untar::{untar_gz, UnpackEvent}}, package::package::{PackageCache, PackageInstalled}, ui::{event::{BytesEvent, DetailEvent}, EventListener}};
+use crate::{error::{Error, ErrorExt, IoErrorExt, Result}, io::{relocate::{relocate, RelocateType, Relocated, RelocationPattern}, untar::{untar_gz, UnpackEvent}}, package::package::{PackageCache, PackageInstalled, PourMode}, ui::{event::{BytesEvent, DetailEvent}, EventListener}};
 
-pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(pattern: &RelocationPattern, cache_pkg: P, dest_dir: Q, tracker: impl EventListener<BytesEvent>) -> Result<Vec<(PathBuf, RelocateType)>> {
+/// unpack `cache_pkg` into `dest_dir`, every file is relocated unless `pattern` is none
+pub async fn step<P: AsRef<Path>, Q: AsRef<Path>>(pattern: Option<&RelocationPattern>, cache_pkg: P, dest_dir: Q, tracker: impl EventListener<BytesEvent>) -> Result<Vec<(PathBuf, Relocated)>> {
   use crate::ui::event::Event::*;
   let dest_dir = dest_dir.as_ref();
   let relocates = Arc::new(Mutex::new(Vec::new()));
   untar_gz(&cache_pkg, dest_dir, |e: UnpackEvent| {
     if let Some(name) = e.current_entry {
+      let Some(pattern) = pattern else {
+        tracker.on_event(Progress { current: e.pos, max: Some(e.total_size) });
+        return;
+      };
       let filename = dest_dir.join(&name);
       // TODO: python@3.13/3.13.3/Frameworks/Python.framework/Versions/3.13/lib/python3.13/test/test_importlib/name
       if !filename.exists() {
         return;
       }
       match relocate(filename, pattern) {
-        Ok(RelocateType::None) => {},
-        Ok(ty) => relocates.lock().unwrap().push((name, ty)),
+        Ok(result) if result == Relocated::default() => {},
+        Ok(result) => relocates.lock().unwrap().push((name, result)),
         Err(e) => {
           error!(error=?e, "relocate failed");
-          relocates.lock().unwrap().push((name, RelocateType::None));
+          relocates.lock().unwrap().push((name, Relocated::default()));
         },
       }
     }
     tracker.on_event(Progress { current: e.pos, max: Some(e.total_size) });
   }).await?;
   let relocates: Vec<_> = std::mem::take(relocates.lock().unwrap().as_mut());
-  for (name, ty) in &relocates {
-    if ty == &RelocateType::None {
+  for (name, result) in &relocates {
+    if result.ty == RelocateType::None {
       warn!(name=%name.display(), "relocate failed");
       // return Err(std::io::Error::other(format!("relocate {} failed", name.display()))).when(("unpack", cache_pkg.as_ref()))?;
     }
@@ -38,14 +43,29 @@ pub struct Args<'a> {
   pub prefix: &'a Path,
   pub cellar: &'a Path,
   pub force: bool,
+  /// extra placeholders and overrides, see `RelocationPattern::placeholders`
+  pub placeholders: Option<&'a BTreeMap<String, String>>,
+  /// relocate bottles built for another fixed cellar anyway, instead of refusing them
+  pub force_relocate: bool,
+  /// leave kegs in `staging_dir`, which are moved into the cellar by `Transaction::install_keg`
+  pub stage: bool,
 }
 impl<'a> Args<'a> {
   pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(prefix: &'a P1, cellar: &'a P2) -> Self {
-    Self { prefix: prefix.as_ref(), cellar: cellar.as_ref(), force: false }
+    Self { prefix: prefix.as_ref(), cellar: cellar.as_ref(), force: false, placeholders: None, force_relocate: false, stage: false }
   }
   pub fn force(self, f: bool) -> Self {
     Self { force: f, ..self }
   }
+  pub fn stage(self, s: bool) -> Self {
+    Self { stage: s, ..self }
+  }
+  pub fn force_relocate(self, f: bool) -> Self {
+    Self { force_relocate: f, ..self }
+  }
+  pub fn placeholders(self, placeholders: &'a BTreeMap<String, String>) -> Self {
+    Self { placeholders: Some(placeholders), ..self }
+  }
 }
 
 pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
@@ -56,40 +76,66 @@ pub async fn exec<'a, I: IntoIterator<Item = &'a PackageCache> + Clone>(
   use DetailEvent::*;
   use crate::ui::event::Event::*;
   let mut result = Vec::new();
-  let pattern = RelocationPattern::new(args.prefix, args.cellar);
+  let mut pattern = RelocationPattern::new(args.prefix, args.cellar)?;
+  if let Some(placeholders) = args.placeholders {
+    pattern = pattern.placeholders(placeholders);
+  }
   tracker.on_event(Overall(Init { max: pkgs.clone().into_iter().count() }));
   for (i, pkg) in pkgs.into_iter().enumerate() {
-    let tmp_target = Path::new(args.cellar).join(&pkg.name).join("tmp");
+    let pattern = match PourMode::new(&pkg.cellar, args.cellar) {
+      PourMode::Relocate => Some(&pattern),
+      PourMode::SkipRelocation => None,
+      PourMode::CellarMismatch if args.force_relocate => {
+        warn!(name=%pkg.name, cellar=%pkg.cellar, "bottle is built for another cellar, relocate anyway");
+        Some(&pattern)
+      },
+      PourMode::CellarMismatch => return Err(Error::CellarMismatch { name: pkg.name.clone(), expect: pkg.cellar.clone(), actual: args.cellar.to_owned() }),
+    };
+    let tmp_target = staging_dir(args.cellar, &pkg.name);
     debug!(cache_pkg=%pkg.cache_pkg.display(), tmp_dir=%tmp_target.display());
     std::fs::remove_dir_all(&tmp_target).ok_not_found().when(("remove_dir_all", &tmp_target))?;
     std::fs::create_dir_all(&tmp_target).when(("create_dir_all", &tmp_target))?;
 
     tracker.on_event(Item(i, Message { name: format!("{}", pkg.name) }));
     tracker.on_event(Item(i, Message { name: format!("unpacking {}", pkg.name) }));
-    let reloc = step(&pattern, &pkg.cache_pkg, &tmp_target, |e: BytesEvent| tracker.on_event(Item(i, e))).await?;
+    let reloc = step(pattern, &pkg.cache_pkg, &tmp_target, |e: BytesEvent| tracker.on_event(Item(i, e))).await?;
     tracker.on_event(Item(i, Finish));
     tracker.on_event(Overall(Progress { current: i, max: None }));
     let version = guess_version(tmp_target.join(&pkg.name)).when(("unpack guess version", &tmp_target))?;
     let tmp_target_versioned = tmp_target.join(&pkg.name).join(&version);
-    let target_versioned = Path::new(args.cellar).join(&pkg.name).join(&version);
-    debug!(tmp_target_versioned=%tmp_target_versioned.display(), target_versioned=%target_versioned.display(), args.force, "rename");
-    if args.force {
-      std::fs::remove_dir_all(&target_versioned).ok();
-    }
-    std::fs::rename(&tmp_target_versioned, &target_versioned).when(("unpack.rename", &tmp_target_versioned))?;
-    std::fs::remove_dir_all(&tmp_target).ok();
+    let target_versioned = if args.stage {
+      tmp_target_versioned
+    } else {
+      let target_versioned = Path::new(args.cellar).join(&pkg.name).join(&version);
+      debug!(tmp_target_versioned=%tmp_target_versioned.display(), target_versioned=%target_versioned.display(), args.force, "rename");
+      if args.force {
+        std::fs::remove_dir_all(&target_versioned).ok();
+      }
+      std::fs::rename(&tmp_target_versioned, &target_versioned).when(("unpack.rename", &tmp_target_versioned))?;
+      std::fs::remove_dir_all(&tmp_target).ok();
+      target_versioned
+    };
 
     result.push(PackageInstalled {
       name: pkg.name.clone(),
       dest: target_versioned,
       version: version.to_string_lossy().to_string(),
-      reloc: reloc.into_iter().collect(),
+      unrelocated: reloc.iter().filter(|(_, i)| !i.unrelocated.is_empty()).map(|(name, i)| (name.clone(), i.unrelocated.clone())).collect(),
+      reloc: reloc.into_iter().map(|(name, i)| (name, i.ty)).collect(),
     });
   }
   tracker.on_event(Overall(Finish));
   Ok(result)
 }
 
+/// name of the staging dir next to the versions of a formula, which is never a version
+pub const STAGING_DIR: &str = "tmp";
+
+/// where a keg of `name` is unpacked and relocated before it is moved into the cellar
+pub fn staging_dir(cellar: &Path, name: &str) -> PathBuf {
+  cellar.join(name).join(STAGING_DIR)
+}
+
 fn guess_version(path: PathBuf) -> std::io::Result<OsString> {
   let mut children = path.read_dir()?;
   let child = children.next().ok_or_else(|| std::io::Error::other("no children"))??;
@@ -1,25 +1,111 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::{error::{Error, ErrorExt as _, Result}, io::{fetch::{fetch_remote, FetchReq, MirrorLists}, read::{read_formulas, tmp_path}, FetchState}, ui::{event::BytesEvent, EventListener}};
+use crate::{error::{Error, ErrorExt as _, IoErrorExt as _, Result}, io::{fetch::{fetch_remote_cond, FetchReq, MirrorLists}, http::{Fetched, Validators}, jws::{self, PublicKeys}, read::{read_formulas, read_toml, tmp_path, write_toml}, FetchState}, package::index::FormulaIndex, ui::{event::BytesEvent, EventListener}};
 
-#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = mirrors.len()))]
-pub async fn exec<P: AsRef<Path>>(mirrors: &MirrorLists, dest_dir: P, tracker: impl EventListener<BytesEvent>) -> Result<()> {
-  // TODO: support formula.json.gz
-  let req = FetchReq::Api("formula.json".to_string());
-  let target = req.target(dest_dir);
+/// validators of an api file, kept next to formula.json
+pub const VALIDATORS_SUFFIX: &str = ".validators.toml";
+pub const FORMULA_JSON: &str = "formula.json";
+pub const FORMULA_JWS_JSON: &str = "formula.jws.json";
+/// marker next to formula.json when it comes from a verified formula.jws.json
+pub const VERIFIED_SUFFIX: &str = ".verified";
+
+pub struct Args<'a> {
+  pub mirrors: &'a MirrorLists,
+  pub dest_dir: &'a Path,
+  pub keys: Option<&'a PublicKeys>,
+  pub require_signature: bool,
+}
+impl<'a> Args<'a> {
+  pub fn new<P: AsRef<Path> + 'a>(mirrors: &'a MirrorLists, dest_dir: &'a P) -> Self {
+    Self { mirrors, dest_dir: dest_dir.as_ref(), keys: None, require_signature: false }
+  }
+  /// fetch the signed formula.jws.json and verify it with `keys`,
+  /// unless `required`, mirrors without it fall back to the unsigned formula.json.
+  pub fn verify(self, keys: &'a PublicKeys, required: bool) -> Self {
+    Self { keys: Some(keys), require_signature: required, ..self }
+  }
+}
+
+/// fetch api file `name` into a temporary file,
+/// a conditional request is sent when formula.json is present, and none is returned if it is not modified.
+/// validators of formula.jws.json are only sent when formula.json is verified from it.
+async fn fetch(args: &Args<'_>, name: &str, tracker: &impl EventListener<BytesEvent>) -> Result<Option<(PathBuf, Validators, PathBuf)>> {
+  let req = FetchReq::Api(name.to_string());
+  let target = req.target(args.dest_dir);
   let tmp_file = tmp_path(&target, ".new");
-  fetch_remote(mirrors, req, &tmp_file, |state: FetchState| tracker.on_event(BytesEvent::Progress { current: state.current, max: Some(state.max) })).await?;
+  let validators_file = tmp_path(&target, VALIDATORS_SUFFIX);
+  let formula_json = args.dest_dir.join(FORMULA_JSON);
+  let verified = name != FORMULA_JWS_JSON || tmp_path(&formula_json, VERIFIED_SUFFIX).exists();
+  let validators = match formula_json.exists() && verified && validators_file.exists() {
+    true => read_toml::<Validators, _>(&validators_file).map_err(|e| warn!(error=%e, "drop broken validators")).ok(),
+    false => None,
+  };
+  let fetched = fetch_remote_cond(args.mirrors, req, &tmp_file, validators.as_ref(), |state: FetchState| tracker.on_event(BytesEvent::from(state))).await?;
+  let Fetched::Updated { validators, .. } = fetched else {
+    return Ok(None);
+  };
   if !tmp_file.exists() {
     return Err(Error::parse_response_error("fetch", &tmp_file.display().to_string(), "not exists"));
   }
-  let formulas = read_formulas(&tmp_file)?;
+  Ok(Some((tmp_file, validators, validators_file)))
+}
+
+/// check and move `tmp_file` to formula.json and rebuild its index, then remember `validators` for the next conditional request,
+/// `verified` tells whether it is the payload of a verified formula.jws.json.
+fn replace(dest_dir: &Path, tmp_file: &Path, verified: bool, validators: &Validators, validators_file: &Path) -> Result<()> {
+  let formulas = read_formulas(tmp_file)?;
   if formulas.is_empty() {
     return Err(Error::parse_response_error("fetch", &tmp_file.display().to_string(), "empty"));
   }
-  std::fs::rename(&tmp_file, &target).when(("rename", &target))?;
+  let target = dest_dir.join(FORMULA_JSON);
+  let marker = tmp_path(&target, VERIFIED_SUFFIX);
+  // drop the marker first, an unverified formula.json never keeps it even if interrupted
+  std::fs::remove_file(&marker).ok_not_found_none().when(("remove_file", &marker))?;
+  std::fs::rename(tmp_file, &target).when(("rename", &target))?;
+  FormulaIndex::write(&target, formulas)?;
+  if validators.is_empty() {
+    std::fs::remove_file(validators_file).ok();
+  } else {
+    write_toml(validators_file, validators, true)?;
+  }
+  if verified {
+    std::fs::write(&marker, b"").when(("write", &marker))?;
+  }
   Ok(())
 }
 
+/// refresh formula.json under `dest_dir`, returns false when it is already up to date.
+#[tracing::instrument(level = "debug", skip_all, fields(mirrors.len = args.mirrors.len(), dest_dir = %args.dest_dir.display(), signed = args.keys.is_some()))]
+pub async fn exec(args: Args<'_>, tracker: impl EventListener<BytesEvent>) -> Result<bool> {
+  let keys = args.keys.filter(|i| !i.is_empty());
+  if args.require_signature && keys.is_none() {
+    return Err(Error::SignatureInvalid { target: FORMULA_JWS_JSON.to_string(), reason: "signature is required but no public key configured".to_string() });
+  }
+  if let Some(keys) = keys {
+    match fetch(&args, FORMULA_JWS_JSON, &tracker).await {
+      Ok(None) => return Ok(false),
+      Ok(Some((jws_file, validators, validators_file))) => {
+        let content = std::fs::read_to_string(&jws_file).when(("read", &jws_file))?;
+        std::fs::remove_file(&jws_file).when(("remove_file", &jws_file))?;
+        let payload = jws::verify(FORMULA_JWS_JSON, &content, keys)?;
+        let tmp_file = tmp_path(&args.dest_dir.join(FORMULA_JSON), ".new");
+        std::fs::write(&tmp_file, payload).when(("write", &tmp_file))?;
+        replace(args.dest_dir, &tmp_file, true, &validators, &validators_file)?;
+        return Ok(true);
+      },
+      Err(e @ Error::MirrorFailed(_)) if !args.require_signature => {
+        warn!(error=%e, "signed index not available, fall back to the unsigned one");
+      },
+      Err(e) => return Err(e),
+    }
+  }
+  let Some((tmp_file, validators, validators_file)) = fetch(&args, FORMULA_JSON, &tracker).await? else {
+    return Ok(false);
+  };
+  replace(args.dest_dir, &tmp_file, false, &validators, &validators_file)?;
+  Ok(true)
+}
+
 #[tokio::test]
 async fn test_update_formula() {
   use crate::tests::*;
@@ -31,10 +117,114 @@ async fn test_update_formula() {
     active_pb,
     None,
     None,
-    |tracker| exec(&mirrors, CACHE_PATH, tracker),
+    |tracker| exec(Args::new(&mirrors, &CACHE_PATH), tracker),
     (),
   ).await.unwrap();
   assert!(target.exists());
   info!(len=%std::fs::metadata(&target).unwrap().len());
   // std::fs::remove_file(target).unwrap();
 }
+
+#[tokio::test]
+async fn test_update_conditional() {
+  use std::{io::Write as _, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+  use crate::{package::mirror::{MirrorServer, MirrorType}, tests::{formula_json, stub::{serve, Response}}};
+  let json = serde_json::json!([formula_json("foo", "1.0", &[], "x86_64_linux")]).to_string();
+  let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  gz.write_all(json.as_bytes()).unwrap();
+  let gz = gz.finish().unwrap();
+  let sent = Arc::new(AtomicUsize::new(0));
+  let counter = sent.clone();
+  let base = serve(move |req| match req.path.as_str() {
+    "/api/formula.json.gz" if req.header("if-none-match").map(String::as_str) == Some("\"v1\"") => Response::new(304, Vec::new()),
+    "/api/formula.json.gz" => {
+      counter.fetch_add(1, Ordering::AcqRel);
+      Response::new(200, gz.clone()).header("etag", "\"v1\"")
+    },
+    _ => Response::new(404, Vec::new()),
+  }).await;
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)], Default::default());
+  let dest = std::env::temp_dir().join(format!("pacbrew-test-update-{}", std::process::id()));
+  assert!(exec(Args::new(&mirrors, &dest), ()).await.unwrap());
+  assert_eq!(read_formulas(dest.join("formula.json")).unwrap()[0].name, "foo");
+  assert!(!exec(Args::new(&mirrors, &dest), ()).await.unwrap());
+  assert_eq!(sent.load(Ordering::Acquire), 1);
+
+  // validators are only sent with the db present
+  std::fs::remove_file(dest.join("formula.json")).unwrap();
+  assert!(exec(Args::new(&mirrors, &dest), ()).await.unwrap());
+  assert_eq!(sent.load(Ordering::Acquire), 2);
+  std::fs::remove_dir_all(&dest).unwrap();
+}
+
+#[tokio::test]
+async fn test_update_signed() {
+  use crate::{io::jws::tests::{private_key, sign}, package::mirror::{MirrorServer, MirrorType}, tests::{formula_json, stub::{serve, Response}}};
+  let key = private_key();
+  let json = serde_json::json!([formula_json("foo", "1.0", &[], "x86_64_linux")]).to_string();
+  let signed = sign(&key, "homebrew-1", &json);
+  let forged = sign(&private_key(), "homebrew-1", &json);
+  let base = serve(move |req| match req.path.as_str() {
+    "/signed/api/formula.jws.json" => Response::new(200, signed.clone().into_bytes()),
+    "/forged/api/formula.jws.json" => Response::new(200, forged.clone().into_bytes()),
+    "/forged/api/formula.json" | "/unsigned/api/formula.json" => Response::new(200, json.clone().into_bytes()),
+    _ => Response::new(404, Vec::new()),
+  }).await;
+  let mirrors = |path: &str| MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &format!("{}/{}", base, path), None)], crate::io::fetch::NetworkPolicy { retry: 0, ..Default::default() });
+  let keys = PublicKeys::from([("homebrew-1".to_string(), key.to_public_key())]);
+  let dest = std::env::temp_dir().join(format!("pacbrew-test-signed-{}", std::process::id()));
+
+  let signed = mirrors("signed");
+  assert!(exec(Args::new(&signed, &dest).verify(&keys, true), ()).await.unwrap());
+  assert_eq!(read_formulas(dest.join(FORMULA_JSON)).unwrap()[0].name, "foo");
+  std::fs::remove_dir_all(&dest).unwrap();
+
+  // a bad signature never falls back
+  let forged = mirrors("forged");
+  let result = exec(Args::new(&forged, &dest).verify(&keys, false), ()).await;
+  assert!(matches!(result, Err(Error::SignatureInvalid { .. })), "{:?}", result);
+  assert!(!dest.join(FORMULA_JSON).exists());
+
+  let unsigned = mirrors("unsigned");
+  let result = exec(Args::new(&unsigned, &dest).verify(&keys, true), ()).await;
+  assert!(matches!(result, Err(Error::MirrorFailed(_))), "{:?}", result);
+  assert!(exec(Args::new(&unsigned, &dest).verify(&keys, false), ()).await.unwrap());
+  assert!(matches!(exec(Args::new(&unsigned, &dest).verify(&PublicKeys::new(), true), ()).await, Err(Error::SignatureInvalid { .. })));
+  std::fs::remove_dir_all(&dest).unwrap();
+}
+
+#[tokio::test]
+async fn test_update_signed_fallback() {
+  use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+  use crate::{io::jws::tests::{private_key, sign}, package::mirror::{MirrorServer, MirrorType}, tests::{formula_json, stub::{serve, Response}}};
+  let key = private_key();
+  let signed = sign(&key, "homebrew-1", &serde_json::json!([formula_json("foo", "1.0", &[], "x86_64_linux")]).to_string());
+  let unsigned = serde_json::json!([formula_json("bar", "1.0", &[], "x86_64_linux")]).to_string();
+  let down = Arc::new(AtomicBool::new(false));
+  let signed_down = down.clone();
+  let base = serve(move |req| match req.path.as_str() {
+    "/api/formula.jws.json" if signed_down.load(Ordering::Acquire) => Response::new(404, Vec::new()),
+    "/api/formula.jws.json" if req.header("if-none-match").map(String::as_str) == Some("\"v1\"") => Response::new(304, Vec::new()),
+    "/api/formula.jws.json" => Response::new(200, signed.clone().into_bytes()).header("etag", "\"v1\""),
+    "/api/formula.json" => Response::new(200, unsigned.clone().into_bytes()),
+    _ => Response::new(404, Vec::new()),
+  }).await;
+  let mirrors = MirrorLists::new(vec![MirrorServer::new(MirrorType::Bottle, &base, None)], crate::io::fetch::NetworkPolicy { retry: 0, ..Default::default() });
+  let keys = PublicKeys::from([("homebrew-1".to_string(), key.to_public_key())]);
+  let dest = std::env::temp_dir().join(format!("pacbrew-test-signed-fallback-{}", std::process::id()));
+  let name = || read_formulas(dest.join(FORMULA_JSON)).unwrap()[0].name.clone();
+
+  assert!(exec(Args::new(&mirrors, &dest).verify(&keys, true), ()).await.unwrap());
+  assert_eq!(name(), "foo");
+  assert!(!exec(Args::new(&mirrors, &dest).verify(&keys, true), ()).await.unwrap());
+
+  down.store(true, Ordering::Release);
+  assert!(exec(Args::new(&mirrors, &dest).verify(&keys, false), ()).await.unwrap());
+  assert_eq!(name(), "bar");
+
+  // the unsigned formula.json is not kept as up to date by a 304 of the signed one
+  down.store(false, Ordering::Release);
+  assert!(exec(Args::new(&mirrors, &dest).verify(&keys, true), ()).await.unwrap());
+  assert_eq!(name(), "foo");
+  std::fs::remove_dir_all(&dest).unwrap();
+}
@@ -48,15 +48,16 @@ pub async fn exec<'a, P: AsRef<Path>, I: IntoIterator<Item = (&'a PkgBuild, &'a
         name: pkg.name.clone(),
         cache_pkg,
         cache_size,
+        cellar: pkg.cellar.clone(),
       }
     });
     let mut reason = None;
     if pkg.name != url.name || pkg.name != cached.name {
       reason = Some("name not match");
       warn!(pkg.name, url.name, cached.name, "name not match");
-    } else if cached.cache_size != url.pkg_size {
+    } else if url.pkg_size.is_some_and(|i| i != cached.cache_size) {
       reason = Some("size not match");
-      warn!(pkg.name, cached.cache_size, url.pkg_size, "size not match");
+      warn!(pkg.name, cached.cache_size, ?url.pkg_size, "size not match");
     } else if !cached.cache_pkg.exists() || !cached.cache_pkg.is_file() {
       reason = Some("cache_pkg not exists");
       warn!(pkg.name, cached.cache_pkg=%cached.cache_pkg.display(), "not exists");
@@ -107,12 +108,13 @@ pub fn get_pkgs<'a>(packages: &'a [crate::package::package::PackageVersion], cac
     let url = PackageUrl {
       name: pkg.name.clone(),
       pkg_url: file_name.clone(),
-      pkg_size: entry.metadata().unwrap().len(),
+      pkg_size: Some(entry.metadata().unwrap().len()),
     };
     let cache = PackageCache {
       name: pkg.name.clone(),
       cache_pkg: entry.path(),
       cache_size: entry.metadata().unwrap().len(),
+      cellar: pkg.cellar.clone(),
     };
     pkgs.push((pkg, url, cache));
   }
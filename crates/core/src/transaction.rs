@@ -0,0 +1,385 @@
+//! install packages as a transaction.
+//!
+//! kegs are unpacked into a staging dir first (see `unpack::Args::stage`), then for each package the keg is
+//! switched into the cellar, linked and recorded in db. every change is written to a journal before it is made,
+//! so `rollback` could undo them in reverse order: staging dirs are removed, the old keg is moved back, old symlinks
+//! are restored and the old db record is written again. a journal left by a crash or kill is rolled back when the next
+//! transaction begins.
+//!
+//! versions of a formula are kept side by side in the cellar and db, `activate` moves the links to another one.
+
+use std::path::{Path, PathBuf};
+
+use crate::{db, error::{ErrorExt, IoErrorExt, Result}, io::read::{read_json, write_to_file}, package::package::{InstalledPackage, PackageInstalled, PackageLinked}, stage::{link, unpack::staging_dir}, ui::{event::ItemEvent, EventListener}};
+
+const JOURNAL_FILE: &str = "transaction.json";
+/// kegs replaced are kept in the staging dir, while files overwritten in prefix are kept here in db
+const BACKUP_DIR: &str = ".backup";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+  /// kegs are unpacked into the staging dir `dir`, which is removed on roll back
+  Staging { dir: PathBuf },
+  /// keg `dest` is replaced by the one in `staging`, the previous keg is kept at `backup`
+  Keg { dest: PathBuf, staging: PathBuf, backup: Option<PathBuf> },
+  /// symlink at `path` is replaced, `old` is the target of the previous symlink
+  Link { path: PathBuf, old: Option<PathBuf> },
+  /// file at `path` in prefix is overwritten, and kept at `backup` until commit
+  Overwrite { path: PathBuf, backup: PathBuf },
+  /// db record of `name` at `version` is written, `old` is the previous record of that version,
+  /// and `active` the version active before
+  Db {
+    name: String,
+    #[serde(default)]
+    version: String,
+    old: Option<Box<InstalledPackage>>,
+    #[serde(default)]
+    active: Option<String>,
+  },
+}
+
+pub struct Transaction {
+  prefix: PathBuf,
+  db_root: PathBuf,
+  changes: Vec<Change>,
+}
+
+fn journal_path(db_root: &Path) -> PathBuf {
+  db_root.join(JOURNAL_FILE)
+}
+
+impl Transaction {
+  /// start a transaction, the journal of an unfinished one is rolled back first
+  pub fn begin<P: AsRef<Path>, Q: AsRef<Path>>(prefix: P, db_root: Q) -> Result<Self> {
+    let db_root = db_root.as_ref().to_owned();
+    let journal = journal_path(&db_root);
+    let changes = if journal.exists() {
+      warn!(journal=%journal.display(), "unfinished transaction found, roll back");
+      read_json(&journal)?
+    } else {
+      Vec::new()
+    };
+    let prefix = prefix.as_ref().to_owned();
+    Self { prefix: prefix.clone(), db_root: db_root.clone(), changes }.rollback()?;
+    Ok(Self { prefix, db_root, changes: Vec::new() })
+  }
+
+  pub fn changes(&self) -> &[Change] {
+    &self.changes
+  }
+
+  fn save(&self) -> Result<()> {
+    std::fs::create_dir_all(&self.db_root).when(("create_dir_all", &self.db_root))?;
+    let content = serde_json::to_vec(&self.changes).when(("ser", "transaction journal", None))?;
+    write_to_file(journal_path(&self.db_root), &content, true)?;
+    Ok(())
+  }
+
+  fn record<I: IntoIterator<Item = Change>>(&mut self, changes: I) -> Result<()> {
+    self.changes.extend(changes);
+    self.save()
+  }
+
+  /// journal the staging dirs before kegs are unpacked there, so they are not left in the cellar if the install stops
+  pub fn stage<I: IntoIterator<Item = PathBuf>>(&mut self, dirs: I) -> Result<()> {
+    self.record(dirs.into_iter().map(|dir| Change::Staging { dir }))
+  }
+
+  /// move the staged keg into the cellar, the keg of the same version is kept until commit
+  pub fn install_keg<P: AsRef<Path>>(&mut self, cellar: P, staged: &PackageInstalled) -> Result<PackageInstalled> {
+    let cellar = cellar.as_ref();
+    let staging = staging_dir(cellar, &staged.name);
+    let dest = cellar.join(&staged.name).join(&staged.version);
+    let backup = dest.exists().then(|| staging.join(BACKUP_DIR));
+    self.record([Change::Keg { dest: dest.clone(), staging, backup: backup.clone() }])?;
+    if let Some(backup) = &backup {
+      std::fs::rename(&dest, backup).when(("backup keg", &dest))?;
+    }
+    debug!(from=%staged.dest.display(), to=%dest.display(), "switch keg");
+    std::fs::rename(&staged.dest, &dest).when(("switch keg", &staged.dest))?;
+    Ok(PackageInstalled { dest, ..staged.clone() })
+  }
+
+  /// link the keg into prefix, previous symlinks are journaled, only `opt/<name>` is linked for `keg_only`.
+  /// with `overwrite`, files in the way are moved aside rather than failing the link
+  pub async fn link(&mut self, pkg: &PackageInstalled, keg_only: bool, overwrite: bool, tracker: impl EventListener<ItemEvent>) -> Result<PackageLinked> {
+    let files = match keg_only {
+      true => vec![link::opt_file(&pkg.name)],
+      false => link::owned_files(&self.prefix, &pkg.name, &pkg.dest)?,
+    };
+    if overwrite {
+      for file in &files {
+        let path = self.prefix.join(file);
+        if !path.symlink_metadata().is_ok_and(|i| i.is_file()) {
+          continue;
+        }
+        let backup = self.db_root.join(BACKUP_DIR).join(file);
+        self.record([Change::Overwrite { path: path.clone(), backup: backup.clone() }])?;
+        warn!(path=%path.display(), "overwrite");
+        if let Some(parent) = backup.parent() {
+          std::fs::create_dir_all(parent).when(("create_dir_all", parent))?;
+        }
+        std::fs::rename(&path, &backup).when(("backup file", &path))?;
+      }
+    }
+    let changes = files.iter().map(|file| {
+      let path = self.prefix.join(file);
+      let old = path.read_link().ok();
+      Change::Link { path, old }
+    }).collect::<Vec<_>>();
+    self.record(changes)?;
+    if keg_only {
+      return link::link_opt(&self.prefix, pkg);
+    }
+    let mut linked = link::exec(&self.prefix, [pkg], tracker).await?;
+    Ok(linked.remove(0))
+  }
+
+  /// remove symlinks of `files` from prefix, each is journaled so it could be restored
+  pub fn unlink(&mut self, files: &[String]) -> Result<()> {
+    let changes = files.iter().filter_map(|file| {
+      let path = self.prefix.join(file);
+      let old = path.read_link().ok()?;
+      Some(Change::Link { path, old: Some(old) })
+    }).collect::<Vec<_>>();
+    self.record(changes)?;
+    link::unlink_files(&self.prefix, files)
+  }
+
+  /// make the keg the active version of its formula, links of the version active before are removed first
+  pub async fn activate(&mut self, pkg: &PackageInstalled, keg_only: bool, overwrite: bool, tracker: impl EventListener<ItemEvent>) -> Result<PackageLinked> {
+    if let Some(active) = db::read_installed(&self.db_root, &pkg.name)? {
+      self.unlink(&active.files)?;
+      if active.record.version != pkg.version {
+        // only the active version has links recorded
+        self.write_record(&InstalledPackage { files: Vec::new(), ..active })?;
+      }
+    }
+    self.link(pkg, keg_only, overwrite, tracker).await
+  }
+
+  fn journal_db(&mut self, package: &InstalledPackage) -> Result<()> {
+    let (name, version) = (&package.record.name, &package.record.version);
+    let old = db::read_installed_version(&self.db_root, name, version)?.map(Box::new);
+    let active = db::active_version(&self.db_root, name)?;
+    self.record([Change::Db { name: name.clone(), version: version.clone(), old, active }])
+  }
+
+  /// write the db record of a version without activating it, the previous one is journaled
+  pub fn write_record(&mut self, package: &InstalledPackage) -> Result<()> {
+    self.journal_db(package)?;
+    db::write_record(&self.db_root, package)
+  }
+
+  /// write the db record and make it the active version, the previous one is journaled
+  pub fn write_installed(&mut self, package: &InstalledPackage) -> Result<()> {
+    self.journal_db(package)?;
+    db::write_installed(&self.db_root, package)
+  }
+
+  /// keep all changes, backups of replaced kegs are removed
+  pub fn commit(self) -> Result<()> {
+    for change in &self.changes {
+      if let Change::Keg { staging, .. } | Change::Staging { dir: staging } = change {
+        std::fs::remove_dir_all(staging).ok_not_found_none().when(("remove_dir_all", staging))?;
+      }
+    }
+    let backup = self.db_root.join(BACKUP_DIR);
+    std::fs::remove_dir_all(&backup).ok_not_found_none().when(("remove_dir_all", &backup))?;
+    let journal = journal_path(&self.db_root);
+    std::fs::remove_file(&journal).ok_not_found_none().when(("remove_file", &journal))?;
+    Ok(())
+  }
+
+  /// undo all changes in reverse order, keep going on errors and return the first one
+  pub fn rollback(self) -> Result<()> {
+    let mut result = Ok(());
+    for change in self.changes.iter().rev() {
+      debug!(?change, "roll back");
+      if let Err(e) = self.undo(change) {
+        error!(error=%e, ?change, "roll back failed");
+        if result.is_ok() {
+          result = Err(e);
+        }
+      }
+    }
+    result?;
+    let backup = self.db_root.join(BACKUP_DIR);
+    std::fs::remove_dir_all(&backup).ok_not_found_none().when(("remove_dir_all", &backup))?;
+    let journal = journal_path(&self.db_root);
+    std::fs::remove_file(&journal).ok_not_found_none().when(("remove_file", &journal))?;
+    Ok(())
+  }
+
+  fn undo(&self, change: &Change) -> Result<()> {
+    match change {
+      Change::Staging { dir } => {
+        std::fs::remove_dir_all(dir).ok_not_found_none().when(("remove_dir_all", dir))?;
+      },
+      Change::Keg { dest, staging, backup } => {
+        // the old keg is still at `dest` if the backup was not made
+        match backup {
+          Some(backup) if backup.exists() => {
+            std::fs::remove_dir_all(dest).ok_not_found_none().when(("remove_dir_all", dest))?;
+            std::fs::rename(backup, dest).when(("restore keg", backup))?;
+          },
+          Some(_) => {},
+          None => std::fs::remove_dir_all(dest).ok_not_found_none().when(("remove_dir_all", dest))?,
+        }
+        std::fs::remove_dir_all(staging).ok_not_found_none().when(("remove_dir_all", staging))?;
+      },
+      Change::Link { path, old } => {
+        if path.symlink_metadata().is_ok_and(|i| i.file_type().is_symlink()) {
+          symlink::remove_symlink_auto(path).when(("remove_symlink", path))?;
+        }
+        match old {
+          Some(old) if path.symlink_metadata().is_err() => {
+            // parents may be cleaned up by unlink
+            if let Some(parent) = path.parent() {
+              std::fs::create_dir_all(parent).when(("create_dir_all", parent))?;
+            }
+            symlink::symlink_auto(old, path).when(("restore symlink", path))?
+          },
+          Some(_) => warn!(path=%path.display(), "cannot restore symlink, path is taken"),
+          None => link::cleanup_empty_parents(&self.prefix, path)?,
+        }
+      },
+      Change::Overwrite { path, backup } => {
+        if backup.exists() {
+          if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).when(("create_dir_all", parent))?;
+          }
+          std::fs::rename(backup, path).when(("restore file", backup))?;
+        }
+      },
+      Change::Db { name, version, old, active } => {
+        match old {
+          Some(old) => {
+            debug!(name, version, "restore db record");
+            db::write_record(&self.db_root, old)?;
+          },
+          None => { db::remove_installed_version(&self.db_root, name, version)?; },
+        }
+        db::set_active(&self.db_root, name, active.as_deref())?;
+      },
+    }
+    Ok(())
+  }
+}
+
+#[tokio::test]
+async fn test_transaction() {
+  use crate::package::package::{InstallReason, InstalledPackageRecord};
+
+  let prefix = std::env::temp_dir().join(format!("pacbrew-transaction-test-{}", std::process::id()));
+  let cellar = prefix.join("Cellar");
+  let db_root = prefix.join("db");
+  std::fs::remove_dir_all(&prefix).ok();
+
+  let keg = |dir: &Path, content: &str, extra: bool| {
+    std::fs::create_dir_all(dir.join("bin")).unwrap();
+    std::fs::write(dir.join("bin/foo"), content).unwrap();
+    if extra {
+      std::fs::write(dir.join("bin/bar"), content).unwrap();
+    }
+    PackageInstalled { name: "foo".to_string(), dest: dir.to_owned(), version: "1.0".to_string(), reloc: Default::default(), unrelocated: Default::default() }
+  };
+  let record = |pkg: &PackageInstalled, install_date| InstalledPackage {
+    record: InstalledPackageRecord {
+      name: pkg.name.clone(),
+      version: pkg.version.clone(),
+      version_scheme: 0,
+      desc: String::new(),
+      license: None,
+      deps: Vec::new(),
+      reason: InstallReason::Explicit,
+      install_date,
+      dest: pkg.dest.clone(),
+      keg_only: None,
+    },
+    files: Vec::new(),
+    reloc: Default::default(),
+    unrelocated: Default::default(),
+  };
+  let install = |content: &'static str| {
+    let (prefix, cellar, db_root) = (prefix.clone(), cellar.clone(), db_root.clone());
+    async move {
+      let staged = keg(&staging_dir(&cellar, "foo").join("foo/1.0"), content, true);
+      let mut tx = Transaction::begin(&prefix, &db_root).unwrap();
+      let pkg = tx.install_keg(&cellar, &staged).unwrap();
+      let linked = tx.link(&pkg, false, false, ()).await.unwrap();
+      tx.write_installed(&InstalledPackage { files: linked.files, ..record(&pkg, 2) }).unwrap();
+      tx
+    }
+  };
+
+  let old = keg(&cellar.join("foo/1.0"), "old", false);
+  link::exec(&prefix, [&old], ()).await.unwrap();
+  db::write_installed(&db_root, &record(&old, 1)).unwrap();
+
+  // an unfinished transaction is rolled back by the next one
+  let tx = install("new").await;
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/bar")).unwrap(), "new");
+  drop(tx);
+  Transaction::begin(&prefix, &db_root).unwrap();
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/foo")).unwrap(), "old");
+  assert!(prefix.join("bin/bar").symlink_metadata().is_err());
+  assert!(!staging_dir(&cellar, "foo").exists());
+  assert!(!journal_path(&db_root).exists());
+  assert_eq!(db::read_installed(&db_root, "foo").unwrap().unwrap().record.install_date, 1);
+
+  let tx = install("new").await;
+  assert_eq!(tx.changes().len(), 5);
+  tx.commit().unwrap();
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/foo")).unwrap(), "new");
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/bar")).unwrap(), "new");
+  assert!(!staging_dir(&cellar, "foo").exists());
+  assert!(!journal_path(&db_root).exists());
+  assert_eq!(db::read_installed(&db_root, "foo").unwrap().unwrap().record.install_date, 2);
+
+  // unlinked files are restored on roll back, parents cleaned up included
+  let files = link::owned_files(&prefix, "foo", &cellar.join("foo/1.0")).unwrap();
+  let mut tx = Transaction::begin(&prefix, &db_root).unwrap();
+  tx.unlink(&files).unwrap();
+  assert!(!prefix.join("bin").exists());
+  assert!(!prefix.join("opt/foo").exists());
+  tx.rollback().unwrap();
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/bar")).unwrap(), "new");
+  assert!(prefix.join("opt/foo").is_dir());
+
+  // another version is kept side by side, and the links are switched to the active one
+  let install_v2 = || async {
+    let staged = PackageInstalled { version: "2.0".to_string(), ..keg(&staging_dir(&cellar, "foo").join("foo/2.0"), "v2", false) };
+    let mut tx = Transaction::begin(&prefix, &db_root).unwrap();
+    let pkg = tx.install_keg(&cellar, &staged).unwrap();
+    let linked = tx.activate(&pkg, false, false, ()).await.unwrap();
+    tx.write_installed(&InstalledPackage { files: linked.files, ..record(&pkg, 3) }).unwrap();
+    tx
+  };
+  let tx = install_v2().await;
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/foo")).unwrap(), "v2");
+  assert!(prefix.join("bin/bar").symlink_metadata().is_err());
+  tx.rollback().unwrap();
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/bar")).unwrap(), "new");
+  assert_eq!(db::active_version(&db_root, "foo").unwrap().as_deref(), Some("1.0"));
+  assert_eq!(db::list_versions(&db_root, "foo").unwrap().len(), 1);
+  assert!(!cellar.join("foo/2.0").exists());
+
+  install_v2().await.commit().unwrap();
+  assert_eq!(db::list_versions(&db_root, "foo").unwrap().len(), 2);
+  assert_eq!(db::read_installed(&db_root, "foo").unwrap().unwrap().record.version, "2.0");
+  let v1 = db::read_installed_version(&db_root, "foo", "1.0").unwrap().unwrap();
+  assert!(v1.files.is_empty());
+  assert!(cellar.join("foo/1.0").exists());
+
+  let mut tx = Transaction::begin(&prefix, &db_root).unwrap();
+  let linked = tx.activate(&v1.keg(), false, false, ()).await.unwrap();
+  tx.write_installed(&InstalledPackage { files: linked.files, ..v1 }).unwrap();
+  tx.commit().unwrap();
+  assert_eq!(std::fs::read_to_string(prefix.join("bin/bar")).unwrap(), "new");
+  assert_eq!(prefix.join("opt/foo").canonicalize().unwrap(), cellar.join("foo/1.0").canonicalize().unwrap());
+  assert!(db::read_installed_version(&db_root, "foo", "2.0").unwrap().unwrap().files.is_empty());
+
+  std::fs::remove_dir_all(&prefix).ok();
+}
@@ -71,6 +71,10 @@ pub trait FeedBar {
   fn message(&self) -> Option<String>;
   fn position(&self) -> Option<u64>;
   fn length(&self) -> Option<u64>;
+  /// the length is unknown, the bar should drop the one it has
+  fn unbounded(&self) -> bool {
+    false
+  }
 }
 
 impl<T: FeedBar> EventListener<T> for ProgressBar {
@@ -80,6 +84,8 @@ impl<T: FeedBar> EventListener<T> for ProgressBar {
     }
     if let Some(len) = event.length() {
       self.set_length(len);
+    } else if event.unbounded() {
+      self.unset_length();
     }
     if let Some(pos) = event.position() {
       self.set_position(pos);
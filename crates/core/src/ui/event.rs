@@ -17,6 +17,8 @@ pub type BytesEvent = Event<u64>;
 pub enum Event<T> {
   Init { max: T },
   Progress { current: T, max: Option<T> },
+  /// progress without a known length, e.g. a download without `Content-Length`
+  Unbounded { current: T },
   Message { name: String },
   Finish,
 }
@@ -31,11 +33,15 @@ impl<T: AsU64> FeedBar for Event<T> {
 
   fn position(&self) -> Option<u64> {
     match self {
-      Self::Progress { current, .. } => Some(current.as_u64()),
+      Self::Progress { current, .. } | Self::Unbounded { current } => Some(current.as_u64()),
       _ => None,
     }
   }
 
+  fn unbounded(&self) -> bool {
+    matches!(self, Self::Unbounded { .. })
+  }
+
   fn length(&self) -> Option<u64> {
     match self {
       Self::Init { max } => Some(max.as_u64()),
@@ -72,6 +78,13 @@ impl<S: AsU64, T: AsU64> FeedBar for DetailEvent<S, T> {
       Self::Item(_, e) => e.length(),
     }
   }
+
+  fn unbounded(&self) -> bool {
+    match self {
+      Self::Overall(e) => e.unbounded(),
+      Self::Item(_, e) => e.unbounded(),
+    }
+  }
 }
 
 impl<S: AsU64, T: AsU64> FeedMulti<usize> for DetailEvent<S, T> {